@@ -0,0 +1,441 @@
+use std::{path::PathBuf, time::Duration};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use simple_server_analytics_db::{
+    export::{ExportFormat, ExportTable},
+    import::{ImportCounts, LogFormat},
+    stats::TopField,
+    store::partitioned::{PartitionPeriod, PartitionedDb},
+    Db, Request,
+};
+
+use table::Table;
+use tokio::{fs::File, io::BufReader};
+
+mod table;
+
+/// Query and maintain a simple-server-analytics database.
+#[derive(Debug, Parser)]
+#[command(name = "ssa", version)]
+struct Cli {
+    /// Path to the analytics database.
+    #[arg(long, env = "SSA_DB", default_value = "analytics.db", global = true)]
+    db: PathBuf,
+
+    /// Print JSON instead of tables.
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Traffic, error and latency totals.
+    Summary {
+        #[command(flatten)]
+        range: Range,
+    },
+    /// Most common values of a field.
+    Top {
+        field: TopArg,
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: u32,
+        #[command(flatten)]
+        range: Range,
+    },
+    /// Show the most recent requests.
+    Tail {
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: u32,
+        /// Keep printing new requests as they are recorded.
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Delete data older than the given number of days.
+    Prune {
+        #[arg(long)]
+        older_than_days: i64,
+    },
+    /// Reclaim space left behind by deleted data.
+    Vacuum,
+    /// Apply any pending schema migrations, creating the database if needed.
+    /// Other commands expect it to be up to date.
+    Migrate,
+    /// Copy the database to a file while it is in use.
+    Backup { path: PathBuf },
+    /// Write rows to stdout.
+    Export {
+        table: TableArg,
+        #[arg(short, long, default_value = "jsonl")]
+        format: FormatArg,
+        #[command(flatten)]
+        range: Range,
+    },
+    /// Load historical access logs.
+    Import {
+        /// `common`, `combined` or an nginx `log_format` string.
+        #[arg(short, long, default_value = "combined")]
+        format: String,
+        /// Hostname to record when the format has no `$host`.
+        #[arg(long, default_value = "localhost")]
+        host: String,
+        /// Log files to read, or `-` for stdin.
+        #[arg(default_value = "-")]
+        files: Vec<PathBuf>,
+    },
+    /// Compress partitions older than the given number of days into the
+    /// partition directory's archive. Retention still deletes them once they
+    /// are older than its period.
+    Archive {
+        #[command(flatten)]
+        partitions: Partitions,
+        #[arg(long)]
+        older_than_days: i64,
+    },
+    /// Decompress an archived partition back into the partition directory.
+    Restore {
+        #[command(flatten)]
+        partitions: Partitions,
+        /// First day of the partition, e.g. `2023-10-01`.
+        start: NaiveDate,
+    },
+}
+
+#[derive(Debug, Args)]
+struct Partitions {
+    /// Directory holding one database file per partition.
+    #[arg(long, env = "SSA_PARTITIONS")]
+    dir: PathBuf,
+    #[arg(long, default_value = "month")]
+    period: PeriodArg,
+}
+
+impl Partitions {
+    async fn open(&self) -> anyhow::Result<PartitionedDb> {
+        PartitionedDb::new(&self.dir, self.period.into()).await
+    }
+}
+
+#[derive(Debug, Args)]
+struct Range {
+    /// Start of the range (RFC 3339), defaults to `--days` before `--to`.
+    #[arg(long)]
+    from: Option<DateTime<Utc>>,
+    /// End of the range (RFC 3339), defaults to now.
+    #[arg(long)]
+    to: Option<DateTime<Utc>>,
+    #[arg(long, default_value_t = 7)]
+    days: i64,
+}
+
+impl Range {
+    fn resolve(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = self.from.unwrap_or(to - chrono::Duration::days(self.days));
+        (from, to)
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum TopArg {
+    Paths,
+    Hosts,
+    Methods,
+    UserAgents,
+    Referrers,
+    Statuses,
+}
+
+impl From<TopArg> for TopField {
+    fn from(value: TopArg) -> Self {
+        match value {
+            TopArg::Paths => Self::Paths,
+            TopArg::Hosts => Self::Hosts,
+            TopArg::Methods => Self::Methods,
+            TopArg::UserAgents => Self::UserAgents,
+            TopArg::Referrers => Self::Referrers,
+            TopArg::Statuses => Self::Statuses,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum TableArg {
+    Connections,
+    Requests,
+    Responses,
+    /// Requests with their response in the same row.
+    Joined,
+}
+
+impl From<TableArg> for ExportTable {
+    fn from(value: TableArg) -> Self {
+        match value {
+            TableArg::Connections => Self::Connections,
+            TableArg::Requests => Self::Requests,
+            TableArg::Responses => Self::Responses,
+            TableArg::Joined => Self::Joined,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum FormatArg {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl From<FormatArg> for ExportFormat {
+    fn from(value: FormatArg) -> Self {
+        match value {
+            FormatArg::Csv => Self::Csv,
+            FormatArg::Jsonl => Self::Jsonl,
+            FormatArg::Parquet => Self::Parquet,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum PeriodArg {
+    Month,
+    Week,
+}
+
+impl From<PeriodArg> for PartitionPeriod {
+    fn from(value: PeriodArg) -> Self {
+        match value {
+            PeriodArg::Month => Self::Month,
+            PeriodArg::Week => Self::Week,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // These work on a partition directory rather than `--db`.
+    match &cli.command {
+        Command::Archive {
+            partitions,
+            older_than_days,
+        } => {
+            let before = Utc::now() - chrono::Duration::days(*older_than_days);
+            let counts = partitions.open().await?.archive(&before).await?;
+            if cli.json {
+                return print_json(&counts);
+            }
+
+            let mut table = Table::new(&["partitions", "bytes in", "bytes out"]);
+            table.row(vec![
+                counts.partitions.to_string(),
+                counts.bytes_in.to_string(),
+                counts.bytes_out.to_string(),
+            ]);
+            table.print();
+            return Ok(());
+        }
+        Command::Restore { partitions, start } => {
+            return partitions.open().await?.restore(*start).await;
+        }
+        _ => {}
+    }
+
+    // Only `migrate` changes the schema, and reports don't write at all.
+    let db = match &cli.command {
+        Command::Migrate => Db::new(&cli.db).await?,
+        Command::Summary { .. }
+        | Command::Top { .. }
+        | Command::Tail { .. }
+        | Command::Export { .. } => Db::open_read_only(&cli.db).await?,
+        _ => Db::open(&cli.db).await?,
+    };
+
+    match &cli.command {
+        Command::Summary { range } => {
+            let (from, to) = range.resolve();
+            let summary = db.stats().summary(&from, &to).await?;
+            if cli.json {
+                return print_json(&summary);
+            }
+
+            let ms = |v: Option<f64>| v.map_or("-".to_owned(), |v| format!("{v:.1}ms"));
+            let mut table = Table::new(&["metric", "value"]);
+            table.row(vec!["requests".into(), summary.requests.to_string()]);
+            table.row(vec!["visitors".into(), summary.visitors.to_string()]);
+            table.row(vec![
+                "responses".into(),
+                summary.responses.total.to_string(),
+            ]);
+            table.row(vec![
+                "4xx".into(),
+                summary.responses.client_errors.to_string(),
+            ]);
+            table.row(vec![
+                "5xx".into(),
+                summary.responses.server_errors.to_string(),
+            ]);
+            table.row(vec!["p50".into(), ms(summary.p50_ms)]);
+            table.row(vec!["p90".into(), ms(summary.p90_ms)]);
+            table.row(vec!["p99".into(), ms(summary.p99_ms)]);
+            table.print();
+        }
+        Command::Top {
+            field,
+            limit,
+            range,
+        } => {
+            let (from, to) = range.resolve();
+            let entries = db.stats().top((*field).into(), &from, &to, *limit).await?;
+            if cli.json {
+                return print_json(&entries);
+            }
+
+            let mut table = Table::new(&["value", "count"]);
+            for entry in entries {
+                table.row(vec![entry.value, entry.count.to_string()]);
+            }
+            table.print();
+        }
+        Command::Tail { lines, follow } => {
+            let mut requests = db.request_table().recent(*lines).await?;
+            requests.reverse();
+            print_requests(&requests, cli.json)?;
+
+            let mut last = requests
+                .last()
+                .map(|r| r.created_at)
+                .unwrap_or_else(Utc::now);
+            while *follow {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let requests = db.request_table().since(&last, 1000).await?;
+                if let Some(r) = requests.last() {
+                    last = r.created_at;
+                }
+                print_requests(&requests, cli.json)?;
+            }
+        }
+        Command::Prune { older_than_days } => {
+            let before = Utc::now() - chrono::Duration::days(*older_than_days);
+            let counts = db.prune(&before).await?;
+            if cli.json {
+                return print_json(&counts);
+            }
+
+            let mut table = Table::new(&["table", "deleted"]);
+            table.row(vec!["connections".into(), counts.connections.to_string()]);
+            table.row(vec!["requests".into(), counts.requests.to_string()]);
+            table.row(vec!["responses".into(), counts.responses.to_string()]);
+            table.print();
+        }
+        Command::Vacuum => {
+            db.vacuum().await?;
+        }
+        Command::Migrate => {
+            // Opening the database for this command applied any pending
+            // migrations.
+            let migrations = db.applied_migrations().await?;
+            if cli.json {
+                return print_json(&migrations);
+            }
+
+            let mut table = Table::new(&["version", "description"]);
+            for (version, description) in migrations {
+                table.row(vec![version.to_string(), description]);
+            }
+            table.print();
+        }
+        Command::Backup { path } => {
+            db.backup_to(path).await?;
+        }
+        Command::Export {
+            table,
+            format,
+            range,
+        } => {
+            let (from, to) = range.resolve();
+            db.exporter()
+                .export(
+                    (*table).into(),
+                    (*format).into(),
+                    &from,
+                    &to,
+                    tokio::io::stdout(),
+                )
+                .await?;
+        }
+        Command::Import {
+            format,
+            host,
+            files,
+        } => {
+            let format = match format.as_str() {
+                "common" => LogFormat::common(),
+                "combined" => LogFormat::combined(),
+                custom => custom.parse()?,
+            };
+
+            let mut table = Table::new(&["file", "imported", "skipped"]);
+            let mut total = ImportCounts::default();
+            for file in files {
+                let importer = db.importer();
+                let counts = if file.as_os_str() == "-" {
+                    let stdin = BufReader::new(tokio::io::stdin());
+                    importer.import(&format, host, stdin).await?
+                } else {
+                    let file = BufReader::new(File::open(file).await?);
+                    importer.import(&format, host, file).await?
+                };
+                total.imported += counts.imported;
+                total.skipped += counts.skipped;
+                table.row(vec![
+                    file.display().to_string(),
+                    counts.imported.to_string(),
+                    counts.skipped.to_string(),
+                ]);
+            }
+            if cli.json {
+                return print_json(&total);
+            }
+            table.print();
+        }
+        Command::Archive { .. } | Command::Restore { .. } => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn print_requests(requests: &[Request], json: bool) -> anyhow::Result<()> {
+    if requests.is_empty() {
+        return Ok(());
+    }
+    if json {
+        for request in requests {
+            println!("{}", serde_json::to_string(request)?);
+        }
+        return Ok(());
+    }
+
+    let mut table = Table::new(&["time", "method", "host", "path", "user agent"]);
+    for r in requests {
+        table.row(vec![
+            r.created_at.format("%F %T").to_string(),
+            r.method.clone(),
+            r.hostname.clone(),
+            r.path.clone(),
+            r.user_agent.clone(),
+        ]);
+    }
+    table.print();
+    Ok(())
+}
@@ -0,0 +1,45 @@
+/// A plain text table with left-aligned columns.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &[&str]) -> Self {
+        Self {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>) {
+        self.rows.push(cells);
+    }
+
+    pub fn print(&self) {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        print_row(&self.headers, &widths);
+        let underline: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+        print_row(&underline, &widths);
+        for row in &self.rows {
+            print_row(row, &widths);
+        }
+    }
+}
+
+fn print_row(cells: &[String], widths: &[usize]) {
+    let line = cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{cell:width$}"))
+        .collect::<Vec<_>>()
+        .join("  ");
+    println!("{}", line.trim_end());
+}
@@ -0,0 +1,224 @@
+//! Report latency over a generated database.
+//!
+//! The database is written to `target/bench-analytics.db` the first time the
+//! benchmarks run and reused afterwards; delete it to regenerate. Set
+//! `SSA_BENCH_REQUESTS` to change how many requests it holds.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use simple_id::chrono_id::Id as ChronoId;
+use simple_server_analytics_db::{
+    apdex::ApdexConfig, attribution::Attribution, stats::TopField, Connection, ConnectionTable, Db,
+    ExchangeTable, Request, Response,
+};
+use tokio::runtime::Runtime;
+
+const DEFAULT_REQUESTS: u64 = 2_000_000;
+const DAYS: i64 = 90;
+const REQUESTS_PER_CONNECTION: u64 = 8;
+const BATCH_SIZE: u64 = 10_000;
+
+const PATHS: &[&str] = &[
+    "/",
+    "/about",
+    "/blog",
+    "/blog/announcing-1-0",
+    "/blog/why-sqlite",
+    "/docs",
+    "/docs/getting-started",
+    "/docs/configuration",
+    "/api/events",
+    "/favicon.ico",
+];
+const USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (X11; Linux x86_64; rv:118.0) Gecko/20100101 Firefox/118.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Safari/605.1.15",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36",
+    "curl/8.4.0",
+];
+const REFERERS: &[Option<&str>] = &[
+    None,
+    None,
+    Some("https://www.google.com/"),
+    Some("https://news.ycombinator.com/"),
+    Some("https://github.com/"),
+];
+const STATUSES: &[u16] = &[200, 200, 200, 200, 200, 200, 304, 404, 500];
+
+/// xorshift64, so the generated data is the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[(self.next() % items.len() as u64) as usize]
+    }
+}
+
+fn start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2023, 7, 1, 0, 0, 0).unwrap()
+}
+
+fn bench_db_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../target")
+        .join("bench-analytics.db")
+}
+
+async fn generate(db: &Db, requests: u64) -> sqlx::Result<()> {
+    let mut rng = Rng(0x5eed);
+    let step = chrono::Duration::days(DAYS) / requests as i32;
+    let mut tx = db.begin().await?;
+    let mut connection: Option<Connection> = None;
+
+    for i in 0..requests {
+        let created_at = start() + step * i as i32;
+
+        if i % REQUESTS_PER_CONNECTION == 0 {
+            let ip = rng.next() % 20_000;
+            let conn = Connection {
+                id: ChronoId::from_datetime(created_at),
+                created_at,
+                local_addr: SocketAddr::from(([10, 0, 0, 1], 443)),
+                remote_addr: SocketAddr::from((
+                    [100, 64, (ip >> 8) as u8, ip as u8],
+                    (rng.next() % 60_000) as u16 + 1024,
+                )),
+                http_scheme: "https".to_owned(),
+                http_version: "HTTP/1.1".parse().unwrap(),
+            };
+            ConnectionTable::insert_record(&mut *tx, &conn).await?;
+            connection = Some(conn);
+        }
+
+        let hostname = "example.com";
+        let request = Request {
+            id: ChronoId::from_datetime(created_at),
+            created_at,
+            conn_id: connection.as_ref().map(|c| c.id),
+            method: if rng.next() % 10 == 0 { "POST" } else { "GET" }.to_owned(),
+            path: rng.pick(PATHS).to_string(),
+            hostname: hostname.to_owned(),
+            user_agent: rng.pick(USER_AGENTS).to_string(),
+            attribution: Attribution::parse(*rng.pick(REFERERS), hostname, None),
+            sample_weight: 1,
+            trace_id: None,
+            parent_span_id: None,
+            external_id: None,
+        };
+        let duration = Duration::from_micros(500 + rng.next() % 400_000);
+        let finished_at = created_at + chrono::Duration::from_std(duration).unwrap();
+        let response = Response {
+            id: ChronoId::from_datetime(finished_at),
+            created_at: finished_at,
+            conn_id: request.conn_id,
+            req_id: request.id,
+            duration,
+            status: *rng.pick(STATUSES),
+            sample_weight: 1,
+        };
+        ExchangeTable::insert_record(&mut *tx, &request, Some(&response)).await?;
+
+        if (i + 1) % BATCH_SIZE == 0 {
+            tx.commit().await?;
+            tx = db.begin().await?;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn open_bench_db() -> Db {
+    let path = bench_db_path();
+    let exists = path.exists();
+    let db = Db::new(&path).await.unwrap();
+    if !exists {
+        let requests = std::env::var("SSA_BENCH_REQUESTS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_REQUESTS);
+        eprintln!("Generating {requests} requests in {}", path.display());
+        generate(&db, requests).await.unwrap();
+        sqlx::query("ANALYZE").execute(&*db).await.unwrap();
+    }
+    db
+}
+
+fn reports(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let db = rt.block_on(open_bench_db());
+    let stats = db.stats();
+    let apdex = ApdexConfig::default();
+    let to = start() + chrono::Duration::days(DAYS);
+
+    let mut group = c.benchmark_group("reports");
+    group.sample_size(10);
+
+    for days in [1, 7, 30] {
+        let from = to - chrono::Duration::days(days);
+        let range = format!("{days}d");
+
+        group.bench_with_input(
+            BenchmarkId::new("request_count", &range),
+            &from,
+            |b, from| {
+                b.to_async(&rt)
+                    .iter(|| async { stats.request_count(from, &to).await.unwrap() })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("response_counts", &range),
+            &from,
+            |b, from| {
+                b.to_async(&rt)
+                    .iter(|| async { stats.response_counts(from, &to).await.unwrap() })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("summary", &range), &from, |b, from| {
+            b.to_async(&rt)
+                .iter(|| async { stats.summary(from, &to).await.unwrap() })
+        });
+        group.bench_with_input(BenchmarkId::new("top_paths", &range), &from, |b, from| {
+            b.to_async(&rt)
+                .iter(|| async { stats.top(TopField::Paths, from, &to, 10).await.unwrap() })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("top_statuses", &range),
+            &from,
+            |b, from| {
+                b.to_async(&rt)
+                    .iter(|| async { stats.top(TopField::Statuses, from, &to, 10).await.unwrap() })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("top_sources", &range), &from, |b, from| {
+            b.to_async(&rt)
+                .iter(|| async { stats.top_sources(from, &to, 10).await.unwrap() })
+        });
+        group.bench_with_input(BenchmarkId::new("cohorts", &range), &from, |b, from| {
+            b.to_async(&rt)
+                .iter(|| async { stats.cohorts(from, &to).await.unwrap() })
+        });
+        group.bench_with_input(BenchmarkId::new("apdex", &range), &from, |b, from| {
+            b.to_async(&rt)
+                .iter(|| async { stats.apdex(&apdex, from, &to).await.unwrap() })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, reports);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+CREATE TABLE "sa_connection" (
+    "id" BYTEA NOT NULL PRIMARY KEY,
+    "created_at" TIMESTAMPTZ NOT NULL,
+    "local_addr" INET NOT NULL,
+    "local_port" INTEGER NOT NULL,
+    "remote_addr" INET NOT NULL,
+    "remote_port" INTEGER NOT NULL,
+    "http_scheme" TEXT NOT NULL,
+    "http_version" TEXT NOT NULL
+);
+
+CREATE INDEX "sa_connection_created_at" ON "sa_connection" ("created_at");
+
+-- Partitioned by month, with partitions created by the application as they
+-- are needed. The partition key has to be part of the primary key, so
+-- responses can't have a foreign key to their request.
+CREATE TABLE "sa_request" (
+    "id" BYTEA NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL,
+    "conn_id" BYTEA NULL REFERENCES "sa_connection" ("id") ON DELETE SET NULL,
+    "method" TEXT NOT NULL,
+    "path" TEXT NOT NULL,
+    "hostname" TEXT NOT NULL,
+    "user_agent" TEXT NOT NULL,
+    "referrer_domain" TEXT NULL,
+    "referrer_category" TEXT NOT NULL DEFAULT 'direct',
+    "utm_source" TEXT NULL,
+    "utm_medium" TEXT NULL,
+    "utm_campaign" TEXT NULL,
+    "utm_term" TEXT NULL,
+    "utm_content" TEXT NULL,
+    PRIMARY KEY ("id", "created_at")
+) PARTITION BY RANGE ("created_at");
+
+CREATE INDEX "sa_request_created_at" ON "sa_request" ("created_at");
+
+CREATE TABLE "sa_response" (
+    "id" BYTEA NOT NULL PRIMARY KEY,
+    "created_at" TIMESTAMPTZ NOT NULL,
+    "conn_id" BYTEA NULL REFERENCES "sa_connection" ("id") ON DELETE SET NULL,
+    "req_id" BYTEA NOT NULL,
+    "duration_us" BIGINT NOT NULL,
+    "status" INTEGER NOT NULL
+);
+
+CREATE INDEX "sa_response_created_at" ON "sa_response" ("created_at");
+CREATE INDEX "sa_response_req_id" ON "sa_response" ("req_id");
+
+CREATE TABLE "sa_alert" (
+    "id" BYTEA NOT NULL PRIMARY KEY,
+    "rule" TEXT NOT NULL,
+    "state" TEXT NOT NULL,
+    "value" DOUBLE PRECISION NOT NULL,
+    "threshold" DOUBLE PRECISION NOT NULL,
+    "fired_at" TIMESTAMPTZ NOT NULL,
+    "resolved_at" TIMESTAMPTZ NULL
+);
+
+CREATE INDEX "sa_alert_rule_state" ON "sa_alert" ("rule", "state");
@@ -0,0 +1,8 @@
+-- Covering indexes for the common reports, matching the SQLite schema.
+CREATE INDEX "sa_request_created_at_path" ON "sa_request" ("created_at") INCLUDE ("path", "conn_id");
+CREATE INDEX "sa_response_created_at_status" ON "sa_response" ("created_at") INCLUDE ("status", "duration_us");
+
+-- Deleting a connection sets these to NULL, which is a full scan of each
+-- table without them.
+CREATE INDEX "sa_request_conn_id" ON "sa_request" ("conn_id");
+CREATE INDEX "sa_response_conn_id" ON "sa_response" ("conn_id");
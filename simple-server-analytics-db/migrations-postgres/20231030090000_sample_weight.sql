@@ -0,0 +1,9 @@
+-- How many requests each row stands for when only a sample is recorded.
+ALTER TABLE "sa_request" ADD COLUMN "sample_weight" BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE "sa_response" ADD COLUMN "sample_weight" BIGINT NOT NULL DEFAULT 1;
+
+-- Counts sum the weights, so keep the reports covered by their indexes.
+DROP INDEX "sa_request_created_at_path";
+DROP INDEX "sa_response_created_at_status";
+CREATE INDEX "sa_request_created_at_path" ON "sa_request" ("created_at") INCLUDE ("path", "conn_id", "sample_weight");
+CREATE INDEX "sa_response_created_at_status" ON "sa_response" ("created_at") INCLUDE ("status", "duration_us", "sample_weight");
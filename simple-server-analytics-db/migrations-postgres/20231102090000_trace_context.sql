@@ -0,0 +1,5 @@
+-- W3C Trace Context of the request, linking it to a distributed trace.
+ALTER TABLE "sa_request" ADD COLUMN "trace_id" TEXT NULL;
+ALTER TABLE "sa_request" ADD COLUMN "span_id" TEXT NULL;
+
+CREATE INDEX "sa_request_trace_id" ON "sa_request" ("trace_id") WHERE "trace_id" IS NOT NULL;
@@ -0,0 +1,5 @@
+-- The request id set by a trusted proxy in front. Rows keep their own generated
+-- id, as proxies don't guarantee theirs are unique.
+ALTER TABLE "sa_request" ADD COLUMN "external_id" TEXT NULL;
+
+CREATE INDEX "sa_request_external_id" ON "sa_request" ("external_id") WHERE "external_id" IS NOT NULL;
@@ -0,0 +1,2 @@
+-- The span id in `traceparent` is the caller's, not one of ours.
+ALTER TABLE "sa_request" RENAME COLUMN "span_id" TO "parent_span_id";
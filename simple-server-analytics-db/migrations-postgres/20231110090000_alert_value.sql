@@ -0,0 +1,2 @@
+-- Alerts that resolve because there was nothing left to measure have no value.
+ALTER TABLE "sa_alert" ALTER COLUMN "value" DROP NOT NULL;
@@ -0,0 +1,7 @@
+ALTER TABLE "sa_request" ADD COLUMN "referrer_domain" TEXT NULL;
+ALTER TABLE "sa_request" ADD COLUMN "referrer_category" TEXT NOT NULL DEFAULT 'direct';
+ALTER TABLE "sa_request" ADD COLUMN "utm_source" TEXT NULL;
+ALTER TABLE "sa_request" ADD COLUMN "utm_medium" TEXT NULL;
+ALTER TABLE "sa_request" ADD COLUMN "utm_campaign" TEXT NULL;
+ALTER TABLE "sa_request" ADD COLUMN "utm_term" TEXT NULL;
+ALTER TABLE "sa_request" ADD COLUMN "utm_content" TEXT NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE "sa_alert" (
+    "id" BLOB NOT NULL PRIMARY KEY,
+    "rule" TEXT NOT NULL,
+    "state" TEXT NOT NULL,
+    "value" REAL NOT NULL,
+    "threshold" REAL NOT NULL,
+    "fired_at" DATETIME NOT NULL,
+    "resolved_at" DATETIME NULL
+);
+
+CREATE INDEX "sa_alert_rule_state" ON "sa_alert" ("rule", "state");
@@ -0,0 +1,20 @@
+-- Every report filters on a created_at range, so each table leads with it.
+-- The extra columns let the common reports run from the index alone.
+CREATE INDEX "sa_connection_created_at" ON "sa_connection" ("created_at");
+
+-- Request counts, and visitors and cohorts joining to their connection.
+CREATE INDEX "sa_request_created_at_conn_id" ON "sa_request" ("created_at", "conn_id");
+-- Top paths, and the path lookups for SLOs and Apdex.
+CREATE INDEX "sa_request_created_at_path" ON "sa_request" ("created_at", "path");
+
+-- Response counts, top statuses and latency quantiles.
+CREATE INDEX "sa_response_created_at_status" ON "sa_response" ("created_at", "status", "duration");
+-- Joined exports, and pruning responses to deleted requests.
+CREATE INDEX "sa_response_req_id" ON "sa_response" ("req_id");
+
+-- Deleting a connection sets these to NULL, which is a full scan of each
+-- table without them.
+CREATE INDEX "sa_request_conn_id" ON "sa_request" ("conn_id");
+CREATE INDEX "sa_response_conn_id" ON "sa_response" ("conn_id");
+
+ANALYZE;
@@ -0,0 +1,89 @@
+-- A request and its response are recorded as one row once the response has
+-- been sent. Requests that never complete keep NULL response columns.
+CREATE TABLE "sa_exchange" (
+    "id" BLOB NOT NULL PRIMARY KEY,
+    "created_at" DATETIME NOT NULL,
+    "conn_id" BLOB NULL REFERENCES "sa_connection" ("id") ON DELETE SET NULL,
+    "method" TEXT NOT NULL,
+    "path" TEXT NOT NULL,
+    "hostname" TEXT NOT NULL,
+    "user_agent" TEXT NOT NULL,
+    "referrer_domain" TEXT NULL,
+    "referrer_category" TEXT NOT NULL DEFAULT 'direct',
+    "utm_source" TEXT NULL,
+    "utm_medium" TEXT NULL,
+    "utm_campaign" TEXT NULL,
+    "utm_term" TEXT NULL,
+    "utm_content" TEXT NULL,
+    "response_id" BLOB NULL,
+    "responded_at" DATETIME NULL,
+    "duration" TEXT NULL,
+    "status" INT NULL
+);
+
+-- Should a request somehow have several responses, the first one is kept.
+INSERT INTO "sa_exchange"
+SELECT
+    q."id",
+    q."created_at",
+    q."conn_id",
+    q."method",
+    q."path",
+    q."hostname",
+    q."user_agent",
+    q."referrer_domain",
+    q."referrer_category",
+    q."utm_source",
+    q."utm_medium",
+    q."utm_campaign",
+    q."utm_term",
+    q."utm_content",
+    r."id",
+    r."created_at",
+    r."duration",
+    r."status"
+FROM "sa_request" q
+LEFT JOIN "sa_response" r ON r."id" = (
+    SELECT "id" FROM "sa_response" WHERE "req_id" = q."id" ORDER BY "created_at" LIMIT 1
+);
+
+DROP TABLE "sa_response";
+DROP TABLE "sa_request";
+
+-- Reports read through views with the old table names and columns.
+CREATE VIEW "sa_request" AS
+SELECT
+    "id",
+    "created_at",
+    "conn_id",
+    "method",
+    "path",
+    "hostname",
+    "user_agent",
+    "referrer_domain",
+    "referrer_category",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content"
+FROM "sa_exchange";
+
+CREATE VIEW "sa_response" AS
+SELECT
+    "response_id" AS "id",
+    "responded_at" AS "created_at",
+    "conn_id",
+    "id" AS "req_id",
+    "duration",
+    "status"
+FROM "sa_exchange"
+WHERE "response_id" IS NOT NULL;
+
+CREATE INDEX "sa_exchange_created_at_conn_id" ON "sa_exchange" ("created_at", "conn_id");
+CREATE INDEX "sa_exchange_created_at_path" ON "sa_exchange" ("created_at", "path");
+CREATE INDEX "sa_exchange_responded_at_status" ON "sa_exchange" ("responded_at", "status", "duration")
+    WHERE "response_id" IS NOT NULL;
+CREATE INDEX "sa_exchange_conn_id" ON "sa_exchange" ("conn_id");
+
+ANALYZE;
@@ -0,0 +1,45 @@
+-- How many requests each row stands for when only a sample is recorded.
+ALTER TABLE "sa_exchange" ADD COLUMN "sample_weight" INTEGER NOT NULL DEFAULT 1;
+
+DROP VIEW "sa_request";
+DROP VIEW "sa_response";
+
+CREATE VIEW "sa_request" AS
+SELECT
+    "id",
+    "created_at",
+    "conn_id",
+    "method",
+    "path",
+    "hostname",
+    "user_agent",
+    "referrer_domain",
+    "referrer_category",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "sample_weight"
+FROM "sa_exchange";
+
+CREATE VIEW "sa_response" AS
+SELECT
+    "response_id" AS "id",
+    "responded_at" AS "created_at",
+    "conn_id",
+    "id" AS "req_id",
+    "duration",
+    "status",
+    "sample_weight"
+FROM "sa_exchange"
+WHERE "response_id" IS NOT NULL;
+
+-- Counts sum the weights, so keep the reports covered by their indexes.
+DROP INDEX "sa_exchange_created_at_path";
+DROP INDEX "sa_exchange_responded_at_status";
+CREATE INDEX "sa_exchange_created_at_path" ON "sa_exchange" ("created_at", "path", "sample_weight");
+CREATE INDEX "sa_exchange_responded_at_status" ON "sa_exchange" ("responded_at", "status", "duration", "sample_weight")
+    WHERE "response_id" IS NOT NULL;
+
+ANALYZE;
@@ -0,0 +1,28 @@
+-- W3C Trace Context of the request, linking it to a distributed trace.
+ALTER TABLE "sa_exchange" ADD COLUMN "trace_id" TEXT NULL;
+ALTER TABLE "sa_exchange" ADD COLUMN "span_id" TEXT NULL;
+
+DROP VIEW "sa_request";
+
+CREATE VIEW "sa_request" AS
+SELECT
+    "id",
+    "created_at",
+    "conn_id",
+    "method",
+    "path",
+    "hostname",
+    "user_agent",
+    "referrer_domain",
+    "referrer_category",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "sample_weight",
+    "trace_id",
+    "span_id"
+FROM "sa_exchange";
+
+CREATE INDEX "sa_exchange_trace_id" ON "sa_exchange" ("trace_id") WHERE "trace_id" IS NOT NULL;
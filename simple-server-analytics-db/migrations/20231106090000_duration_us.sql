@@ -0,0 +1,22 @@
+-- Durations as whole microseconds, so latency quantiles can be computed in SQL
+-- instead of after loading every duration. Rows from before this migration are
+-- filled in when the database is opened.
+ALTER TABLE "sa_exchange" ADD COLUMN "duration_us" INTEGER NULL;
+
+DROP VIEW "sa_response";
+
+CREATE VIEW "sa_response" AS
+SELECT
+    "response_id" AS "id",
+    "responded_at" AS "created_at",
+    "conn_id",
+    "id" AS "req_id",
+    "duration",
+    "duration_us",
+    "status",
+    "sample_weight"
+FROM "sa_exchange"
+WHERE "response_id" IS NOT NULL;
+
+CREATE INDEX "sa_exchange_responded_at_duration_us" ON "sa_exchange" ("responded_at", "duration_us", "sample_weight")
+    WHERE "response_id" IS NOT NULL;
@@ -0,0 +1,29 @@
+-- The request id set by a trusted proxy in front. Rows keep their own generated
+-- id, as proxies don't guarantee theirs are unique.
+ALTER TABLE "sa_exchange" ADD COLUMN "external_id" TEXT NULL;
+
+DROP VIEW "sa_request";
+
+CREATE VIEW "sa_request" AS
+SELECT
+    "id",
+    "created_at",
+    "conn_id",
+    "method",
+    "path",
+    "hostname",
+    "user_agent",
+    "referrer_domain",
+    "referrer_category",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "sample_weight",
+    "trace_id",
+    "span_id",
+    "external_id"
+FROM "sa_exchange";
+
+CREATE INDEX "sa_exchange_external_id" ON "sa_exchange" ("external_id") WHERE "external_id" IS NOT NULL;
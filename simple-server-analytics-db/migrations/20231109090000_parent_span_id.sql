@@ -0,0 +1,26 @@
+-- The span id in `traceparent` is the caller's, not one of ours.
+DROP VIEW "sa_request";
+
+ALTER TABLE "sa_exchange" RENAME COLUMN "span_id" TO "parent_span_id";
+
+CREATE VIEW "sa_request" AS
+SELECT
+    "id",
+    "created_at",
+    "conn_id",
+    "method",
+    "path",
+    "hostname",
+    "user_agent",
+    "referrer_domain",
+    "referrer_category",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "sample_weight",
+    "trace_id",
+    "parent_span_id",
+    "external_id"
+FROM "sa_exchange";
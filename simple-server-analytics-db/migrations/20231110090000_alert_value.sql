@@ -0,0 +1,18 @@
+-- Alerts that resolve because there was nothing left to measure have no value.
+CREATE TABLE "sa_alert_new" (
+    "id" BLOB NOT NULL PRIMARY KEY,
+    "rule" TEXT NOT NULL,
+    "state" TEXT NOT NULL,
+    "value" REAL NULL,
+    "threshold" REAL NOT NULL,
+    "fired_at" DATETIME NOT NULL,
+    "resolved_at" DATETIME NULL
+);
+
+INSERT INTO "sa_alert_new" SELECT * FROM "sa_alert";
+
+DROP TABLE "sa_alert";
+
+ALTER TABLE "sa_alert_new" RENAME TO "sa_alert";
+
+CREATE INDEX "sa_alert_rule_state" ON "sa_alert" ("rule", "state");
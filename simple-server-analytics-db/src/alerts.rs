@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use simple_id::chrono_id::Id as ChronoId;
+use sqlx::SqlitePool;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Alert {
+    pub id: ChronoId,
+    pub rule: String,
+    pub state: AlertState,
+    /// The measured value when it last fired or resolved, or `None` if it
+    /// resolved because there was nothing left to measure.
+    pub value: Option<f64>,
+    pub threshold: f64,
+    pub fired_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+impl Alert {
+    pub fn new(rule: &str, value: f64, threshold: f64) -> Self {
+        Self {
+            id: ChronoId::new(),
+            rule: rule.to_owned(),
+            state: AlertState::Firing,
+            value: Some(value),
+            threshold,
+            fired_at: Utc::now(),
+            resolved_at: None,
+        }
+    }
+
+    pub fn resolve(&mut self, value: Option<f64>) {
+        self.state = AlertState::Resolved;
+        self.value = value;
+        self.resolved_at = Some(Utc::now());
+    }
+}
+
+/// Alert history. At most one alert per rule is firing at a time, so a rule
+/// that stays in breach across evaluations only produces a single alert.
+#[derive(Debug, Clone)]
+pub struct AlertTable(pub(crate) SqlitePool);
+
+impl AlertTable {
+    pub async fn firing(&self, rule: &str) -> sqlx::Result<Option<Alert>> {
+        sqlx::query_as("SELECT * FROM sa_alert WHERE rule = ? AND state = 'firing'")
+            .bind(rule)
+            .fetch_optional(&self.0)
+            .await
+    }
+
+    pub async fn list(&self, limit: u32) -> sqlx::Result<Vec<Alert>> {
+        sqlx::query_as("SELECT * FROM sa_alert ORDER BY fired_at DESC LIMIT ?")
+            .bind(limit)
+            .fetch_all(&self.0)
+            .await
+    }
+
+    pub async fn fire(&self, rule: &str, value: f64, threshold: f64) -> sqlx::Result<Alert> {
+        let e = Alert::new(rule, value, threshold);
+
+        sqlx::query(
+            "
+            INSERT INTO sa_alert (
+                id,
+                rule,
+                state,
+                value,
+                threshold,
+                fired_at,
+                resolved_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?)
+        ",
+        )
+        .bind(&e.id)
+        .bind(&e.rule)
+        .bind(&e.state)
+        .bind(&e.value)
+        .bind(&e.threshold)
+        .bind(&e.fired_at)
+        .bind(&e.resolved_at)
+        .execute(&self.0)
+        .await?;
+
+        Ok(e)
+    }
+
+    pub async fn resolve(&self, mut alert: Alert, value: Option<f64>) -> sqlx::Result<Alert> {
+        alert.resolve(value);
+
+        sqlx::query("UPDATE sa_alert SET state = ?, value = ?, resolved_at = ? WHERE id = ?")
+            .bind(&alert.state)
+            .bind(&alert.value)
+            .bind(&alert.resolved_at)
+            .bind(&alert.id)
+            .execute(&self.0)
+            .await?;
+
+        Ok(alert)
+    }
+}
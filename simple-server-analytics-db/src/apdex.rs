@@ -0,0 +1,174 @@
+use chrono::{DateTime, Duration, Utc};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{human_readable_duration::HumanReadableDuration, stats::Stats};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApdexConfig {
+    /// Responses at or under this are satisfied, under four times this are
+    /// tolerating, and anything slower is frustrated.
+    pub threshold_ms: f64,
+    /// Per-route thresholds. The longest matching path prefix wins.
+    #[serde(default)]
+    pub routes: Vec<ApdexRoute>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApdexRoute {
+    pub path_prefix: String,
+    pub threshold_ms: f64,
+}
+
+impl Default for ApdexConfig {
+    fn default() -> Self {
+        Self {
+            threshold_ms: 500.0,
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl ApdexConfig {
+    pub fn threshold_ms(&self, path: &str) -> f64 {
+        self.routes
+            .iter()
+            .filter(|r| path.starts_with(&r.path_prefix))
+            .max_by_key(|r| r.path_prefix.len())
+            .map_or(self.threshold_ms, |r| r.threshold_ms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ApdexScore {
+    pub satisfied: u64,
+    pub tolerating: u64,
+    pub frustrated: u64,
+    /// `None` when there were no responses.
+    pub score: Option<f64>,
+}
+
+impl ApdexScore {
+    /// Server errors are always frustrated regardless of how fast they were.
+    /// `weight` is how many responses this one stands for.
+    fn record(&mut self, threshold_ms: f64, status: u16, duration_ms: f64, weight: u64) {
+        if status >= 500 || duration_ms > threshold_ms * 4.0 {
+            self.frustrated += weight;
+        } else if duration_ms > threshold_ms {
+            self.tolerating += weight;
+        } else {
+            self.satisfied += weight;
+        }
+
+        let total = self.satisfied + self.tolerating + self.frustrated;
+        self.score = Some((self.satisfied as f64 + self.tolerating as f64 / 2.0) / total as f64);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApdexPoint {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub apdex: ApdexScore,
+}
+
+/// Most points a timeseries is split into. Longer steps are used instead of
+/// going over it.
+pub const MAX_POINTS: i64 = 2000;
+
+/// Buckets responses into points `step` apart as they are fed in.
+pub(crate) struct ApdexSeries<'a> {
+    config: &'a ApdexConfig,
+    from: DateTime<Utc>,
+    step_secs: i64,
+    pub(crate) points: Vec<ApdexPoint>,
+}
+
+impl<'a> ApdexSeries<'a> {
+    pub(crate) fn new(
+        config: &'a ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        step: Duration,
+    ) -> Self {
+        let span_secs = (*to - *from).num_seconds().max(0);
+        let step_secs = step
+            .num_seconds()
+            .max(1)
+            .max((span_secs + MAX_POINTS - 1) / MAX_POINTS);
+        let steps = (span_secs + step_secs - 1) / step_secs;
+        let points = (0..steps.max(1))
+            .map(|i| ApdexPoint {
+                at: *from + Duration::seconds(i * step_secs),
+                apdex: ApdexScore::default(),
+            })
+            .collect();
+
+        Self {
+            config,
+            from: *from,
+            step_secs,
+            points,
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        created_at: &DateTime<Utc>,
+        path: &str,
+        status: u16,
+        duration: &std::time::Duration,
+        sample_weight: i64,
+    ) {
+        let i = ((*created_at - self.from).num_seconds() / self.step_secs) as usize;
+        if let Some(point) = self.points.get_mut(i) {
+            point.apdex.record(
+                self.config.threshold_ms(path),
+                status,
+                duration.as_secs_f64() * 1000.0,
+                sample_weight.max(0) as u64,
+            );
+        }
+    }
+}
+
+impl Stats {
+    pub async fn apdex(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> sqlx::Result<ApdexScore> {
+        let points = self.apdex_timeseries(config, from, to, *to - *from).await?;
+        Ok(points.first().map(|p| p.apdex).unwrap_or_default())
+    }
+
+    pub async fn apdex_timeseries(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        step: Duration,
+    ) -> sqlx::Result<Vec<ApdexPoint>> {
+        let mut series = ApdexSeries::new(config, from, to, step);
+
+        let mut rows =
+            sqlx::query_as::<_, (DateTime<Utc>, String, u16, HumanReadableDuration, i64)>(
+                "
+            SELECT r.created_at, q.path, r.status, r.duration, r.sample_weight
+            FROM sa_response r
+            JOIN sa_request q ON q.id = r.req_id
+            WHERE r.created_at >= ? AND r.created_at < ?
+        ",
+            )
+            .bind(from)
+            .bind(to)
+            .fetch(&self.0);
+
+        while let Some((created_at, path, status, duration, weight)) = rows.try_next().await? {
+            series.record(&created_at, &path, status, &duration.0, weight);
+        }
+
+        Ok(series.points)
+    }
+}
@@ -0,0 +1,73 @@
+//! zstd compression of whole database files, used to archive old partitions.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Archives are written once and rarely read, so favour size over speed.
+pub const LEVEL: i32 = 19;
+
+/// Extension appended to the name of an archived file.
+pub const EXTENSION: &str = "zst";
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ArchiveCounts {
+    pub partitions: u64,
+    /// Size of the archived database files.
+    pub bytes_in: u64,
+    /// Size of the compressed archives.
+    pub bytes_out: u64,
+}
+
+/// Compresses `src` into `dst`, returning the compressed size. `dst` only
+/// appears once it has been written in full.
+pub async fn compress_file(src: &Path, dst: &Path, level: i32) -> io::Result<u64> {
+    let (src, dst) = (src.to_owned(), dst.to_owned());
+    blocking(move || {
+        let tmp = tmp_path(&dst);
+        let mut reader = BufReader::new(File::open(&src)?);
+        let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(&tmp)?), level)?;
+        io::copy(&mut reader, &mut encoder)?;
+        let mut writer = encoder.finish()?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&tmp, &dst)?;
+        Ok(std::fs::metadata(&dst)?.len())
+    })
+    .await
+}
+
+/// Decompresses `src` into `dst`, returning the decompressed size. `dst` only
+/// appears once it has been written in full.
+pub async fn decompress_file(src: &Path, dst: &Path) -> io::Result<u64> {
+    let (src, dst) = (src.to_owned(), dst.to_owned());
+    blocking(move || {
+        let tmp = tmp_path(&dst);
+        let mut decoder = zstd::Decoder::new(File::open(&src)?)?;
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let len = io::copy(&mut decoder, &mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&tmp, &dst)?;
+        Ok(len)
+    })
+    .await
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A visitor's request starts a new session if they made none for this long.
+/// Source and campaign reports count only the first request of each session.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+const SEARCH_DOMAINS: &[&str] = &[
+    "google.",
+    "bing.com",
+    "duckduckgo.com",
+    "search.yahoo.",
+    "yandex.",
+    "baidu.com",
+    "ecosia.org",
+    "search.brave.com",
+    "startpage.com",
+    "kagi.com",
+];
+
+const SOCIAL_DOMAINS: &[&str] = &[
+    "facebook.com",
+    "instagram.com",
+    "t.co",
+    "twitter.com",
+    "x.com",
+    "linkedin.com",
+    "lnkd.in",
+    "reddit.com",
+    "news.ycombinator.com",
+    "pinterest.com",
+    "youtube.com",
+    "tiktok.com",
+    "mastodon.social",
+];
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum ReferrerCategory {
+    #[default]
+    Direct,
+    Internal,
+    Search,
+    Social,
+    Referral,
+}
+
+/// Where a request came from, taken from its `Referer` header and any `utm_*`
+/// query parameters. Campaign parameters are only kept on landing requests.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Attribution {
+    pub referrer_domain: Option<String>,
+    pub referrer_category: ReferrerCategory,
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub utm_term: Option<String>,
+    pub utm_content: Option<String>,
+}
+
+impl Attribution {
+    pub fn parse(referer: Option<&str>, hostname: &str, query: Option<&str>) -> Self {
+        let referrer_domain = referer
+            .and_then(|r| url::Url::parse(r).ok())
+            .and_then(|u| u.host_str().map(normalize_host));
+        // The host header may carry a port, and IPv6 hosts are bracketed as
+        // in URLs, e.g. `[::1]:8080`.
+        let host = match hostname.parse::<http::uri::Authority>() {
+            Ok(authority) => normalize_host(authority.host()),
+            Err(_) => normalize_host(hostname),
+        };
+
+        let referrer_category = match referrer_domain.as_deref() {
+            None => ReferrerCategory::Direct,
+            Some(domain) if domain == host => ReferrerCategory::Internal,
+            Some(domain) if is_search_engine(domain) => ReferrerCategory::Search,
+            Some(domain) if is_social(domain) => ReferrerCategory::Social,
+            Some(_) => ReferrerCategory::Referral,
+        };
+
+        let mut attribution = Self {
+            referrer_domain,
+            referrer_category,
+            ..Default::default()
+        };
+
+        if attribution.is_landing() {
+            for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+                let field = match key.as_ref() {
+                    "utm_source" => &mut attribution.utm_source,
+                    "utm_medium" => &mut attribution.utm_medium,
+                    "utm_campaign" => &mut attribution.utm_campaign,
+                    "utm_term" => &mut attribution.utm_term,
+                    "utm_content" => &mut attribution.utm_content,
+                    _ => continue,
+                };
+                if !value.is_empty() {
+                    *field = Some(value.into_owned());
+                }
+            }
+        }
+
+        attribution
+    }
+
+    /// Whether the request could start a session, by not having been
+    /// navigated to from within the site itself. Only those that do, as the
+    /// visitor's first request in [`SESSION_TIMEOUT`], count as landings.
+    pub fn is_landing(&self) -> bool {
+        self.referrer_category != ReferrerCategory::Internal
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    match host.strip_prefix("www.") {
+        Some(stripped) => stripped.to_owned(),
+        None => host,
+    }
+}
+
+/// Search engines are matched on the whole host, so other services on the
+/// same domain such as `mail.google.com` are not counted as search. Patterns
+/// ending in `.` match any public suffix, e.g. `google.` matches both
+/// `google.com` and `google.co.uk`.
+fn is_search_engine(domain: &str) -> bool {
+    SEARCH_DOMAINS
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('.') {
+            Some(name) => domain
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .is_some_and(is_public_suffix),
+            None => domain == *pattern,
+        })
+}
+
+/// Social networks are matched on their domain and any subdomain of it, e.g.
+/// `m.facebook.com`.
+fn is_social(domain: &str) -> bool {
+    SOCIAL_DOMAINS.iter().any(|pattern| {
+        domain == *pattern
+            || domain
+                .strip_suffix(pattern)
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+/// A top-level domain such as `com`, or a second-level one under a country
+/// code such as `co.uk`.
+fn is_public_suffix(suffix: &str) -> bool {
+    let is_label =
+        |label: &str| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphabetic());
+    match suffix.split_once('.') {
+        None => is_label(suffix),
+        Some((second, country)) => {
+            ["ac", "co", "com", "ne", "net", "or", "org"].contains(&second)
+                && country.len() == 2
+                && is_label(country)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(referer: &str, hostname: &str) -> ReferrerCategory {
+        Attribution::parse(Some(referer), hostname, None).referrer_category
+    }
+
+    #[test]
+    fn categorises_referrers() {
+        assert_eq!(
+            Attribution::parse(None, "example.com", None).referrer_category,
+            ReferrerCategory::Direct
+        );
+        assert_eq!(
+            category("https://www.example.com/pricing", "example.com:8080"),
+            ReferrerCategory::Internal
+        );
+        assert_eq!(
+            category("http://[::1]:8080/", "[::1]:8080"),
+            ReferrerCategory::Internal
+        );
+        assert_eq!(
+            category("https://www.google.co.uk/", "example.com"),
+            ReferrerCategory::Search
+        );
+        assert_eq!(
+            category("https://search.yahoo.co.jp/", "example.com"),
+            ReferrerCategory::Search
+        );
+        assert_eq!(
+            category("https://m.facebook.com/", "example.com"),
+            ReferrerCategory::Social
+        );
+        assert_eq!(
+            category("https://blog.example.org/post", "example.com"),
+            ReferrerCategory::Referral
+        );
+    }
+
+    #[test]
+    fn other_services_of_search_engines_are_referrals() {
+        for referer in [
+            "https://mail.google.com/",
+            "https://docs.google.com/document/d/1",
+            "https://google.example.com/",
+            "https://notbing.com/",
+        ] {
+            assert_eq!(
+                category(referer, "example.com"),
+                ReferrerCategory::Referral,
+                "{referer}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_campaign_only_on_landing() {
+        let landing = Attribution::parse(
+            Some("https://news.ycombinator.com/"),
+            "example.com",
+            Some("utm_source=hn&utm_medium=&utm_campaign=launch&ref=x"),
+        );
+        assert_eq!(
+            landing.referrer_domain.as_deref(),
+            Some("news.ycombinator.com")
+        );
+        assert_eq!(landing.referrer_category, ReferrerCategory::Social);
+        assert_eq!(landing.utm_source.as_deref(), Some("hn"));
+        assert_eq!(landing.utm_medium, None);
+        assert_eq!(landing.utm_campaign.as_deref(), Some("launch"));
+
+        let internal = Attribution::parse(
+            Some("https://example.com/"),
+            "example.com",
+            Some("utm_source=hn"),
+        );
+        assert!(!internal.is_landing());
+        assert_eq!(internal.utm_source, None);
+    }
+}
@@ -0,0 +1,898 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+};
+
+use arrow_array::{
+    ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use parquet::{arrow::ArrowWriter, file::properties::WriterProperties};
+use serde::{Deserialize, Serialize};
+use simple_id::chrono_id::Id as ChronoId;
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    human_readable_duration::HumanReadableDuration, store::AnalyticsStore, Connection, Request,
+    Response,
+};
+
+/// Rows are encoded and written out in batches of this many.
+const BATCH_SIZE: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv",
+            Self::Jsonl => "application/jsonl",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportTable {
+    Connections,
+    Requests,
+    Responses,
+    /// Requests with their response, if any, in the same row.
+    Joined,
+}
+
+impl ExportTable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Connections => "connections",
+            Self::Requests => "requests",
+            Self::Responses => "responses",
+            Self::Joined => "joined",
+        }
+    }
+
+    fn columns(&self) -> &'static [Column] {
+        match self {
+            Self::Connections => CONNECTION_COLUMNS,
+            Self::Requests => REQUEST_COLUMNS,
+            Self::Responses => RESPONSE_COLUMNS,
+            Self::Joined => JOINED_COLUMNS,
+        }
+    }
+
+    fn from_sql(&self) -> &'static str {
+        match self {
+            Self::Connections => "sa_connection c",
+            Self::Requests => "sa_request q",
+            Self::Responses => "sa_response r",
+            Self::Joined => "sa_request q LEFT JOIN sa_response r ON r.req_id = q.id",
+        }
+    }
+
+    fn created_at_sql(&self) -> &'static str {
+        match self {
+            Self::Connections => "c.created_at",
+            Self::Requests | Self::Joined => "q.created_at",
+            Self::Responses => "r.created_at",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ColumnKind {
+    Text,
+    /// Exported in the same form ids serialize to, whatever the backend.
+    Id,
+    Integer,
+    Timestamp,
+    /// Stored as human readable text, exported as milliseconds.
+    DurationMs,
+}
+
+#[derive(Debug)]
+struct Column {
+    name: &'static str,
+    sql: &'static str,
+    kind: ColumnKind,
+}
+
+const fn col(name: &'static str, sql: &'static str, kind: ColumnKind) -> Column {
+    Column { name, sql, kind }
+}
+
+const CONNECTION_COLUMNS: &[Column] = &[
+    col("id", "c.id", ColumnKind::Id),
+    col("created_at", "c.created_at", ColumnKind::Timestamp),
+    col("local_addr", "c.local_addr", ColumnKind::Text),
+    col("remote_addr", "c.remote_addr", ColumnKind::Text),
+    col("http_scheme", "c.http_scheme", ColumnKind::Text),
+    col("http_version", "c.http_version", ColumnKind::Text),
+];
+
+const REQUEST_COLUMNS: &[Column] = &[
+    col("id", "q.id", ColumnKind::Id),
+    col("created_at", "q.created_at", ColumnKind::Timestamp),
+    col("conn_id", "q.conn_id", ColumnKind::Id),
+    col("method", "q.method", ColumnKind::Text),
+    col("path", "q.path", ColumnKind::Text),
+    col("hostname", "q.hostname", ColumnKind::Text),
+    col("user_agent", "q.user_agent", ColumnKind::Text),
+    col("referrer_domain", "q.referrer_domain", ColumnKind::Text),
+    col("referrer_category", "q.referrer_category", ColumnKind::Text),
+    col("utm_source", "q.utm_source", ColumnKind::Text),
+    col("utm_medium", "q.utm_medium", ColumnKind::Text),
+    col("utm_campaign", "q.utm_campaign", ColumnKind::Text),
+    col("utm_term", "q.utm_term", ColumnKind::Text),
+    col("utm_content", "q.utm_content", ColumnKind::Text),
+    col("sample_weight", "q.sample_weight", ColumnKind::Integer),
+    col("trace_id", "q.trace_id", ColumnKind::Text),
+    col("parent_span_id", "q.parent_span_id", ColumnKind::Text),
+    col("external_id", "q.external_id", ColumnKind::Text),
+];
+
+const RESPONSE_COLUMNS: &[Column] = &[
+    col("id", "r.id", ColumnKind::Id),
+    col("created_at", "r.created_at", ColumnKind::Timestamp),
+    col("conn_id", "r.conn_id", ColumnKind::Id),
+    col("req_id", "r.req_id", ColumnKind::Id),
+    col("duration_ms", "r.duration", ColumnKind::DurationMs),
+    col("status", "r.status", ColumnKind::Integer),
+    col("sample_weight", "r.sample_weight", ColumnKind::Integer),
+];
+
+const JOINED_COLUMNS: &[Column] = &[
+    col("id", "q.id", ColumnKind::Id),
+    col("created_at", "q.created_at", ColumnKind::Timestamp),
+    col("conn_id", "q.conn_id", ColumnKind::Id),
+    col("method", "q.method", ColumnKind::Text),
+    col("path", "q.path", ColumnKind::Text),
+    col("hostname", "q.hostname", ColumnKind::Text),
+    col("user_agent", "q.user_agent", ColumnKind::Text),
+    col("referrer_domain", "q.referrer_domain", ColumnKind::Text),
+    col("referrer_category", "q.referrer_category", ColumnKind::Text),
+    col("utm_source", "q.utm_source", ColumnKind::Text),
+    col("utm_medium", "q.utm_medium", ColumnKind::Text),
+    col("utm_campaign", "q.utm_campaign", ColumnKind::Text),
+    col("utm_term", "q.utm_term", ColumnKind::Text),
+    col("utm_content", "q.utm_content", ColumnKind::Text),
+    col("sample_weight", "q.sample_weight", ColumnKind::Integer),
+    col("trace_id", "q.trace_id", ColumnKind::Text),
+    col("parent_span_id", "q.parent_span_id", ColumnKind::Text),
+    col("external_id", "q.external_id", ColumnKind::Text),
+    col("response_id", "r.id", ColumnKind::Id),
+    col("responded_at", "r.created_at", ColumnKind::Timestamp),
+    col("duration_ms", "r.duration", ColumnKind::DurationMs),
+    col("status", "r.status", ColumnKind::Integer),
+];
+
+#[derive(Debug, Clone)]
+enum Value {
+    Null,
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    Timestamp(DateTime<Utc>),
+}
+
+impl Value {
+    fn decode(row: &SqliteRow, i: usize, kind: ColumnKind) -> sqlx::Result<Self> {
+        Ok(match kind {
+            ColumnKind::Text => row.try_get::<Option<String>, _>(i)?.map(Self::Text),
+            ColumnKind::Id => row
+                .try_get::<Option<ChronoId>, _>(i)?
+                .map(|id| id_value(&id)),
+            ColumnKind::Integer => row.try_get::<Option<i64>, _>(i)?.map(Self::Integer),
+            ColumnKind::Timestamp => row
+                .try_get::<Option<DateTime<Utc>>, _>(i)?
+                .map(Self::Timestamp),
+            ColumnKind::DurationMs => row
+                .try_get::<Option<HumanReadableDuration>, _>(i)?
+                .map(|d| Self::Float(d.0.as_secs_f64() * 1000.0)),
+        }
+        .unwrap_or(Self::Null))
+    }
+
+    fn to_csv(&self) -> String {
+        match self {
+            Self::Null => String::new(),
+            Self::Text(s) => s.clone(),
+            Self::Integer(i) => i.to_string(),
+            Self::Float(f) => f.to_string(),
+            Self::Timestamp(t) => t.to_rfc3339(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Null => serde_json::Value::Null,
+            Self::Text(s) => s.clone().into(),
+            Self::Integer(i) => (*i).into(),
+            Self::Float(f) => (*f).into(),
+            Self::Timestamp(t) => t.to_rfc3339().into(),
+        }
+    }
+}
+
+enum Encoder {
+    Csv,
+    Jsonl,
+    Parquet(ArrowWriter<Vec<u8>>, SchemaRef),
+}
+
+impl Encoder {
+    fn new(format: ExportFormat, columns: &[Column], out: &mut Vec<u8>) -> anyhow::Result<Self> {
+        Ok(match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer.write_record(columns.iter().map(|c| c.name))?;
+                writer.flush()?;
+                Self::Csv
+            }
+            ExportFormat::Jsonl => Self::Jsonl,
+            ExportFormat::Parquet => {
+                // One row group per batch, so each is written out as soon as
+                // it is encoded rather than held until a large group fills.
+                let schema = arrow_schema(columns);
+                let properties = WriterProperties::builder()
+                    .set_max_row_group_size(BATCH_SIZE)
+                    .build();
+                Self::Parquet(
+                    ArrowWriter::try_new(Vec::new(), schema.clone(), Some(properties))?,
+                    schema,
+                )
+            }
+        })
+    }
+
+    fn encode(
+        &mut self,
+        columns: &[Column],
+        rows: &[Vec<Value>],
+        out: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(out);
+                for row in rows {
+                    writer.write_record(row.iter().map(Value::to_csv))?;
+                }
+                writer.flush()?;
+            }
+            Self::Jsonl => {
+                for row in rows {
+                    let object: serde_json::Map<_, _> = columns
+                        .iter()
+                        .zip(row)
+                        .map(|(c, v)| (c.name.to_owned(), v.to_json()))
+                        .collect();
+                    serde_json::to_writer(&mut *out, &object)?;
+                    out.push(b'\n');
+                }
+            }
+            Self::Parquet(writer, schema) => {
+                writer.write(&record_batch(schema.clone(), columns, rows)?)?;
+                writer.flush()?;
+                out.append(writer.inner_mut());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        if let Self::Parquet(writer, _) = self {
+            out.append(&mut writer.into_inner()?);
+        }
+        Ok(())
+    }
+}
+
+fn arrow_schema(columns: &[Column]) -> SchemaRef {
+    Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|c| {
+                let data_type = match c.kind {
+                    ColumnKind::Text | ColumnKind::Id => DataType::Utf8,
+                    ColumnKind::Integer => DataType::Int64,
+                    ColumnKind::Timestamp => {
+                        DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+                    }
+                    ColumnKind::DurationMs => DataType::Float64,
+                };
+                Field::new(c.name, data_type, true)
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+fn record_batch(
+    schema: SchemaRef,
+    columns: &[Column],
+    rows: &[Vec<Value>],
+) -> anyhow::Result<RecordBatch> {
+    let arrays = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| -> ArrayRef {
+            let values = rows.iter().map(|row| &row[i]);
+            match c.kind {
+                ColumnKind::Text | ColumnKind::Id => {
+                    Arc::new(StringArray::from_iter(values.map(|v| match v {
+                        Value::Text(s) => Some(s.as_str()),
+                        _ => None,
+                    })))
+                }
+                ColumnKind::Integer => Arc::new(Int64Array::from_iter(values.map(|v| match v {
+                    Value::Integer(i) => Some(*i),
+                    _ => None,
+                }))),
+                ColumnKind::Timestamp => Arc::new(
+                    TimestampMicrosecondArray::from_iter(values.map(|v| match v {
+                        Value::Timestamp(t) => Some(t.timestamp_micros()),
+                        _ => None,
+                    }))
+                    .with_timezone("UTC"),
+                ),
+                ColumnKind::DurationMs => {
+                    Arc::new(Float64Array::from_iter(values.map(|v| match v {
+                        Value::Float(f) => Some(*f),
+                        _ => None,
+                    })))
+                }
+            }
+        })
+        .collect();
+
+    Ok(RecordBatch::try_new(schema, arrays)?)
+}
+
+/// Streams table rows out in a file format without holding more than a batch
+/// of rows in memory.
+#[derive(Debug, Clone)]
+pub struct Exporter(pub(crate) SqlitePool);
+
+impl Exporter {
+    /// Writes every row created in `[from, to)` to `writer`, returning the
+    /// number of rows written.
+    pub async fn export<W: AsyncWrite + Unpin>(
+        &self,
+        table: ExportTable,
+        format: ExportFormat,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        writer: W,
+    ) -> anyhow::Result<u64> {
+        let columns = table.columns();
+        let select = columns.iter().map(|c| c.sql).collect::<Vec<_>>().join(", ");
+        let created_at = table.created_at_sql();
+        let sql = format!(
+            "SELECT {select} FROM {} WHERE {created_at} >= ? AND {created_at} < ? ORDER BY {created_at}",
+            table.from_sql()
+        );
+
+        let rows = sqlx::query(&sql).bind(from).bind(to).fetch(&self.0).map(
+            |row| -> anyhow::Result<Vec<Value>> {
+                let row = row?;
+                Ok(columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| Value::decode(&row, i, c.kind))
+                    .collect::<sqlx::Result<Vec<_>>>()?)
+            },
+        );
+
+        write_rows(columns, format, rows, writer).await
+    }
+}
+
+/// How long after its request a response may have been recorded and still be
+/// joined to it by [`export_records`].
+const JOIN_GRACE_HOURS: i64 = 1;
+
+/// Exports from any store by scanning its records.
+///
+/// Joined rows are built by scanning requests and responses side by side, so
+/// only the responses recorded within [`JOIN_GRACE_HOURS`] of the request
+/// being written are held in memory.
+pub(crate) async fn export_records<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    table: ExportTable,
+    format: ExportFormat,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    writer: &mut (dyn AsyncWrite + Send + Unpin),
+) -> anyhow::Result<u64> {
+    let columns = table.columns();
+    match table {
+        ExportTable::Connections => {
+            let rows = store
+                .connections(from, to)
+                .map_ok(|c| connection_values(&c));
+            write_rows(columns, format, rows, writer).await
+        }
+        ExportTable::Requests => {
+            let rows = store.requests(from, to).map_ok(|r| request_values(&r));
+            write_rows(columns, format, rows, writer).await
+        }
+        ExportTable::Responses => {
+            let rows = store
+                .responses(from, to)
+                .map_ok(|r| response_values(Some(&r)));
+            write_rows(columns, format, rows, writer).await
+        }
+        ExportTable::Joined => {
+            let grace = chrono::Duration::hours(JOIN_GRACE_HOURS);
+            let until = *to + grace;
+            let state = (
+                store.requests(from, to),
+                store.responses(from, &until).peekable(),
+                PendingResponses::default(),
+            );
+            let rows = futures_util::stream::try_unfold(
+                state,
+                |(mut requests, mut responses, mut pending)| async move {
+                    let Some(request) = requests.try_next().await? else {
+                        return Ok(None);
+                    };
+                    let horizon = request.created_at + grace;
+                    loop {
+                        let due = match Pin::new(&mut responses).peek().await {
+                            Some(Ok(r)) => r.created_at < horizon,
+                            Some(Err(_)) => true,
+                            None => false,
+                        };
+                        if !due {
+                            break;
+                        }
+                        if let Some(response) = responses.try_next().await? {
+                            pending.push(response);
+                        }
+                    }
+
+                    let response = pending.take(&request);
+                    let mut values = request_values(&request);
+                    values.push(response.as_ref().map_or(Value::Null, |r| id_value(&r.id)));
+                    values.push(
+                        response
+                            .as_ref()
+                            .map_or(Value::Null, |r| Value::Timestamp(r.created_at)),
+                    );
+                    values.extend(
+                        response_values(response.as_ref())
+                            .into_iter()
+                            .skip(4)
+                            .take(2),
+                    );
+                    Ok::<_, anyhow::Error>(Some((values, (requests, responses, pending))))
+                },
+            );
+            write_rows(columns, format, Box::pin(rows), writer).await
+        }
+    }
+}
+
+/// Responses read ahead of the requests they belong to, in the order they
+/// were recorded.
+#[derive(Default)]
+struct PendingResponses {
+    by_request: BTreeMap<ChronoId, Response>,
+    recorded: VecDeque<(DateTime<Utc>, ChronoId)>,
+}
+
+impl PendingResponses {
+    fn push(&mut self, response: Response) {
+        self.recorded
+            .push_back((response.created_at, response.req_id));
+        self.by_request.insert(response.req_id, response);
+    }
+
+    /// The response to `request`, dropping any recorded before it. Those
+    /// belong to earlier requests, as responses come after their request.
+    fn take(&mut self, request: &Request) -> Option<Response> {
+        while let Some((recorded_at, req_id)) = self.recorded.front() {
+            if *recorded_at >= request.created_at {
+                break;
+            }
+            self.by_request.remove(req_id);
+            self.recorded.pop_front();
+        }
+        self.by_request.remove(&request.id)
+    }
+}
+
+fn id_value(id: &ChronoId) -> Value {
+    match serde_json::to_value(id) {
+        Ok(serde_json::Value::String(s)) => Value::Text(s),
+        Ok(other) => Value::Text(other.to_string()),
+        Err(_) => Value::Null,
+    }
+}
+
+fn text_value(s: Option<&str>) -> Value {
+    s.map_or(Value::Null, |s| Value::Text(s.to_owned()))
+}
+
+fn connection_values(c: &Connection) -> Vec<Value> {
+    vec![
+        id_value(&c.id),
+        Value::Timestamp(c.created_at),
+        Value::Text(c.local_addr.to_string()),
+        Value::Text(c.remote_addr.to_string()),
+        Value::Text(c.http_scheme.clone()),
+        Value::Text(c.http_version.to_string()),
+    ]
+}
+
+fn request_values(r: &Request) -> Vec<Value> {
+    let a = &r.attribution;
+    vec![
+        id_value(&r.id),
+        Value::Timestamp(r.created_at),
+        r.conn_id.as_ref().map_or(Value::Null, id_value),
+        Value::Text(r.method.clone()),
+        Value::Text(r.path.clone()),
+        Value::Text(r.hostname.clone()),
+        Value::Text(r.user_agent.clone()),
+        text_value(a.referrer_domain.as_deref()),
+        serde_json::to_value(a.referrer_category)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| Value::Text(s.to_owned())))
+            .unwrap_or(Value::Null),
+        text_value(a.utm_source.as_deref()),
+        text_value(a.utm_medium.as_deref()),
+        text_value(a.utm_campaign.as_deref()),
+        text_value(a.utm_term.as_deref()),
+        text_value(a.utm_content.as_deref()),
+        Value::Integer(r.sample_weight),
+        text_value(r.trace_id.as_deref()),
+        text_value(r.parent_span_id.as_deref()),
+        text_value(r.external_id.as_deref()),
+    ]
+}
+
+/// All nulls for a missing response, so joined rows keep their shape.
+fn response_values(r: Option<&Response>) -> Vec<Value> {
+    let Some(r) = r else {
+        return vec![Value::Null; RESPONSE_COLUMNS.len()];
+    };
+    vec![
+        id_value(&r.id),
+        Value::Timestamp(r.created_at),
+        r.conn_id.as_ref().map_or(Value::Null, id_value),
+        id_value(&r.req_id),
+        Value::Float(r.duration.as_secs_f64() * 1000.0),
+        Value::Integer(r.status.into()),
+        Value::Integer(r.sample_weight),
+    ]
+}
+
+async fn write_rows<W, S>(
+    columns: &[Column],
+    format: ExportFormat,
+    mut rows: S,
+    mut writer: W,
+) -> anyhow::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+    S: Stream<Item = anyhow::Result<Vec<Value>>> + Unpin,
+{
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(format, columns, &mut out)?;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut count = 0;
+
+    while let Some(row) = rows.try_next().await? {
+        batch.push(row);
+
+        if batch.len() == BATCH_SIZE {
+            encoder.encode(columns, &batch, &mut out)?;
+            count += batch.len() as u64;
+            batch.clear();
+            writer.write_all(&out).await?;
+            out.clear();
+        }
+    }
+
+    encoder.encode(columns, &batch, &mut out)?;
+    count += batch.len() as u64;
+    encoder.finish(&mut out)?;
+    writer.write_all(&out).await?;
+    writer.flush().await?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::{attribution::Attribution, store::memory::MemoryStore, Db};
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        "2023-11-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap() + chrono::Duration::seconds(secs)
+    }
+
+    fn id_text(id: &ChronoId) -> String {
+        match id_value(id) {
+            Value::Text(s) => s,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    fn request(id: ChronoId, created_at: DateTime<Utc>, conn_id: Option<ChronoId>) -> Request {
+        Request {
+            id,
+            created_at,
+            conn_id,
+            method: "GET".to_owned(),
+            path: "/items".to_owned(),
+            hostname: "example.com".to_owned(),
+            user_agent: "curl/8.0".to_owned(),
+            attribution: Attribution::default(),
+            sample_weight: 1,
+            trace_id: None,
+            parent_span_id: None,
+            external_id: None,
+        }
+    }
+
+    fn response(request: &Request, duration: Duration, status: u16) -> Response {
+        Response {
+            id: ChronoId::from_datetime(
+                request.created_at + chrono::Duration::from_std(duration).unwrap(),
+            ),
+            created_at: request.created_at + chrono::Duration::from_std(duration).unwrap(),
+            conn_id: request.conn_id,
+            req_id: request.id,
+            duration,
+            status,
+            sample_weight: 1,
+        }
+    }
+
+    /// The first request's response is recorded after the third's, and the
+    /// second request has none.
+    fn exchanges(conn_id: ChronoId) -> Vec<(Request, Option<Response>)> {
+        let first = request(ChronoId::from_datetime(at(1)), at(1), Some(conn_id));
+        let second = request(ChronoId::from_datetime(at(2)), at(2), None);
+        let third = request(ChronoId::from_datetime(at(3)), at(3), None);
+        vec![
+            (
+                first.clone(),
+                Some(response(&first, Duration::from_secs(9), 200)),
+            ),
+            (second, None),
+            (
+                third.clone(),
+                Some(response(&third, Duration::from_millis(500), 503)),
+            ),
+        ]
+    }
+
+    async fn fill(store: &dyn AnalyticsStore) -> (ChronoId, Vec<(Request, Option<Response>)>) {
+        let connection = Connection {
+            id: ChronoId::from_datetime(at(0)),
+            created_at: at(0),
+            local_addr: "127.0.0.1:443".parse().unwrap(),
+            remote_addr: "203.0.113.7:50000".parse().unwrap(),
+            http_scheme: "https".to_owned(),
+            http_version: "HTTP/1.1".parse().unwrap(),
+        };
+        store.insert_connection(&connection).await.unwrap();
+        let exchanges = exchanges(connection.id);
+        for (request, response) in &exchanges {
+            store
+                .insert_exchange(request, response.as_ref())
+                .await
+                .unwrap();
+        }
+        (connection.id, exchanges)
+    }
+
+    async fn sqlite() -> Db {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let db = Db::from_pool(pool);
+        db.migrate().await.unwrap();
+        db
+    }
+
+    async fn export(
+        store: &dyn AnalyticsStore,
+        table: ExportTable,
+        format: ExportFormat,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        store
+            .export(table, format, &at(0), &at(3600), &mut out)
+            .await
+            .unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn backends_export_the_same_rows() {
+        let db = sqlite().await;
+        let memory = MemoryStore::new(100);
+        fill(&db).await;
+        fill(&memory).await;
+
+        for table in [
+            ExportTable::Connections,
+            ExportTable::Requests,
+            ExportTable::Responses,
+            ExportTable::Joined,
+        ] {
+            for format in [ExportFormat::Csv, ExportFormat::Jsonl] {
+                assert_eq!(
+                    String::from_utf8(export(&db, table, format).await).unwrap(),
+                    String::from_utf8(export(&memory, table, format).await).unwrap(),
+                    "{table:?} as {format:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn joined_csv_round_trips() {
+        let memory = MemoryStore::new(100);
+        let (conn_id, exchanges) = fill(&memory).await;
+        let out = export(&memory, ExportTable::Joined, ExportFormat::Csv).await;
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let headers: Vec<String> = reader
+            .headers()
+            .unwrap()
+            .iter()
+            .map(str::to_owned)
+            .collect();
+        let names: Vec<&str> = JOINED_COLUMNS.iter().map(|c| c.name).collect();
+        assert_eq!(headers, names);
+
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 3);
+        let field = |row: &csv::StringRecord, name: &str| {
+            row[names.iter().position(|n| *n == name).unwrap()].to_owned()
+        };
+        let (first, first_response) = &exchanges[0];
+        assert_eq!(field(&rows[0], "id"), id_text(&first.id));
+        assert_eq!(field(&rows[0], "conn_id"), id_text(&conn_id));
+        assert_eq!(field(&rows[0], "created_at"), at(1).to_rfc3339());
+        assert_eq!(
+            field(&rows[0], "response_id"),
+            id_text(&first_response.as_ref().unwrap().id)
+        );
+        assert_eq!(field(&rows[0], "responded_at"), at(10).to_rfc3339());
+        assert_eq!(field(&rows[0], "duration_ms"), "9000");
+        assert_eq!(field(&rows[0], "status"), "200");
+        assert_eq!(field(&rows[1], "conn_id"), "");
+        assert_eq!(field(&rows[1], "response_id"), "");
+        assert_eq!(field(&rows[1], "status"), "");
+        assert_eq!(field(&rows[2], "duration_ms"), "500");
+        assert_eq!(field(&rows[2], "status"), "503");
+    }
+
+    #[tokio::test]
+    async fn joined_jsonl_round_trips() {
+        let memory = MemoryStore::new(100);
+        let (_, exchanges) = fill(&memory).await;
+        let out = export(&memory, ExportTable::Joined, ExportFormat::Jsonl).await;
+
+        let rows: Vec<serde_json::Value> = out
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        for (row, (request, _)) in rows.iter().zip(&exchanges) {
+            assert_eq!(row["id"], id_text(&request.id));
+            assert_eq!(row["method"], "GET");
+            assert_eq!(row["referrer_category"], "direct");
+            assert_eq!(row["sample_weight"], 1);
+            assert_eq!(
+                row.as_object().unwrap().len(),
+                JOINED_COLUMNS.len(),
+                "{row}"
+            );
+        }
+        assert_eq!(rows[0]["duration_ms"], 9000.0);
+        assert_eq!(rows[1]["response_id"], serde_json::Value::Null);
+        assert_eq!(rows[2]["status"], 503);
+    }
+
+    #[tokio::test]
+    async fn joined_parquet_round_trips() {
+        let db = sqlite().await;
+        let (_, exchanges) = fill(&db).await;
+        let out = export(&db, ExportTable::Joined, ExportFormat::Parquet).await;
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(out))
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let names: Vec<&str> = JOINED_COLUMNS.iter().map(|c| c.name).collect();
+        let fields: Vec<&str> = batch
+            .schema_ref()
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect();
+        assert_eq!(fields, names);
+
+        let ids = batch
+            .column_by_name("id")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let expected: Vec<String> = exchanges.iter().map(|(r, _)| id_text(&r.id)).collect();
+        assert_eq!(ids.iter().map(Option::unwrap).collect::<Vec<_>>(), expected);
+        let statuses = batch
+            .column_by_name("status")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(
+            statuses.iter().collect::<Vec<_>>(),
+            [Some(200), None, Some(503)]
+        );
+        let created = batch
+            .column_by_name("created_at")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .unwrap();
+        assert_eq!(created.value(2), at(3).timestamp_micros());
+    }
+
+    #[test]
+    fn pending_responses_drop_those_of_earlier_requests() {
+        let exchanges = exchanges(ChronoId::from_datetime(at(0)));
+        // A response whose request fell before the range.
+        let earlier = request(ChronoId::from_datetime(at(-5)), at(-5), None);
+
+        let mut pending = PendingResponses::default();
+        pending.push(response(&earlier, Duration::from_secs(1), 204));
+        pending.push(exchanges[2].1.clone().unwrap());
+        pending.push(exchanges[0].1.clone().unwrap());
+
+        assert_eq!(pending.take(&exchanges[0].0).unwrap().status, 200);
+        assert!(pending.take(&exchanges[1].0).is_none());
+        assert_eq!(pending.take(&exchanges[2].0).unwrap().status, 503);
+        assert!(pending.by_request.is_empty());
+    }
+}
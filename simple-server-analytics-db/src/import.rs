@@ -0,0 +1,393 @@
+use std::{collections::BTreeMap, net::IpAddr, net::SocketAddr, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use simple_id::chrono_id::Id as ChronoId;
+use sqlx::{SqliteConnection, SqlitePool};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::{
+    attribution::Attribution, Connection, ConnectionTable, ExchangeTable, Request, Response,
+};
+
+/// Lines are committed in transactions of this many.
+const BATCH_SIZE: u64 = 1000;
+
+/// How far back from the latest log time [`IdClock`] remembers the times it
+/// has handed out ids at, as log lines are only roughly in order.
+const ID_MEMORY_HOURS: i64 = 1;
+
+/// A log line format in nginx `log_format` syntax, e.g. `$remote_addr [$time_local] "$request"`.
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    regex: Regex,
+}
+
+impl LogFormat {
+    /// Common Log Format, as written by Apache and nginx.
+    pub const COMMON: &'static str =
+        r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent"#;
+
+    /// Combined Log Format, nginx's default.
+    pub const COMBINED: &'static str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+    pub fn common() -> Self {
+        Self::COMMON.parse().unwrap()
+    }
+
+    pub fn combined() -> Self {
+        Self::COMBINED.parse().unwrap()
+    }
+
+    pub fn parse_line(&self, line: &str) -> Option<LogEntry> {
+        let caps = self.regex.captures(line)?;
+        let field = |name: &str| {
+            caps.name(name)
+                .map(|m| m.as_str())
+                .filter(|v| !v.is_empty() && *v != "-")
+        };
+
+        let logged_at = parse_time(&caps)?;
+        // A duration that is there but negative or not finite means the line
+        // is not what the format says, so it is skipped like any other.
+        let duration = match field("request_time") {
+            Some(t) => Duration::try_from_secs_f64(t.parse().ok()?).ok()?,
+            None => Duration::default(),
+        };
+
+        let (method, uri, protocol) = match field("request") {
+            Some(request) => {
+                let mut parts = request.split_ascii_whitespace();
+                (parts.next()?, parts.next()?, parts.next())
+            }
+            None => (
+                field("request_method")?,
+                field("request_uri").or(field("uri"))?,
+                field("server_protocol"),
+            ),
+        };
+        let (path, query) = match uri.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (uri, field("args")),
+        };
+
+        Some(LogEntry {
+            started_at: logged_at - chrono::Duration::from_std(duration).ok()?,
+            duration,
+            remote_addr: field("remote_addr").and_then(|a| a.parse().ok()),
+            scheme: field("scheme").unwrap_or("http").to_owned(),
+            protocol: protocol.unwrap_or("HTTP/1.0").to_owned(),
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: query.map(str::to_owned),
+            host: field("host").map(str::to_owned),
+            status: field("status")?.parse().ok()?,
+            referer: field("http_referer").map(str::to_owned),
+            user_agent: field("http_user_agent").unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        let variable = Regex::new(r"\$\{?([a-z0-9_]+)\}?").unwrap();
+
+        let mut pattern = String::from("^");
+        let mut seen = Vec::new();
+        let mut last = 0;
+        for caps in variable.captures_iter(format) {
+            let whole = caps.get(0).unwrap();
+            let name = &caps[1];
+            pattern.push_str(&regex::escape(&format[last..whole.start()]));
+            if seen.contains(&name) {
+                pattern.push_str("(?:.*?)");
+            } else {
+                pattern.push_str(&format!("(?P<{name}>.*?)"));
+                seen.push(name);
+            }
+            last = whole.end();
+        }
+        pattern.push_str(&regex::escape(&format[last..]));
+        pattern.push('$');
+
+        anyhow::ensure!(
+            ["time_local", "time_iso8601", "msec"]
+                .iter()
+                .any(|v| seen.contains(v)),
+            "log format has no $time_local, $time_iso8601 or $msec"
+        );
+        anyhow::ensure!(seen.contains(&"status"), "log format has no $status");
+        anyhow::ensure!(
+            seen.contains(&"request") || seen.contains(&"request_method"),
+            "log format has no $request or $request_method"
+        );
+
+        Ok(Self {
+            regex: Regex::new(&pattern)?,
+        })
+    }
+}
+
+fn parse_time(caps: &Captures) -> Option<DateTime<Utc>> {
+    if let Some(t) = caps.name("time_local") {
+        return DateTime::parse_from_str(t.as_str(), "%d/%b/%Y:%H:%M:%S %z")
+            .ok()
+            .map(|t| t.with_timezone(&Utc));
+    }
+    if let Some(t) = caps.name("time_iso8601") {
+        return DateTime::parse_from_rfc3339(t.as_str())
+            .ok()
+            .map(|t| t.with_timezone(&Utc));
+    }
+    let msec = caps.name("msec")?.as_str().parse::<f64>().ok()?;
+    DateTime::from_timestamp_millis((msec * 1000.0) as i64)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Servers log when a request finishes, so this is the logged time minus
+    /// the request duration.
+    pub started_at: DateTime<Utc>,
+    pub duration: Duration,
+    pub remote_addr: Option<IpAddr>,
+    pub scheme: String,
+    pub protocol: String,
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub host: Option<String>,
+    pub status: u16,
+    pub referer: Option<String>,
+    pub user_agent: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ImportCounts {
+    pub imported: u64,
+    /// Lines that did not match the log format.
+    pub skipped: u64,
+}
+
+/// Backfills access logs into the request and response tables, keeping the
+/// timestamps from the logs.
+#[derive(Debug, Clone)]
+pub struct Importer(pub(crate) SqlitePool);
+
+impl Importer {
+    /// Imports every line of `reader`. `default_host` is recorded as the
+    /// hostname when the format has no `$host`.
+    pub async fn import<R: AsyncBufRead + Unpin>(
+        &self,
+        format: &LogFormat,
+        default_host: &str,
+        reader: R,
+    ) -> anyhow::Result<ImportCounts> {
+        let mut counts = ImportCounts::default();
+        let mut ids = IdClock::default();
+        let mut lines = reader.lines();
+        let mut tx = self.0.begin().await?;
+
+        while let Some(line) = lines.next_line().await? {
+            let Some(entry) = format.parse_line(&line) else {
+                counts.skipped += 1;
+                continue;
+            };
+
+            insert_entry(&mut tx, &mut ids, &entry, default_host).await?;
+            counts.imported += 1;
+
+            if counts.imported % BATCH_SIZE == 0 {
+                tx.commit().await?;
+                tx = self.0.begin().await?;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(counts)
+    }
+}
+
+/// Hands out ids that sort by log time rather than by time of import.
+///
+/// Logs are often written at one-second resolution, so many lines share a
+/// time. Each id taken at a time that was already used is offset by one more
+/// microsecond, which keeps ids unique and in the order of the log.
+#[derive(Debug, Default)]
+struct IdClock {
+    used: BTreeMap<DateTime<Utc>, i64>,
+}
+
+impl IdClock {
+    fn id_at(&mut self, at: &DateTime<Utc>) -> ChronoId {
+        let n = self.used.entry(*at).or_default();
+        let id = ChronoId::from_datetime(*at + chrono::Duration::microseconds(*n));
+        *n += 1;
+
+        let (latest, _) = self.used.last_key_value().unwrap();
+        let forget_before = *latest - chrono::Duration::hours(ID_MEMORY_HOURS);
+        if self.used.first_key_value().unwrap().0 < &forget_before {
+            self.used = self.used.split_off(&forget_before);
+        }
+        id
+    }
+}
+
+/// Each line gets its own connection so imported requests can still be
+/// attributed to visitors by remote address.
+async fn insert_entry(
+    conn: &mut SqliteConnection,
+    ids: &mut IdClock,
+    entry: &LogEntry,
+    default_host: &str,
+) -> sqlx::Result<()> {
+    let finished_at = entry.started_at + chrono::Duration::from_std(entry.duration).unwrap();
+    let hostname = entry.host.as_deref().unwrap_or(default_host);
+
+    let connection = entry.remote_addr.map(|ip| Connection {
+        id: ids.id_at(&entry.started_at),
+        created_at: entry.started_at,
+        local_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+        remote_addr: SocketAddr::new(ip, 0),
+        http_scheme: entry.scheme.clone(),
+        http_version: entry.protocol.parse().unwrap(),
+    });
+    if let Some(connection) = &connection {
+        ConnectionTable::insert_record(&mut *conn, connection).await?;
+    }
+
+    let request = Request {
+        id: ids.id_at(&entry.started_at),
+        created_at: entry.started_at,
+        conn_id: connection.as_ref().map(|c| c.id),
+        method: entry.method.clone(),
+        path: entry.path.clone(),
+        hostname: hostname.to_owned(),
+        user_agent: entry.user_agent.clone(),
+        attribution: Attribution::parse(entry.referer.as_deref(), hostname, entry.query.as_deref()),
+        sample_weight: 1,
+        trace_id: None,
+        parent_span_id: None,
+        external_id: None,
+    };
+    let response = Response {
+        id: ids.id_at(&finished_at),
+        created_at: finished_at,
+        conn_id: request.conn_id,
+        req_id: request.id,
+        duration: entry.duration,
+        status: entry.status,
+        sample_weight: 1,
+    };
+    ExchangeTable::insert_record(&mut *conn, &request, Some(&response)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_combined_line() {
+        let line = r#"203.0.113.7 - - [10/Oct/2023:13:55:36 +0200] "GET /pricing?utm_source=news HTTP/1.1" 200 2326 "https://www.google.com/" "Mozilla/5.0 (X11; Linux x86_64)""#;
+        let entry = LogFormat::combined().parse_line(line).unwrap();
+
+        assert_eq!(
+            entry.started_at,
+            DateTime::parse_from_rfc3339("2023-10-10T11:55:36Z").unwrap()
+        );
+        assert_eq!(entry.duration, Duration::ZERO);
+        assert_eq!(entry.remote_addr, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(entry.method, "GET");
+        assert_eq!(entry.path, "/pricing");
+        assert_eq!(entry.query.as_deref(), Some("utm_source=news"));
+        assert_eq!(entry.protocol, "HTTP/1.1");
+        assert_eq!(entry.status, 200);
+        assert_eq!(entry.referer.as_deref(), Some("https://www.google.com/"));
+        assert_eq!(entry.user_agent, "Mozilla/5.0 (X11; Linux x86_64)");
+        assert_eq!(entry.host, None);
+    }
+
+    #[test]
+    fn parses_custom_format() {
+        let format: LogFormat =
+            r#"$host $remote_addr [$time_iso8601] "$request_method $request_uri" $status $request_time "$http_user_agent""#
+                .parse()
+                .unwrap();
+        let line = r#"example.com 2001:db8::1 [2023-10-10T12:00:01+00:00] "POST /api/items" 201 0.250 "curl/8.0""#;
+        let entry = format.parse_line(line).unwrap();
+
+        assert_eq!(entry.host.as_deref(), Some("example.com"));
+        assert_eq!(entry.remote_addr, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(entry.method, "POST");
+        assert_eq!(entry.path, "/api/items");
+        assert_eq!(entry.query, None);
+        assert_eq!(entry.status, 201);
+        assert_eq!(entry.duration, Duration::from_millis(250));
+        assert_eq!(
+            entry.started_at,
+            DateTime::parse_from_rfc3339("2023-10-10T12:00:00.750Z").unwrap()
+        );
+        assert_eq!(entry.user_agent, "curl/8.0");
+    }
+
+    #[test]
+    fn skips_invalid_request_time() {
+        let format: LogFormat =
+            r#"[$time_local] "$request" $status $request_time"#.parse().unwrap();
+        for time in ["-1", "inf", "NaN", "soon"] {
+            let line = format!(r#"[10/Oct/2023:13:55:36 +0000] "GET / HTTP/1.1" 200 {time}"#);
+            assert!(format.parse_line(&line).is_none(), "{time} was accepted");
+        }
+    }
+
+    #[test]
+    fn skips_unmatched_line() {
+        assert!(LogFormat::combined().parse_line("not a log line").is_none());
+    }
+
+    #[tokio::test]
+    async fn ids_follow_log_order_within_a_second() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let db = crate::Db::from_pool(pool);
+        db.migrate().await.unwrap();
+
+        let log = [
+            r#"203.0.113.7 - - [10/Oct/2023:13:55:36 +0000] "GET /a HTTP/1.1" 200 1 "-" "curl/8.0""#,
+            r#"203.0.113.7 - - [10/Oct/2023:13:55:36 +0000] "GET /b HTTP/1.1" 200 1 "-" "curl/8.0""#,
+            r#"203.0.113.7 - - [10/Oct/2023:13:55:37 +0000] "GET /c HTTP/1.1" 200 1 "-" "curl/8.0""#,
+        ]
+        .join("\n");
+        let counts = db
+            .importer()
+            .import(&LogFormat::combined(), "example.com", log.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(counts.imported, 3);
+
+        let mut rows: Vec<(ChronoId, String)> = sqlx::query_as("SELECT id, path FROM sa_request")
+            .fetch_all(db.reader())
+            .await
+            .unwrap();
+        rows.sort();
+        let paths: Vec<&str> = rows.iter().map(|(_, path)| path.as_str()).collect();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+
+        let logged_at = DateTime::parse_from_rfc3339("2023-10-10T13:55:36Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert!(rows[0].0 >= ChronoId::from_datetime(logged_at));
+        assert!(rows[1].0 < ChronoId::from_datetime(logged_at + chrono::Duration::seconds(1)));
+    }
+
+    #[test]
+    fn rejects_format_without_status() {
+        assert!(r#"[$time_local] "$request""#.parse::<LogFormat>().is_err());
+    }
+}
@@ -1,44 +1,286 @@
 use std::{net::SocketAddr, path::Path, time::Duration};
 
+use attribution::Attribution;
 use chrono::{DateTime, Utc};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
 use http::uri::Scheme;
 use human_readable_duration::HumanReadableDuration;
 use serde::{Deserialize, Serialize};
 use simple_id::chrono_id::Id as ChronoId;
-use sqlx::SqlitePool;
-
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqliteExecutor, SqlitePool,
+};
+
+pub mod alerts;
+pub mod apdex;
+pub mod archive;
+pub mod attribution;
+pub mod export;
 mod human_readable_duration;
-
+pub mod import;
+pub mod slo;
+pub mod stats;
+pub mod store;
+
+/// How long a connection waits on a lock held by another before failing with
+/// `SQLITE_BUSY`. Writes are serialised through one connection in this
+/// process, so this mostly covers other processes such as the CLI.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes go through a single connection so they never contend with each
+/// other, while reports run on a pool of read-only connections that WAL lets
+/// proceed alongside the writer.
 #[derive(Debug, Clone, derive_more::Deref)]
-pub struct Db(SqlitePool);
+pub struct Db {
+    #[deref]
+    writer: SqlitePool,
+    reader: SqlitePool,
+}
 
 impl Db {
     pub async fn new<P: AsRef<Path>>(path: P) -> sqlx::Result<Self> {
-        let options = sqlx::sqlite::SqliteConnectOptions::new()
+        Self::with_options(Self::options(path)).await
+    }
+
+    /// The connection options [`Db::new`] opens `path` with.
+    pub fn options<P: AsRef<Path>>(path: P) -> SqliteConnectOptions {
+        SqliteConnectOptions::new()
             .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
             .create_if_missing(true)
             .pragma("cache_size", "-10000")
             .synchronous(sqlx::sqlite::SqliteSynchronous::Normal)
             .optimize_on_close(true, None)
-            .filename(path);
-        let pool = sqlx::Pool::connect_with(options).await?;
+            .busy_timeout(BUSY_TIMEOUT)
+            .filename(path)
+    }
+
+    /// Opens the writer with `options`, applies any pending migrations, then
+    /// opens the reader pool on the same file.
+    pub async fn with_options(options: SqliteConnectOptions) -> sqlx::Result<Self> {
+        let db = Self::connect(options).await?;
+        db.migrate().await?;
+        Ok(db)
+    }
+
+    /// Opens an existing database at `path` without applying migrations, for
+    /// tools that must not change the schema of a database in use.
+    pub async fn open<P: AsRef<Path>>(path: P) -> sqlx::Result<Self> {
+        Self::connect(Self::options(path).create_if_missing(false)).await
+    }
+
+    /// Opens an existing database at `path` for reading only. Writes fail.
+    pub async fn open_read_only<P: AsRef<Path>>(path: P) -> sqlx::Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(reader_connections())
+            .connect_with(
+                Self::options(path)
+                    .create_if_missing(false)
+                    .read_only(true)
+                    .optimize_on_close(false, None),
+            )
+            .await?;
+        Ok(Self::from_pool(pool))
+    }
+
+    async fn connect(options: SqliteConnectOptions) -> sqlx::Result<Self> {
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await?;
+
+        let reader = SqlitePoolOptions::new()
+            .max_connections(reader_connections())
+            .connect_with(options.read_only(true).optimize_on_close(false, None))
+            .await?;
+
+        Ok(Self { writer, reader })
+    }
+
+    /// Uses one pool for both reads and writes, for databases that only live
+    /// as long as the pool, such as in-memory ones, or that are only read.
+    pub(crate) fn from_pool(pool: SqlitePool) -> Self {
+        Self {
+            writer: pool.clone(),
+            reader: pool,
+        }
+    }
+
+    pub fn writer(&self) -> &SqlitePool {
+        &self.writer
+    }
+
+    pub fn reader(&self) -> &SqlitePool {
+        &self.reader
+    }
+
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        vec![
+            PoolStats::of("writer", &self.writer),
+            PoolStats::of("reader", &self.reader),
+        ]
+    }
+
+    pub async fn migrate(&self) -> sqlx::Result<()> {
+        sqlx::migrate!().run(&self.writer).await?;
+        backfill_duration_us(&self.writer).await?;
+        Ok(())
+    }
+
+    /// Closes both pools, waiting for queries in progress to finish.
+    pub async fn close(&self) {
+        self.reader.close().await;
+        self.writer.close().await;
+    }
+
+    /// Versions and descriptions of the migrations applied to the database.
+    pub async fn applied_migrations(&self) -> sqlx::Result<Vec<(i64, String)>> {
+        sqlx::query_as("SELECT version, description FROM _sqlx_migrations ORDER BY version")
+            .fetch_all(&self.reader)
+            .await
+    }
+
+    pub async fn vacuum(&self) -> sqlx::Result<()> {
+        sqlx::query("VACUUM").execute(&self.writer).await?;
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the database to `path` with `VACUUM INTO`,
+    /// replacing any file already there. Recording carries on while the copy
+    /// is taken; it sees the data as of when it started.
+    pub async fn backup_to<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        // VACUUM INTO refuses to overwrite, so clear out a failed attempt.
+        match tokio::fs::remove_file(&tmp).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let tmp_str = tmp
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("backup path {} is not UTF-8", path.display()))?;
+        sqlx::query("VACUUM INTO ?")
+            .bind(tmp_str)
+            .execute(&self.reader)
+            .await?;
+        tokio::fs::rename(&tmp, path).await?;
+
+        Ok(())
+    }
+
+    /// Deletes everything recorded before `before`. A response is stored with
+    /// its request, so it goes when the request does.
+    pub async fn prune(&self, before: &DateTime<Utc>) -> sqlx::Result<PruneCounts> {
+        let mut tx = self.writer.begin().await?;
 
-        sqlx::migrate!().run(&pool).await?;
+        let responses: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM sa_exchange WHERE created_at < ? AND response_id IS NOT NULL",
+        )
+        .bind(before)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let requests = sqlx::query("DELETE FROM sa_exchange WHERE created_at < ?")
+            .bind(before)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        let connections = sqlx::query("DELETE FROM sa_connection WHERE created_at < ?")
+            .bind(before)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
 
-        Ok(Self(pool.clone()))
+        tx.commit().await?;
+
+        Ok(PruneCounts {
+            connections,
+            requests,
+            responses: responses as u64,
+        })
     }
 
     pub fn connection_table(&self) -> ConnectionTable {
-        ConnectionTable(self.0.clone())
+        ConnectionTable(self.clone())
     }
 
     pub fn request_table(&self) -> RequestTable {
-        RequestTable(self.0.clone())
+        RequestTable(self.clone())
     }
 
     pub fn response_table(&self) -> ResponseTable {
-        ResponseTable(self.0.clone())
+        ResponseTable(self.clone())
     }
+
+    pub fn exchange_table(&self) -> ExchangeTable {
+        ExchangeTable(self.clone())
+    }
+
+    pub fn alert_table(&self) -> alerts::AlertTable {
+        alerts::AlertTable(self.writer.clone())
+    }
+
+    pub fn exporter(&self) -> export::Exporter {
+        export::Exporter(self.reader.clone())
+    }
+
+    pub fn importer(&self) -> import::Importer {
+        import::Importer(self.writer.clone())
+    }
+
+    pub fn stats(&self) -> stats::Stats {
+        stats::Stats(self.reader.clone())
+    }
+}
+
+/// A snapshot of a connection pool's usage, for monitoring.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolStats {
+    pub pool: &'static str,
+    /// Open connections, idle or in use.
+    pub size: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+impl PoolStats {
+    pub fn of<DB: sqlx::Database>(pool: &'static str, p: &sqlx::Pool<DB>) -> Self {
+        Self {
+            pool,
+            size: p.size(),
+            idle: p.num_idle() as u32,
+            max: p.options().get_max_connections(),
+        }
+    }
+
+    /// Adds up the stats of pools playing the same role.
+    pub fn sum(pool: &'static str, stats: impl IntoIterator<Item = Self>) -> Self {
+        stats.into_iter().fold(
+            Self {
+                pool,
+                size: 0,
+                idle: 0,
+                max: 0,
+            },
+            |total, s| Self {
+                pool,
+                size: total.size + s.size,
+                idle: total.idle + s.idle,
+                max: total.max + s.max,
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PruneCounts {
+    pub connections: u64,
+    pub requests: u64,
+    pub responses: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -52,6 +294,22 @@ pub struct Connection {
 }
 
 impl Connection {
+    pub fn new(
+        local_addr: &SocketAddr,
+        remote_addr: &SocketAddr,
+        http_scheme: &Scheme,
+        http_version: &http::Version,
+    ) -> Self {
+        Self {
+            id: ChronoId::new(),
+            created_at: Utc::now(),
+            local_addr: *local_addr,
+            remote_addr: *remote_addr,
+            http_scheme: http_scheme.to_string(),
+            http_version: (*http_version).into(),
+        }
+    }
+
     pub fn to_stored(self) -> StoredConnection {
         StoredConnection {
             id: self.id,
@@ -116,8 +374,10 @@ impl std::fmt::Display for HttpVersion {
     }
 }
 
+/// Anything unrecognised parses as [`HttpVersion::Unknown`], which is also
+/// what it displays as, so parsing never fails.
 impl std::str::FromStr for HttpVersion {
-    type Err = ();
+    type Err = std::convert::Infallible;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(match s {
             "HTTP/0.9" | "0.9" => Self::Http09,
@@ -130,6 +390,7 @@ impl std::str::FromStr for HttpVersion {
     }
 }
 
+#[derive(sqlx::FromRow)]
 pub struct StoredConnection {
     pub id: ChronoId,
     pub created_at: DateTime<Utc>,
@@ -140,7 +401,7 @@ pub struct StoredConnection {
 }
 
 #[derive(Debug, Clone)]
-pub struct ConnectionTable(sqlx::Pool<sqlx::Sqlite>);
+pub struct ConnectionTable(Db);
 
 impl ConnectionTable {
     pub async fn insert(
@@ -150,14 +411,17 @@ impl ConnectionTable {
         http_scheme: &Scheme,
         http_version: &http::Version,
     ) -> sqlx::Result<Connection> {
-        let e = Connection {
-            id: ChronoId::new(),
-            created_at: Utc::now(),
-            local_addr: local_addr.clone(),
-            remote_addr: remote_addr.clone(),
-            http_scheme: http_scheme.to_string(),
-            http_version: http_version.clone().into(),
-        };
+        let e = Connection::new(local_addr, remote_addr, http_scheme, http_version);
+
+        Self::insert_record(&self.0.writer, &e).await?;
+
+        Ok(e)
+    }
+
+    pub async fn insert_record<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        e: &Connection,
+    ) -> sqlx::Result<()> {
         let stored = e.clone().to_stored();
 
         sqlx::query(
@@ -178,10 +442,43 @@ impl ConnectionTable {
         .bind(&stored.remote_addr)
         .bind(&stored.http_scheme)
         .bind(&stored.http_version)
-        .execute(&self.0)
+        .execute(executor)
         .await?;
 
-        Ok(e)
+        Ok(())
+    }
+
+    pub async fn get(&self, id: &ChronoId) -> sqlx::Result<Option<Connection>> {
+        let stored: Option<StoredConnection> =
+            sqlx::query_as("SELECT * FROM sa_connection WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.0.reader)
+                .await?;
+
+        Ok(stored.map(Connection::from_stored))
+    }
+
+    pub fn stream<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<Connection>> {
+        Self::stream_records(&self.0.reader, from, to)
+    }
+
+    pub fn stream_records<'a, E: SqliteExecutor<'a> + 'a>(
+        executor: E,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<Connection>> {
+        sqlx::query_as(
+            "SELECT * FROM sa_connection WHERE created_at >= ? AND created_at < ? ORDER BY created_at",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch(executor)
+        .map_ok(Connection::from_stored)
+        .boxed()
     }
 }
 
@@ -194,55 +491,120 @@ pub struct Request {
     pub path: String,
     pub hostname: String,
     pub user_agent: String,
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub attribution: Attribution,
+    /// How many requests this one stands for, 1 unless it was sampled.
+    #[serde(default = "default_sample_weight")]
+    pub sample_weight: i64,
+    /// The distributed trace the request is part of, from its `traceparent`
+    /// header, as 32 hex digits.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// The caller's span that made the request, as 16 hex digits.
+    #[serde(default, alias = "span_id")]
+    pub parent_span_id: Option<String>,
+    /// The id a trusted proxy gave the request in its request id header.
+    #[serde(default)]
+    pub external_id: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-pub struct RequestTable(sqlx::Pool<sqlx::Sqlite>);
+fn default_sample_weight() -> i64 {
+    1
+}
 
-impl RequestTable {
-    pub async fn insert(
-        &self,
+impl Request {
+    pub fn new(
         conn_id: Option<&ChronoId>,
         method: &str,
         path: &str,
         hostname: &str,
         user_agent: &str,
-    ) -> sqlx::Result<Request> {
-        let e = Request {
+        attribution: &Attribution,
+    ) -> Self {
+        Self {
             id: ChronoId::new(),
             created_at: Utc::now(),
-            conn_id: conn_id.cloned(),
+            conn_id: conn_id.copied(),
             method: method.to_owned(),
             path: path.to_owned(),
             hostname: hostname.to_owned(),
             user_agent: user_agent.to_owned(),
-        };
+            attribution: attribution.clone(),
+            sample_weight: 1,
+            trace_id: None,
+            parent_span_id: None,
+            external_id: None,
+        }
+    }
+}
 
-        sqlx::query(
-            "
-            INSERT INTO sa_request (
-                id,
-                created_at,
-                conn_id,
-                method,
-                path,
-                hostname,
-                user_agent
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)
-        ",
-        )
-        .bind(&e.id)
-        .bind(&e.created_at)
-        .bind(&e.conn_id)
-        .bind(&e.method)
-        .bind(&e.path)
-        .bind(&e.hostname)
-        .bind(&e.user_agent)
-        .execute(&self.0)
-        .await?;
+#[derive(Debug, Clone)]
+pub struct RequestTable(Db);
+
+impl RequestTable {
+    pub async fn insert(
+        &self,
+        conn_id: Option<&ChronoId>,
+        method: &str,
+        path: &str,
+        hostname: &str,
+        user_agent: &str,
+        attribution: &Attribution,
+    ) -> sqlx::Result<Request> {
+        let e = Request::new(conn_id, method, path, hostname, user_agent, attribution);
+
+        Self::insert_record(&self.0.writer, &e).await?;
 
         Ok(e)
     }
+
+    /// Records a request whose response has not been sent yet, or never will
+    /// be. [`ResponseTable::insert_record`] fills in the response later.
+    pub async fn insert_record<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        e: &Request,
+    ) -> sqlx::Result<()> {
+        ExchangeTable::insert_record(executor, e, None).await
+    }
+
+    pub fn stream<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<Request>> {
+        Self::stream_records(&self.0.reader, from, to)
+    }
+
+    pub fn stream_records<'a, E: SqliteExecutor<'a> + 'a>(
+        executor: E,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<Request>> {
+        sqlx::query_as(
+            "SELECT * FROM sa_request WHERE created_at >= ? AND created_at < ? ORDER BY created_at",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch(executor)
+    }
+
+    /// The most recent requests, newest first.
+    pub async fn recent(&self, limit: u32) -> sqlx::Result<Vec<Request>> {
+        sqlx::query_as("SELECT * FROM sa_request ORDER BY created_at DESC LIMIT ?")
+            .bind(limit)
+            .fetch_all(&self.0.reader)
+            .await
+    }
+
+    /// Requests created after `after`, oldest first.
+    pub async fn since(&self, after: &DateTime<Utc>, limit: u32) -> sqlx::Result<Vec<Request>> {
+        sqlx::query_as("SELECT * FROM sa_request WHERE created_at > ? ORDER BY created_at LIMIT ?")
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.0.reader)
+            .await
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -253,8 +615,12 @@ pub struct Response {
     pub req_id: ChronoId,
     pub duration: Duration,
     pub status: u16,
+    /// Always that of its request.
+    #[serde(default = "default_sample_weight")]
+    pub sample_weight: i64,
 }
 
+#[derive(sqlx::FromRow)]
 pub struct StoredResponse {
     pub id: ChronoId,
     pub created_at: DateTime<Utc>,
@@ -262,10 +628,42 @@ pub struct StoredResponse {
     pub req_id: ChronoId,
     pub duration: HumanReadableDuration,
     pub status: u16,
+    pub sample_weight: i64,
+}
+
+impl Response {
+    pub fn new(
+        conn_id: Option<&ChronoId>,
+        req_id: &ChronoId,
+        duration: &Duration,
+        status: u16,
+    ) -> Self {
+        Self {
+            id: ChronoId::new(),
+            created_at: Utc::now(),
+            conn_id: conn_id.copied(),
+            req_id: *req_id,
+            duration: *duration,
+            status,
+            sample_weight: 1,
+        }
+    }
+
+    pub fn from_stored(stored: StoredResponse) -> Self {
+        Response {
+            id: stored.id,
+            created_at: stored.created_at,
+            conn_id: stored.conn_id,
+            req_id: stored.req_id,
+            duration: stored.duration.0,
+            status: stored.status,
+            sample_weight: stored.sample_weight,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct ResponseTable(sqlx::Pool<sqlx::Sqlite>);
+pub struct ResponseTable(Db);
 
 impl ResponseTable {
     pub async fn insert(
@@ -275,36 +673,177 @@ impl ResponseTable {
         duration: &Duration,
         status: u16,
     ) -> sqlx::Result<Response> {
-        let e = Response {
-            id: ChronoId::new(),
-            created_at: Utc::now(),
-            conn_id: conn_id.cloned(),
-            req_id: req_id.clone(),
-            duration: duration.clone(),
-            status,
-        };
+        let e = Response::new(conn_id, req_id, duration, status);
+
+        Self::insert_record(&self.0.writer, &e).await?;
+
+        Ok(e)
+    }
+
+    /// Adds the response to its request's row. Fails with
+    /// [`sqlx::Error::RowNotFound`] if the request was never recorded.
+    pub async fn insert_record<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        e: &Response,
+    ) -> sqlx::Result<()> {
+        let updated = sqlx::query(
+            "
+            UPDATE sa_exchange
+            SET response_id = ?, responded_at = ?, duration = ?, duration_us = ?, status = ?
+            WHERE id = ?
+        ",
+        )
+        .bind(&e.id)
+        .bind(&e.created_at)
+        .bind(&HumanReadableDuration(e.duration))
+        .bind(duration_us(&e.duration))
+        .bind(&e.status)
+        .bind(&e.req_id)
+        .execute(executor)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    pub fn stream<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<Response>> {
+        Self::stream_records(&self.0.reader, from, to)
+    }
 
+    pub fn stream_records<'a, E: SqliteExecutor<'a> + 'a>(
+        executor: E,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<Response>> {
+        sqlx::query_as(
+            "SELECT * FROM sa_response WHERE created_at >= ? AND created_at < ? ORDER BY created_at",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch(executor)
+        .map_ok(Response::from_stored)
+        .boxed()
+    }
+}
+
+/// Requests and their responses, stored together as one row. The
+/// `sa_request` and `sa_response` views read from it.
+#[derive(Debug, Clone)]
+pub struct ExchangeTable(Db);
+
+impl ExchangeTable {
+    /// Records a request along with its response in a single write, or just
+    /// the request when it did not complete.
+    pub async fn insert_record<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        request: &Request,
+        response: Option<&Response>,
+    ) -> sqlx::Result<()> {
         sqlx::query(
             "
-            INSERT INTO sa_response (
+            INSERT INTO sa_exchange (
                 id,
                 created_at,
                 conn_id,
-                req_id,
+                method,
+                path,
+                hostname,
+                user_agent,
+                referrer_domain,
+                referrer_category,
+                utm_source,
+                utm_medium,
+                utm_campaign,
+                utm_term,
+                utm_content,
+                response_id,
+                responded_at,
                 duration,
-                status
-            ) VALUES (?, ?, ?, ?, ?, ?)
+                duration_us,
+                status,
+                sample_weight,
+                trace_id,
+                parent_span_id,
+                external_id
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         ",
         )
-        .bind(&e.id)
-        .bind(&e.created_at)
-        .bind(&e.conn_id)
-        .bind(&e.req_id)
-        .bind(&HumanReadableDuration(e.duration))
-        .bind(&e.status)
-        .execute(&self.0)
+        .bind(&request.id)
+        .bind(&request.created_at)
+        .bind(&request.conn_id)
+        .bind(&request.method)
+        .bind(&request.path)
+        .bind(&request.hostname)
+        .bind(&request.user_agent)
+        .bind(&request.attribution.referrer_domain)
+        .bind(&request.attribution.referrer_category)
+        .bind(&request.attribution.utm_source)
+        .bind(&request.attribution.utm_medium)
+        .bind(&request.attribution.utm_campaign)
+        .bind(&request.attribution.utm_term)
+        .bind(&request.attribution.utm_content)
+        .bind(response.map(|r| r.id))
+        .bind(response.map(|r| r.created_at))
+        .bind(response.map(|r| HumanReadableDuration(r.duration)))
+        .bind(response.map(|r| duration_us(&r.duration)))
+        .bind(response.map(|r| r.status))
+        .bind(&request.sample_weight)
+        .bind(&request.trace_id)
+        .bind(&request.parent_span_id)
+        .bind(&request.external_id)
+        .execute(executor)
         .await?;
 
-        Ok(e)
+        Ok(())
+    }
+
+    pub async fn insert(&self, request: &Request, response: Option<&Response>) -> sqlx::Result<()> {
+        Self::insert_record(&self.0.writer, request, response).await
     }
 }
+
+/// Durations as stored in the `duration_us` column.
+fn duration_us(duration: &Duration) -> i64 {
+    duration.as_micros().try_into().unwrap_or(i64::MAX)
+}
+
+/// Fills in `duration_us` for responses recorded before it was added.
+async fn backfill_duration_us(pool: &SqlitePool) -> sqlx::Result<()> {
+    loop {
+        let rows: Vec<(ChronoId, HumanReadableDuration)> = sqlx::query_as(
+            "
+            SELECT id, duration FROM sa_exchange
+            WHERE duration IS NOT NULL AND duration_us IS NULL
+            LIMIT 1000
+        ",
+        )
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = pool.begin().await?;
+        for (id, duration) in rows {
+            sqlx::query("UPDATE sa_exchange SET duration_us = ? WHERE id = ?")
+                .bind(duration_us(&duration.0))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+    }
+}
+
+/// Enough readers for the reports that might run at once, without opening a
+/// connection per core on large machines.
+fn reader_connections() -> u32 {
+    std::thread::available_parallelism().map_or(4, |n| n.get().clamp(2, 8) as u32)
+}
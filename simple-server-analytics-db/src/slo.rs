@@ -0,0 +1,363 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+
+use crate::{human_readable_duration::HumanReadableDuration, stats::Stats};
+
+/// Windows over which burn rates are reported, in seconds.
+const BURN_WINDOWS: &[u64] = &[
+    5 * 60,
+    30 * 60,
+    60 * 60,
+    6 * 60 * 60,
+    24 * 60 * 60,
+    3 * 24 * 60 * 60,
+];
+
+/// Multi-window burn rate alert conditions from the Google SRE workbook, as
+/// (long window, short window, burn rate threshold).
+const FAST_BURN: (u64, u64, f64) = (60 * 60, 5 * 60, 14.4);
+const SLOW_BURN: (u64, u64, f64) = (6 * 60 * 60, 30 * 60, 6.0);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Slo {
+    pub name: String,
+    /// Only requests whose path starts with this prefix count towards the SLO.
+    #[serde(default)]
+    pub path_prefix: String,
+    /// Percentage of events that must be good, e.g. `99.9`. Must be between 0
+    /// and 100, exclusive, to leave an error budget to burn.
+    #[serde(deserialize_with = "target_percent")]
+    pub target_percent: f64,
+    pub window_days: u32,
+    #[serde(default)]
+    pub good: GoodEvent,
+}
+
+fn target_percent<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let target = f64::deserialize(deserializer)?;
+    if !(target > 0.0 && target < 100.0) {
+        return Err(D::Error::custom(format!(
+            "SLO target of {target}% is not between 0 and 100"
+        )));
+    }
+    Ok(target)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoodEvent {
+    /// Responses with a status below this are good.
+    pub status_below: u16,
+    /// Responses slower than this are bad regardless of status.
+    pub max_duration_ms: Option<f64>,
+}
+
+impl Default for GoodEvent {
+    fn default() -> Self {
+        Self {
+            status_below: 500,
+            max_duration_ms: None,
+        }
+    }
+}
+
+impl GoodEvent {
+    pub fn is_good(&self, status: u16, duration: &Duration) -> bool {
+        status < self.status_below
+            && self
+                .max_duration_ms
+                .map_or(true, |max| duration.as_secs_f64() * 1000.0 <= max)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SloReport {
+    pub name: String,
+    pub target_percent: f64,
+    pub window_days: u32,
+    pub total: u64,
+    pub good: u64,
+    pub attainment_percent: Option<f64>,
+    /// Fraction of the error budget left over the SLO window. Negative once
+    /// the budget has been overspent.
+    pub error_budget_remaining: Option<f64>,
+    pub burn_rates: Vec<BurnRate>,
+    pub fast_burn: bool,
+    pub slow_burn: bool,
+}
+
+/// How fast the error budget is being spent; `1.0` spends exactly the whole
+/// budget over the SLO window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BurnRate {
+    pub window_secs: u64,
+    pub rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    total: u64,
+    good: u64,
+}
+
+impl Counts {
+    fn bad_fraction(&self) -> Option<f64> {
+        (self.total > 0).then(|| (self.total - self.good) as f64 / self.total as f64)
+    }
+}
+
+/// Counts good and total events per window as responses are fed in, in any
+/// order.
+pub(crate) struct SloTally<'a> {
+    slo: &'a Slo,
+    now: DateTime<Utc>,
+    in_slo_window: Counts,
+    in_burn_windows: Vec<Counts>,
+}
+
+impl<'a> SloTally<'a> {
+    pub(crate) fn new(slo: &'a Slo, now: &DateTime<Utc>) -> Self {
+        Self {
+            slo,
+            now: *now,
+            in_slo_window: Counts::default(),
+            in_burn_windows: vec![Counts::default(); BURN_WINDOWS.len()],
+        }
+    }
+
+    fn slo_window(&self) -> u64 {
+        u64::from(self.slo.window_days) * 24 * 60 * 60
+    }
+
+    /// Start of the longest window, before which responses can be skipped.
+    pub(crate) fn from(&self) -> DateTime<Utc> {
+        let longest = BURN_WINDOWS
+            .iter()
+            .copied()
+            .fold(self.slo_window(), u64::max);
+        self.now - chrono::Duration::seconds(longest as i64)
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        created_at: &DateTime<Utc>,
+        status: u16,
+        duration: &Duration,
+        sample_weight: i64,
+    ) {
+        let age = (self.now - *created_at).num_seconds().max(0) as u64;
+        let good = self.slo.good.is_good(status, duration);
+        let weight = sample_weight.max(0) as u64;
+
+        let windows = std::iter::once((self.slo_window(), &mut self.in_slo_window)).chain(
+            BURN_WINDOWS
+                .iter()
+                .copied()
+                .zip(self.in_burn_windows.iter_mut()),
+        );
+        for (window, counts) in windows {
+            if age < window {
+                counts.total += weight;
+                counts.good += good as u64 * weight;
+            }
+        }
+    }
+
+    pub(crate) fn report(self) -> SloReport {
+        let slo = self.slo;
+        let budget = 1.0 - slo.target_percent / 100.0;
+        let burn_rate = |counts: &Counts| counts.bad_fraction().map(|bad| bad / budget);
+        let burn_rates: Vec<BurnRate> = BURN_WINDOWS
+            .iter()
+            .zip(&self.in_burn_windows)
+            .map(|(&window_secs, counts)| BurnRate {
+                window_secs,
+                rate: burn_rate(counts),
+            })
+            .collect();
+        let burning = |(long, short, threshold): (u64, u64, f64)| {
+            [long, short].iter().all(|window| {
+                burn_rates
+                    .iter()
+                    .find(|b| b.window_secs == *window)
+                    .and_then(|b| b.rate)
+                    .map_or(false, |rate| rate > threshold)
+            })
+        };
+
+        SloReport {
+            name: slo.name.clone(),
+            target_percent: slo.target_percent,
+            window_days: slo.window_days,
+            total: self.in_slo_window.total,
+            good: self.in_slo_window.good,
+            attainment_percent: self
+                .in_slo_window
+                .bad_fraction()
+                .map(|bad| (1.0 - bad) * 100.0),
+            error_budget_remaining: self
+                .in_slo_window
+                .bad_fraction()
+                .map(|bad| 1.0 - bad / budget),
+            fast_burn: burning(FAST_BURN),
+            slow_burn: burning(SLOW_BURN),
+            burn_rates,
+        }
+    }
+}
+
+impl Stats {
+    pub async fn slo_report(&self, slo: &Slo, now: &DateTime<Utc>) -> sqlx::Result<SloReport> {
+        let mut tally = SloTally::new(slo, now);
+
+        let mut rows = sqlx::query_as::<_, (DateTime<Utc>, u16, HumanReadableDuration, i64)>(
+            "
+            SELECT r.created_at, r.status, r.duration, r.sample_weight
+            FROM sa_response r
+            JOIN sa_request q ON q.id = r.req_id
+            WHERE r.created_at >= ? AND r.created_at < ?
+                AND substr(q.path, 1, length(?)) = ?
+        ",
+        )
+        .bind(tally.from())
+        .bind(now)
+        .bind(&slo.path_prefix)
+        .bind(&slo.path_prefix)
+        .fetch(&self.0);
+
+        while let Some((created_at, status, duration, weight)) = rows.try_next().await? {
+            tally.record(&created_at, status, &duration.0, weight);
+        }
+
+        Ok(tally.report())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slo() -> Slo {
+        serde_json::from_value(serde_json::json!({
+            "name": "api",
+            "target_percent": 99.0,
+            "window_days": 30,
+        }))
+        .unwrap()
+    }
+
+    fn rate(report: &SloReport, window_secs: u64) -> f64 {
+        report
+            .burn_rates
+            .iter()
+            .find(|b| b.window_secs == window_secs)
+            .and_then(|b| b.rate)
+            .unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn rejects_targets_without_a_budget() {
+        for target in [0.0, 100.0, 150.0, -1.0] {
+            let slo = serde_json::from_value::<Slo>(serde_json::json!({
+                "name": "api",
+                "target_percent": target,
+                "window_days": 30,
+            }));
+            assert!(slo.is_err(), "{target} was accepted");
+        }
+    }
+
+    #[test]
+    fn flags_fast_burn() {
+        let slo = slo();
+        let now = Utc::now();
+        let minute_ago = now - chrono::Duration::minutes(1);
+        let mut tally = SloTally::new(&slo, &now);
+        tally.record(&minute_ago, 200, &Duration::ZERO, 80);
+        tally.record(&minute_ago, 503, &Duration::ZERO, 20);
+        let report = tally.report();
+
+        assert_eq!((report.total, report.good), (100, 80));
+        assert_close(report.attainment_percent.unwrap(), 80.0);
+        assert_close(report.error_budget_remaining.unwrap(), -19.0);
+        for window in BURN_WINDOWS {
+            assert_close(rate(&report, *window), 20.0);
+        }
+        assert!(report.fast_burn);
+        assert!(report.slow_burn);
+    }
+
+    #[test]
+    fn flags_slow_burn_only_when_the_short_window_agrees() {
+        let slo = slo();
+        let now = Utc::now();
+        let mut tally = SloTally::new(&slo, &now);
+        tally.record(
+            &(now - chrono::Duration::minutes(2)),
+            200,
+            &Duration::ZERO,
+            100,
+        );
+        tally.record(
+            &(now - chrono::Duration::minutes(10)),
+            500,
+            &Duration::ZERO,
+            10,
+        );
+        let report = tally.report();
+
+        assert_close(rate(&report, 5 * 60), 0.0);
+        assert_close(rate(&report, 30 * 60), 10.0 / 110.0 / 0.01);
+        assert!(!report.fast_burn);
+        assert!(report.slow_burn);
+
+        // Errors that are over an hour old no longer count towards the short
+        // windows of either alert.
+        let mut tally = SloTally::new(&slo, &now);
+        tally.record(
+            &(now - chrono::Duration::hours(2)),
+            500,
+            &Duration::ZERO,
+            10,
+        );
+        tally.record(
+            &(now - chrono::Duration::minutes(1)),
+            200,
+            &Duration::ZERO,
+            100,
+        );
+        let report = tally.report();
+        assert_close(rate(&report, 6 * 60 * 60), 10.0 / 110.0 / 0.01);
+        assert!(!report.fast_burn);
+        assert!(!report.slow_burn);
+    }
+
+    #[test]
+    fn reports_nothing_without_events() {
+        let slo = slo();
+        let report = SloTally::new(&slo, &Utc::now()).report();
+        assert_eq!(report.total, 0);
+        assert_eq!(report.attainment_percent, None);
+        assert_eq!(report.error_budget_remaining, None);
+        assert!(report.burn_rates.iter().all(|b| b.rate.is_none()));
+        assert!(!report.fast_burn);
+        assert!(!report.slow_burn);
+    }
+
+    #[test]
+    fn slow_responses_are_bad() {
+        let good = GoodEvent {
+            status_below: 500,
+            max_duration_ms: Some(300.0),
+        };
+        assert!(good.is_good(404, &Duration::from_millis(300)));
+        assert!(!good.is_good(200, &Duration::from_millis(301)));
+        assert!(!good.is_good(500, &Duration::from_millis(1)));
+    }
+}
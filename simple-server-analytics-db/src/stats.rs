@@ -0,0 +1,411 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::attribution::{ReferrerCategory, SESSION_TIMEOUT};
+
+/// Visitors are identified by the remote IP of their connection (with the port
+/// stripped) together with their user agent. Requests without a connection are
+/// not attributable to a visitor.
+pub(crate) const VISITOR_SQL: &str =
+    "rtrim(rtrim(c.remote_addr, '0123456789'), ':') || ' ' || r.user_agent";
+
+/// The `landings` table of requests that start a session, being the first from
+/// their visitor in [`SESSION_TIMEOUT`]. Binds the start of the lookback before
+/// the range, the end of the range, then its start. Requests without a
+/// visitor each count as their own session.
+fn landings_sql() -> String {
+    let timeout = SESSION_TIMEOUT.as_secs();
+    format!(
+        "
+        visits AS (
+            SELECT
+                r.*,
+                {VISITOR_SQL} AS visitor,
+                LAG(r.created_at) OVER (
+                    PARTITION BY {VISITOR_SQL} ORDER BY r.created_at
+                ) AS previous_at
+            FROM sa_request r
+            LEFT JOIN sa_connection c ON c.id = r.conn_id
+            WHERE r.created_at >= ? AND r.created_at < ?
+        ),
+        landings AS (
+            SELECT * FROM visits
+            WHERE created_at >= ?
+                AND (
+                    visitor IS NULL
+                    OR previous_at IS NULL
+                    OR (julianday(created_at) - julianday(previous_at)) * 86400 > {timeout}
+                )
+        )
+    "
+    )
+}
+
+/// How far before a range requests are looked at to tell whether the first
+/// ones in it continue an earlier session.
+pub(crate) fn session_lookback(from: &DateTime<Utc>) -> DateTime<Utc> {
+    *from - chrono::Duration::from_std(SESSION_TIMEOUT).unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats(pub(crate) SqlitePool);
+
+/// Week-over-week retention, one row per cohort of visitors first seen in the
+/// same week. `retention[n]` is the fraction of the cohort seen again `n` weeks
+/// after their first week, so `retention[0]` is always `1.0`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CohortMatrix {
+    pub cohorts: Vec<Cohort>,
+}
+
+impl CohortMatrix {
+    /// Builds the matrix from `(cohort week, weeks since, visitors)` counts.
+    pub(crate) fn from_counts(rows: Vec<(NaiveDate, i64, i64)>) -> Self {
+        let Some(last_week) = rows
+            .iter()
+            .map(|(cohort, offset, _)| *cohort + chrono::Duration::weeks(*offset))
+            .max()
+        else {
+            return Self::default();
+        };
+
+        let mut counts: BTreeMap<NaiveDate, Vec<u64>> = BTreeMap::new();
+        for (cohort, offset, visitors) in rows {
+            let weeks = ((last_week - cohort).num_weeks() + 1) as usize;
+            let row = counts.entry(cohort).or_insert_with(|| vec![0; weeks]);
+            row[offset as usize] = visitors as u64;
+        }
+
+        let cohorts = counts
+            .into_iter()
+            .map(|(week, row)| {
+                let visitors = row[0];
+                Cohort {
+                    week,
+                    visitors,
+                    retention: row
+                        .iter()
+                        .map(|&n| n as f64 / visitors.max(1) as f64)
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Self { cohorts }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cohort {
+    pub week: NaiveDate,
+    pub visitors: u64,
+    pub retention: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SourceReport {
+    pub referrer_category: ReferrerCategory,
+    pub referrer_domain: Option<String>,
+    /// Sessions that landed from this source.
+    pub visits: i64,
+    pub visitors: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct CampaignReport {
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    /// Sessions that landed with these parameters.
+    pub visits: i64,
+    pub visitors: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ResponseCounts {
+    pub total: i64,
+    pub client_errors: i64,
+    pub server_errors: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Summary {
+    pub requests: i64,
+    /// Distinct visitors among the recorded requests, which sampling weights
+    /// can't scale back up.
+    pub visitors: i64,
+    pub responses: ResponseCounts,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopField {
+    Paths,
+    Hosts,
+    Methods,
+    UserAgents,
+    Referrers,
+    Statuses,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TopEntry {
+    pub value: String,
+    pub count: i64,
+}
+
+impl Stats {
+    pub async fn cohorts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> sqlx::Result<CohortMatrix> {
+        let sql = format!(
+            "
+            WITH visits AS (
+                SELECT DISTINCT
+                    {VISITOR_SQL} AS visitor,
+                    date(r.created_at, '-6 days', 'weekday 1') AS week
+                FROM sa_request r
+                JOIN sa_connection c ON c.id = r.conn_id
+                WHERE r.created_at >= ? AND r.created_at < ?
+            ),
+            first_visits AS (
+                SELECT visitor, MIN(week) AS cohort
+                FROM visits
+                GROUP BY visitor
+            )
+            SELECT
+                f.cohort,
+                CAST((julianday(v.week) - julianday(f.cohort)) / 7 AS INTEGER) AS week_offset,
+                COUNT(*)
+            FROM visits v
+            JOIN first_visits f ON f.visitor = v.visitor
+            GROUP BY f.cohort, week_offset
+            ORDER BY f.cohort, week_offset
+        "
+        );
+
+        let rows: Vec<(NaiveDate, i64, i64)> = sqlx::query_as(&sql)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.0)
+            .await?;
+
+        Ok(CohortMatrix::from_counts(rows))
+    }
+
+    /// Sessions grouped by where their landing request was referred from.
+    pub async fn top_sources(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> sqlx::Result<Vec<SourceReport>> {
+        let sql = format!(
+            "
+            WITH {}
+            SELECT
+                referrer_category,
+                referrer_domain,
+                SUM(sample_weight) AS visits,
+                COUNT(DISTINCT visitor) AS visitors
+            FROM landings
+            WHERE referrer_category != 'internal'
+            GROUP BY referrer_category, referrer_domain
+            ORDER BY visits DESC
+            LIMIT ?
+        ",
+            landings_sql()
+        );
+
+        sqlx::query_as(&sql)
+            .bind(session_lookback(from))
+            .bind(to)
+            .bind(from)
+            .bind(limit)
+            .fetch_all(&self.0)
+            .await
+    }
+
+    /// Sessions whose landing request carried `utm_*` parameters, grouped by
+    /// campaign.
+    pub async fn campaigns(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> sqlx::Result<Vec<CampaignReport>> {
+        let sql = format!(
+            "
+            WITH {}
+            SELECT
+                utm_source,
+                utm_medium,
+                utm_campaign,
+                SUM(sample_weight) AS visits,
+                COUNT(DISTINCT visitor) AS visitors
+            FROM landings
+            WHERE utm_source IS NOT NULL OR utm_campaign IS NOT NULL
+            GROUP BY utm_source, utm_medium, utm_campaign
+            ORDER BY visits DESC
+            LIMIT ?
+        ",
+            landings_sql()
+        );
+
+        sqlx::query_as(&sql)
+            .bind(session_lookback(from))
+            .bind(to)
+            .bind(from)
+            .bind(limit)
+            .fetch_all(&self.0)
+            .await
+    }
+
+    pub async fn request_count(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(SUM(sample_weight), 0) FROM sa_request WHERE created_at >= ? AND created_at < ?",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_one(&self.0)
+        .await
+    }
+
+    pub async fn response_counts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> sqlx::Result<ResponseCounts> {
+        sqlx::query_as(
+            "
+            SELECT
+                COALESCE(SUM(sample_weight), 0) AS total,
+                COALESCE(SUM((status >= 400 AND status < 500) * sample_weight), 0) AS client_errors,
+                COALESCE(SUM((status >= 500) * sample_weight), 0) AS server_errors
+            FROM sa_response
+            WHERE created_at >= ? AND created_at < ?
+        ",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_one(&self.0)
+        .await
+    }
+
+    pub async fn latency_quantile(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quantile: f64,
+    ) -> sqlx::Result<Option<Duration>> {
+        let micros: Option<i64> = sqlx::query_scalar(
+            "
+            WITH ranked AS (
+                SELECT
+                    duration_us,
+                    SUM(sample_weight) OVER (ORDER BY duration_us ROWS UNBOUNDED PRECEDING) AS seen,
+                    SUM(sample_weight) OVER () AS total
+                FROM sa_response
+                WHERE created_at >= ? AND created_at < ?
+            )
+            SELECT MIN(duration_us) FROM ranked WHERE seen > ROUND(? * (total - 1))
+        ",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(quantile.clamp(0.0, 1.0))
+        .fetch_one(&self.0)
+        .await?;
+
+        Ok(micros.map(|us| Duration::from_micros(us.max(0) as u64)))
+    }
+
+    pub async fn summary(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> sqlx::Result<Summary> {
+        let visitors = sqlx::query_scalar(&format!(
+            "
+            SELECT COUNT(DISTINCT {VISITOR_SQL})
+            FROM sa_request r
+            JOIN sa_connection c ON c.id = r.conn_id
+            WHERE r.created_at >= ? AND r.created_at < ?
+        "
+        ))
+        .bind(from)
+        .bind(to)
+        .fetch_one(&self.0)
+        .await?;
+
+        let millis = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+
+        Ok(Summary {
+            requests: self.request_count(from, to).await?,
+            visitors,
+            responses: self.response_counts(from, to).await?,
+            p50_ms: millis(self.latency_quantile(from, to, 0.5).await?),
+            p90_ms: millis(self.latency_quantile(from, to, 0.9).await?),
+            p99_ms: millis(self.latency_quantile(from, to, 0.99).await?),
+        })
+    }
+
+    pub async fn top(
+        &self,
+        field: TopField,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> sqlx::Result<Vec<TopEntry>> {
+        let (table, column) = match field {
+            TopField::Paths => ("sa_request", "path"),
+            TopField::Hosts => ("sa_request", "hostname"),
+            TopField::Methods => ("sa_request", "method"),
+            TopField::UserAgents => ("sa_request", "user_agent"),
+            TopField::Referrers => ("sa_request", "COALESCE(referrer_domain, '(direct)')"),
+            TopField::Statuses => ("sa_response", "status"),
+        };
+
+        sqlx::query_as(&format!(
+            "
+            SELECT CAST({column} AS TEXT) AS value, SUM(sample_weight) AS count
+            FROM {table}
+            WHERE created_at >= ? AND created_at < ?
+            GROUP BY value
+            ORDER BY count DESC
+            LIMIT ?
+        "
+        ))
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(&self.0)
+        .await
+    }
+}
+
+/// The quantile of durations sorted in ascending order, each counted as many
+/// times as its sample weight.
+pub(crate) fn quantile_of(sorted: &[(Duration, i64)], quantile: f64) -> Option<Duration> {
+    let total: i64 = sorted.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = (quantile.clamp(0.0, 1.0) * (total - 1) as f64).round() as i64;
+    let mut seen = 0;
+    sorted
+        .iter()
+        .find(|(_, weight)| {
+            seen += weight;
+            seen > rank
+        })
+        .map(|(duration, _)| *duration)
+}
@@ -0,0 +1,218 @@
+use std::{path::Path, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::stream::BoxStream;
+use simple_id::chrono_id::Id as ChronoId;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    alerts::Alert,
+    apdex::{ApdexConfig, ApdexPoint, ApdexScore},
+    export::{self, ExportFormat, ExportTable},
+    slo::{Slo, SloReport},
+    stats::{
+        CampaignReport, CohortMatrix, ResponseCounts, SourceReport, Summary, TopEntry, TopField,
+    },
+    Connection, PoolStats, PruneCounts, Request, Response,
+};
+
+pub mod memory;
+pub mod partitioned;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub(crate) mod scan;
+mod sqlite;
+
+/// Where analytics are recorded and queried from.
+///
+/// Backends only need to store and scan records. The aggregates have default
+/// implementations that scan the rows in Rust, which backends should override
+/// with something that runs closer to the data.
+#[async_trait]
+pub trait AnalyticsStore: std::fmt::Debug + Send + Sync {
+    async fn insert_connection(&self, connection: &Connection) -> anyhow::Result<()>;
+
+    async fn insert_request(&self, request: &Request) -> anyhow::Result<()>;
+
+    async fn insert_response(&self, response: &Response) -> anyhow::Result<()>;
+
+    /// Records a finished request together with its response, or just the
+    /// request when it never completed. Backends that store both in one row
+    /// do this in a single write.
+    async fn insert_exchange(
+        &self,
+        request: &Request,
+        response: Option<&Response>,
+    ) -> anyhow::Result<()> {
+        self.insert_request(request).await?;
+        if let Some(response) = response {
+            self.insert_response(response).await?;
+        }
+        Ok(())
+    }
+
+    async fn connection(&self, id: &ChronoId) -> anyhow::Result<Option<Connection>>;
+
+    /// Connections created in `[from, to)`, oldest first.
+    fn connections<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Connection>>;
+
+    /// Requests created in `[from, to)`, oldest first.
+    fn requests<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Request>>;
+
+    /// Responses created in `[from, to)`, oldest first.
+    fn responses<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Response>>;
+
+    /// The most recent requests, newest first.
+    async fn recent_requests(&self, limit: u32) -> anyhow::Result<Vec<Request>>;
+
+    /// Requests created after `after`, oldest first.
+    async fn requests_since(
+        &self,
+        after: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<Request>>;
+
+    /// The alert currently firing for `rule`, if any.
+    async fn firing_alert(&self, rule: &str) -> anyhow::Result<Option<Alert>>;
+
+    /// Alert history, most recently fired first.
+    async fn alerts(&self, limit: u32) -> anyhow::Result<Vec<Alert>>;
+
+    async fn fire_alert(&self, rule: &str, value: f64, threshold: f64) -> anyhow::Result<Alert>;
+
+    /// Resolves `alert` with the value measured, if there was anything to
+    /// measure.
+    async fn resolve_alert(&self, alert: Alert, value: Option<f64>) -> anyhow::Result<Alert>;
+
+    /// Deletes everything created before `before`.
+    async fn prune(&self, before: &DateTime<Utc>) -> anyhow::Result<PruneCounts>;
+
+    /// Reclaims space left behind by pruning, for backends that need it.
+    async fn vacuum(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Usage of the backend's connection pools, for monitoring.
+    async fn pool_stats(&self) -> Vec<PoolStats> {
+        Vec::new()
+    }
+
+    /// Writes a consistent copy of everything recorded to `path` while
+    /// recording carries on.
+    async fn backup_to(&self, path: &Path) -> anyhow::Result<()> {
+        anyhow::bail!("{self:?} does not support backups to {}", path.display())
+    }
+
+    async fn request_count(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<i64> {
+        scan::request_count(self, from, to).await
+    }
+
+    async fn response_counts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<ResponseCounts> {
+        scan::response_counts(self, from, to).await
+    }
+
+    async fn latency_quantile(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quantile: f64,
+    ) -> anyhow::Result<Option<Duration>> {
+        scan::latency_quantile(self, from, to, quantile).await
+    }
+
+    async fn summary(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<Summary> {
+        scan::summary(self, from, to).await
+    }
+
+    async fn top(
+        &self,
+        field: TopField,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<TopEntry>> {
+        scan::top(self, field, from, to, limit).await
+    }
+
+    async fn cohorts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<CohortMatrix> {
+        scan::cohorts(self, from, to).await
+    }
+
+    /// Sessions grouped by where their landing request was referred from.
+    async fn top_sources(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SourceReport>> {
+        scan::top_sources(self, from, to, limit).await
+    }
+
+    /// Sessions whose landing request carried `utm_*` parameters, grouped by
+    /// campaign.
+    async fn campaigns(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<CampaignReport>> {
+        scan::campaigns(self, from, to, limit).await
+    }
+
+    async fn slo_report(&self, slo: &Slo, now: &DateTime<Utc>) -> anyhow::Result<SloReport> {
+        scan::slo_report(self, slo, now).await
+    }
+
+    async fn apdex(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<ApdexScore> {
+        scan::apdex(self, config, from, to).await
+    }
+
+    async fn apdex_timeseries(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        step: chrono::Duration,
+    ) -> anyhow::Result<Vec<ApdexPoint>> {
+        scan::apdex_timeseries(self, config, from, to, step).await
+    }
+
+    /// Writes every row created in `[from, to)` to `writer`, returning the
+    /// number of rows written.
+    async fn export(
+        &self,
+        table: ExportTable,
+        format: ExportFormat,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> anyhow::Result<u64> {
+        export::export_records(self, table, format, from, to, writer).await
+    }
+}
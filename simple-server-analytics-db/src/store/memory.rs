@@ -0,0 +1,390 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::RwLock,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::{stream::BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use simple_id::chrono_id::Id as ChronoId;
+
+use super::AnalyticsStore;
+use crate::{
+    alerts::{Alert, AlertState},
+    Connection, PruneCounts, Request, Response,
+};
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Running totals since the store was created, including records that have
+/// since been evicted. Sampled records count as many times as their weight.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MemoryTotals {
+    pub connections: u64,
+    pub requests: u64,
+    pub responses: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Records {
+    connections: VecDeque<Connection>,
+    requests: VecDeque<Request>,
+    responses: VecDeque<Response>,
+    alerts: VecDeque<Alert>,
+    totals: MemoryTotals,
+}
+
+/// Keeps the last `capacity` connections, requests, responses and alerts in
+/// memory, evicting the oldest once full. Firing alerts are never evicted, as
+/// they could not be resolved afterwards. Nothing survives a restart.
+#[derive(Debug)]
+pub struct MemoryStore {
+    capacity: usize,
+    records: RwLock<Records>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            records: Default::default(),
+        }
+    }
+
+    pub fn totals(&self) -> MemoryTotals {
+        self.records.read().unwrap().totals
+    }
+
+    fn push<T>(&self, records: &mut VecDeque<T>, record: T) {
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Clones the records created in `[from, to)`, oldest first.
+    fn range<T: Clone>(
+        records: &VecDeque<T>,
+        created_at: impl Fn(&T) -> DateTime<Utc>,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Vec<T> {
+        let mut rows: Vec<T> = records
+            .iter()
+            .filter(|r| (*from..*to).contains(&created_at(r)))
+            .cloned()
+            .collect();
+        rows.sort_by_key(|r| created_at(r));
+        rows
+    }
+}
+
+#[async_trait]
+impl AnalyticsStore for MemoryStore {
+    async fn insert_connection(&self, connection: &Connection) -> anyhow::Result<()> {
+        let mut records = self.records.write().unwrap();
+        records.totals.connections += 1;
+        self.push(&mut records.connections, connection.clone());
+        Ok(())
+    }
+
+    async fn insert_request(&self, request: &Request) -> anyhow::Result<()> {
+        let mut records = self.records.write().unwrap();
+        records.totals.requests += request.sample_weight as u64;
+        self.push(&mut records.requests, request.clone());
+        Ok(())
+    }
+
+    async fn insert_response(&self, response: &Response) -> anyhow::Result<()> {
+        let mut records = self.records.write().unwrap();
+        let weight = response.sample_weight as u64;
+        records.totals.responses += weight;
+        records.totals.client_errors += (400..500).contains(&response.status) as u64 * weight;
+        records.totals.server_errors += (response.status >= 500) as u64 * weight;
+        self.push(&mut records.responses, response.clone());
+        Ok(())
+    }
+
+    async fn connection(&self, id: &ChronoId) -> anyhow::Result<Option<Connection>> {
+        let records = self.records.read().unwrap();
+        Ok(records
+            .connections
+            .iter()
+            .rev()
+            .find(|c| c.id == *id)
+            .cloned())
+    }
+
+    fn connections<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Connection>> {
+        let rows = Self::range(
+            &self.records.read().unwrap().connections,
+            |c| c.created_at,
+            from,
+            to,
+        );
+        futures_util::stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+
+    fn requests<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Request>> {
+        let rows = Self::range(
+            &self.records.read().unwrap().requests,
+            |r| r.created_at,
+            from,
+            to,
+        );
+        futures_util::stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+
+    fn responses<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Response>> {
+        let rows = Self::range(
+            &self.records.read().unwrap().responses,
+            |r| r.created_at,
+            from,
+            to,
+        );
+        futures_util::stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+
+    async fn recent_requests(&self, limit: u32) -> anyhow::Result<Vec<Request>> {
+        let records = self.records.read().unwrap();
+        let mut requests: Vec<Request> = records.requests.iter().cloned().collect();
+        requests.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        requests.truncate(limit as usize);
+        Ok(requests)
+    }
+
+    async fn requests_since(
+        &self,
+        after: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<Request>> {
+        let records = self.records.read().unwrap();
+        let mut requests: Vec<Request> = records
+            .requests
+            .iter()
+            .filter(|r| r.created_at > *after)
+            .cloned()
+            .collect();
+        requests.sort_by_key(|r| r.created_at);
+        requests.truncate(limit as usize);
+        Ok(requests)
+    }
+
+    async fn firing_alert(&self, rule: &str) -> anyhow::Result<Option<Alert>> {
+        let records = self.records.read().unwrap();
+        Ok(records
+            .alerts
+            .iter()
+            .find(|a| a.rule == rule && a.state == AlertState::Firing)
+            .cloned())
+    }
+
+    async fn alerts(&self, limit: u32) -> anyhow::Result<Vec<Alert>> {
+        let records = self.records.read().unwrap();
+        let mut alerts: Vec<Alert> = records.alerts.iter().cloned().collect();
+        alerts.sort_by(|a, b| b.fired_at.cmp(&a.fired_at));
+        alerts.truncate(limit as usize);
+        Ok(alerts)
+    }
+
+    async fn fire_alert(&self, rule: &str, value: f64, threshold: f64) -> anyhow::Result<Alert> {
+        let alert = Alert::new(rule, value, threshold);
+        let mut records = self.records.write().unwrap();
+        if records.alerts.len() >= self.capacity {
+            if let Some(i) = records
+                .alerts
+                .iter()
+                .position(|a| a.state != AlertState::Firing)
+            {
+                records.alerts.remove(i);
+            }
+        }
+        records.alerts.push_back(alert.clone());
+        Ok(alert)
+    }
+
+    async fn resolve_alert(&self, mut alert: Alert, value: Option<f64>) -> anyhow::Result<Alert> {
+        alert.resolve(value);
+        let mut records = self.records.write().unwrap();
+        if let Some(stored) = records.alerts.iter_mut().find(|a| a.id == alert.id) {
+            *stored = alert.clone();
+        }
+        Ok(alert)
+    }
+
+    async fn prune(&self, before: &DateTime<Utc>) -> anyhow::Result<PruneCounts> {
+        let mut records = self.records.write().unwrap();
+        let Records {
+            connections,
+            requests,
+            responses,
+            ..
+        } = &mut *records;
+
+        let pruned_requests: BTreeSet<ChronoId> = requests
+            .iter()
+            .filter(|r| r.created_at < *before)
+            .map(|r| r.id)
+            .collect();
+
+        let counts = PruneCounts {
+            connections: retain_count(connections, |c| c.created_at >= *before),
+            requests: retain_count(requests, |r| r.created_at >= *before),
+            responses: retain_count(responses, |r| {
+                r.created_at >= *before && !pruned_requests.contains(&r.req_id)
+            }),
+        };
+
+        Ok(counts)
+    }
+}
+
+/// Keeps the records matching `keep`, returning how many were removed.
+fn retain_count<T>(records: &mut VecDeque<T>, keep: impl Fn(&T) -> bool) -> u64 {
+    let before = records.len();
+    records.retain(keep);
+    (before - records.len()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::attribution::Attribution;
+
+    fn request(path: &str) -> Request {
+        Request::new(
+            None,
+            "GET",
+            path,
+            "example.com",
+            "",
+            &Attribution::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_at_capacity() {
+        let store = MemoryStore::new(2);
+        for (i, path) in ["/a", "/b", "/c"].into_iter().enumerate() {
+            let mut request = request(path);
+            request.created_at += chrono::Duration::seconds(i as i64);
+            store.insert_request(&request).await.unwrap();
+        }
+
+        let paths: Vec<String> = store
+            .recent_requests(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(paths, ["/c", "/b"]);
+
+        let all: Vec<Request> = store
+            .requests(&DateTime::<Utc>::MIN_UTC, &DateTime::<Utc>::MAX_UTC)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn totals_count_evicted_records() {
+        let store = MemoryStore::new(1);
+        for status in [200, 404, 503] {
+            let mut request = request("/");
+            request.sample_weight = 2;
+            let mut response = Response::new(None, &request.id, &Duration::ZERO, status);
+            response.sample_weight = 2;
+            store
+                .insert_exchange(&request, Some(&response))
+                .await
+                .unwrap();
+        }
+
+        let totals = store.totals();
+        assert_eq!(totals.requests, 6);
+        assert_eq!(totals.responses, 6);
+        assert_eq!(totals.client_errors, 2);
+        assert_eq!(totals.server_errors, 2);
+        assert_eq!(store.recent_requests(10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_firing_alerts_at_capacity() {
+        let store = MemoryStore::new(2);
+        let errors = store.fire_alert("errors", 12.0, 5.0).await.unwrap();
+        let latency = store.fire_alert("latency", 900.0, 500.0).await.unwrap();
+        let resolved = store.resolve_alert(latency, Some(100.0)).await.unwrap();
+        assert_eq!(resolved.state, AlertState::Resolved);
+        assert!(resolved.resolved_at.is_some());
+        assert!(store.firing_alert("latency").await.unwrap().is_none());
+
+        // The resolved alert makes way, while the firing one stays.
+        store.fire_alert("traffic", 80.0, 50.0).await.unwrap();
+        let rules: Vec<String> = store
+            .alerts(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|a| a.rule)
+            .collect();
+        assert_eq!(rules.len(), 2);
+        assert!(rules.contains(&"errors".to_owned()));
+        assert!(rules.contains(&"traffic".to_owned()));
+
+        let firing = store.firing_alert("errors").await.unwrap().unwrap();
+        assert_eq!(firing.id, errors.id);
+        assert_eq!(firing.value, Some(12.0));
+
+        // With only firing alerts left, another still gets recorded.
+        store.fire_alert("disk", 95.0, 90.0).await.unwrap();
+        assert!(store.firing_alert("disk").await.unwrap().is_some());
+        assert!(store.firing_alert("errors").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn prunes_responses_with_their_requests() {
+        let store = MemoryStore::new(10);
+        let mut old = request("/old");
+        old.created_at = Utc::now() - chrono::Duration::days(2);
+        let response = Response::new(None, &old.id, &Duration::ZERO, 200);
+        store.insert_exchange(&old, Some(&response)).await.unwrap();
+        store.insert_request(&request("/new")).await.unwrap();
+
+        let counts = store
+            .prune(&(Utc::now() - chrono::Duration::days(1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            (counts.requests, counts.responses, counts.connections),
+            (1, 1, 0)
+        );
+        assert_eq!(store.recent_requests(10).await.unwrap()[0].path, "/new");
+    }
+}
@@ -0,0 +1,1206 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, Utc};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use simple_id::chrono_id::Id as ChronoId;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    Executor, FromRow,
+};
+use tokio::{io::AsyncWrite, sync::Mutex};
+
+use super::{scan, AnalyticsStore};
+use crate::{
+    alerts::Alert,
+    apdex::{ApdexConfig, ApdexPoint, ApdexScore},
+    archive::{self, ArchiveCounts},
+    export::{ExportFormat, ExportTable},
+    slo::{Slo, SloReport, SloTally},
+    stats::{
+        CampaignReport, CohortMatrix, ResponseCounts, SourceReport, Summary, TopEntry, TopField,
+    },
+    Connection, Db, PoolStats, PruneCounts, Request, Response, ResponseTable,
+};
+
+/// SQLite refuses to attach more databases than this to one connection.
+const MAX_ATTACHED: usize = 10;
+
+/// Rows fetched per query when streaming a partition.
+const PAGE_SIZE: usize = 1000;
+
+/// Responses are joined to requests made up to this long before them, which
+/// may sit in the previous partition.
+const LOOKBACK_HOURS: i64 = 1;
+
+const ALERTS_FILE: &str = "alerts.db";
+
+/// Compressed partitions are moved here, under the partition directory.
+const ARCHIVE_DIR: &str = "archive";
+
+/// Archived partitions are decompressed here while they are being queried.
+const EXTRACTED_DIR: &str = "extracted";
+
+/// Extension of the file next to each archive recording its row counts, so
+/// pruning can report them without decompressing it.
+const COUNTS_EXTENSION: &str = "counts.json";
+
+/// How much time each partition file covers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionPeriod {
+    #[default]
+    Month,
+    /// ISO weeks, starting on Monday.
+    Week,
+}
+
+impl PartitionPeriod {
+    /// The first day of the partition `date` falls in.
+    pub fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Month => date.with_day(1).unwrap(),
+            Self::Week => {
+                date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)
+            }
+        }
+    }
+
+    /// The first day of the partition after the one starting on `start`.
+    pub fn next(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Month => start + Months::new(1),
+            Self::Week => start + chrono::Duration::days(7),
+        }
+    }
+}
+
+/// Writes each month (or week) of records to its own SQLite file in `dir`,
+/// named after the day the partition starts, e.g. `2023-10-01.db`.
+///
+/// Queries spanning several partitions attach them read-only to a scratch
+/// connection, and retention deletes whole files rather than rows. Alerts are
+/// kept in `alerts.db` so they survive rotation.
+///
+/// Partitions that have aged out can instead be compressed into `archive/`,
+/// where they are still queried transparently, at the cost of decompressing
+/// them the first time a query touches them. Retention prunes archives the
+/// same as any other partition, using the row counts recorded when they were
+/// archived.
+#[derive(Debug)]
+pub struct PartitionedDb {
+    dir: PathBuf,
+    period: PartitionPeriod,
+    partitions: Mutex<BTreeMap<NaiveDate, Db>>,
+    /// Read copies of archived partitions.
+    extracted: Mutex<BTreeMap<NaiveDate, Db>>,
+    alerts: Db,
+}
+
+impl PartitionedDb {
+    pub async fn new<P: AsRef<Path>>(dir: P, period: PartitionPeriod) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        tokio::fs::create_dir_all(&dir).await?;
+        let alerts = Db::new(dir.join(ALERTS_FILE)).await?;
+
+        // Copies left behind by a previous run may be stale.
+        remove_dir_if_exists(&dir.join(ARCHIVE_DIR).join(EXTRACTED_DIR)).await?;
+
+        Ok(Self {
+            dir,
+            period,
+            partitions: Default::default(),
+            extracted: Default::default(),
+            alerts,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn period(&self) -> PartitionPeriod {
+        self.period
+    }
+
+    /// Start dates of the partition files on disk, oldest first.
+    pub async fn partition_starts(&self) -> std::io::Result<Vec<NaiveDate>> {
+        list_starts(&self.dir, ".db").await
+    }
+
+    /// Start dates of the archived partitions, oldest first.
+    pub async fn archived_starts(&self) -> std::io::Result<Vec<NaiveDate>> {
+        list_starts(&self.archive_dir(), &format!(".db.{}", archive::EXTENSION)).await
+    }
+
+    /// Start dates of every partition, archived or not, oldest first.
+    async fn all_starts(&self) -> std::io::Result<Vec<NaiveDate>> {
+        let mut starts = self.partition_starts().await?;
+        starts.extend(self.archived_starts().await?);
+        starts.sort();
+        starts.dedup();
+        Ok(starts)
+    }
+
+    fn path_of(&self, start: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{start}.db"))
+    }
+
+    fn archive_dir(&self) -> PathBuf {
+        self.dir.join(ARCHIVE_DIR)
+    }
+
+    fn archive_path_of(&self, start: NaiveDate) -> PathBuf {
+        self.archive_dir()
+            .join(format!("{start}.db.{}", archive::EXTENSION))
+    }
+
+    fn counts_path_of(&self, start: NaiveDate) -> PathBuf {
+        self.archive_dir()
+            .join(format!("{start}.db.{COUNTS_EXTENSION}"))
+    }
+
+    fn extracted_path_of(&self, start: NaiveDate) -> PathBuf {
+        self.archive_dir()
+            .join(EXTRACTED_DIR)
+            .join(format!("{start}.db"))
+    }
+
+    fn start_of(&self, at: &DateTime<Utc>) -> NaiveDate {
+        self.period.start_of(at.date_naive())
+    }
+
+    fn end_of(&self, start: NaiveDate) -> DateTime<Utc> {
+        self.period.next(start).and_time(NaiveTime::MIN).and_utc()
+    }
+
+    /// Opens the partition starting on `start` for writing, creating it if
+    /// need be. An archived partition is restored first.
+    async fn open(&self, start: NaiveDate) -> anyhow::Result<Db> {
+        let mut partitions = self.partitions.lock().await;
+        if let Some(db) = partitions.get(&start) {
+            return Ok(db.clone());
+        }
+
+        if tokio::fs::try_exists(self.archive_path_of(start)).await? {
+            self.restore_unlocked(start).await?;
+        }
+        let db = open_partition(self.path_of(start)).await?;
+        partitions.insert(start, db.clone());
+        Ok(db)
+    }
+
+    /// Opens the partition starting on `start` without creating it. Pruning
+    /// and archiving delete files while holding the partitions lock, so one
+    /// that is gone once the lock is taken has been dropped and stays that
+    /// way.
+    async fn open_existing(&self, start: NaiveDate) -> anyhow::Result<Db> {
+        let mut partitions = self.partitions.lock().await;
+        if let Some(db) = partitions.get(&start) {
+            return Ok(db.clone());
+        }
+
+        anyhow::ensure!(
+            tokio::fs::try_exists(self.path_of(start)).await?,
+            "partition {start} has been dropped"
+        );
+        let db = open_partition(self.path_of(start)).await?;
+        partitions.insert(start, db.clone());
+        Ok(db)
+    }
+
+    /// Opens the partition starting on `start` for reading, decompressing a
+    /// copy if it has been archived.
+    async fn open_read(&self, start: NaiveDate) -> anyhow::Result<Db> {
+        if tokio::fs::try_exists(self.path_of(start)).await? {
+            return self.open_existing(start).await;
+        }
+        self.extract(start).await
+    }
+
+    /// The file to read the partition starting on `start` from.
+    async fn read_path(&self, start: NaiveDate) -> anyhow::Result<PathBuf> {
+        if tokio::fs::try_exists(self.path_of(start)).await? {
+            return Ok(self.path_of(start));
+        }
+        self.extract(start).await?;
+        Ok(self.extracted_path_of(start))
+    }
+
+    async fn extract(&self, start: NaiveDate) -> anyhow::Result<Db> {
+        let mut extracted = self.extracted.lock().await;
+        self.extract_locked(&mut extracted, start).await
+    }
+
+    async fn extract_locked(
+        &self,
+        extracted: &mut BTreeMap<NaiveDate, Db>,
+        start: NaiveDate,
+    ) -> anyhow::Result<Db> {
+        if let Some(db) = extracted.get(&start) {
+            return Ok(db.clone());
+        }
+
+        let path = self.extracted_path_of(start);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        archive::decompress_file(&self.archive_path_of(start), &path).await?;
+        let db = open_partition(path).await?;
+        extracted.insert(start, db.clone());
+        Ok(db)
+    }
+
+    async fn writer(&self, at: &DateTime<Utc>) -> anyhow::Result<Db> {
+        self.open(self.start_of(at)).await
+    }
+
+    /// Compresses the partitions that ended by `before` into the archive
+    /// directory and deletes the originals. A partition that straddles
+    /// `before` is kept until it has ended.
+    pub async fn archive(&self, before: &DateTime<Utc>) -> anyhow::Result<ArchiveCounts> {
+        tokio::fs::create_dir_all(self.archive_dir()).await?;
+
+        let mut counts = ArchiveCounts::default();
+        for start in self.partition_starts().await? {
+            if self.end_of(start) > *before {
+                break;
+            }
+
+            // Held until the original is deleted, so no write can land in it
+            // after it has been copied.
+            let mut partitions = self.partitions.lock().await;
+            let Some(db) = self.take(&mut partitions, start).await? else {
+                continue;
+            };
+
+            // Fold the WAL into the main file so it is the only one to copy.
+            sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+                .execute(db.writer())
+                .await?;
+            let mut rows = PruneCounts::default();
+            count_rows(&db, &mut rows).await?;
+            db.close().await;
+            tokio::fs::write(self.counts_path_of(start), serde_json::to_vec(&rows)?).await?;
+
+            let path = self.path_of(start);
+            counts.bytes_in += tokio::fs::metadata(&path).await?.len();
+            counts.bytes_out +=
+                archive::compress_file(&path, &self.archive_path_of(start), archive::LEVEL).await?;
+            counts.partitions += 1;
+            remove_partition_files(&path).await?;
+            drop(partitions);
+
+            tracing::info!(partition = %start, "archived partition");
+        }
+        Ok(counts)
+    }
+
+    /// Decompresses an archived partition back into the partition directory
+    /// and deletes the archive.
+    pub async fn restore(&self, start: NaiveDate) -> anyhow::Result<()> {
+        let _partitions = self.partitions.lock().await;
+        self.restore_unlocked(start).await
+    }
+
+    async fn restore_unlocked(&self, start: NaiveDate) -> anyhow::Result<()> {
+        let path = self.path_of(start);
+        anyhow::ensure!(
+            !tokio::fs::try_exists(&path).await?,
+            "partition {start} has not been archived"
+        );
+
+        let archived = self.archive_path_of(start);
+        archive::decompress_file(&archived, &path).await?;
+        tokio::fs::remove_file(&archived).await?;
+        remove_file_if_exists(&self.counts_path_of(start)).await?;
+
+        if let Some(db) = self.extracted.lock().await.remove(&start) {
+            db.close().await;
+            remove_partition_files(&self.extracted_path_of(start)).await?;
+        }
+
+        tracing::info!(partition = %start, "restored partition");
+        Ok(())
+    }
+
+    /// Removes the partition starting on `start` from `partitions`, opening
+    /// it if it wasn't, so the caller can delete it while holding the lock.
+    /// `None` if it has already been deleted.
+    async fn take(
+        &self,
+        partitions: &mut BTreeMap<NaiveDate, Db>,
+        start: NaiveDate,
+    ) -> anyhow::Result<Option<Db>> {
+        if let Some(db) = partitions.remove(&start) {
+            return Ok(Some(db));
+        }
+        if !tokio::fs::try_exists(self.path_of(start)).await? {
+            return Ok(None);
+        }
+        Ok(Some(open_partition(self.path_of(start)).await?))
+    }
+
+    /// Start dates of the partitions overlapping `[from, to)`, oldest first.
+    async fn starts_in(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> std::io::Result<Vec<NaiveDate>> {
+        let first = self.start_of(from);
+        Ok(self
+            .all_starts()
+            .await?
+            .into_iter()
+            .filter(|start| *start >= first && start.and_time(NaiveTime::MIN).and_utc() < *to)
+            .collect())
+    }
+
+    /// A connection with every partition overlapping `[from, to)` attached
+    /// read-only and `sa_*` views over all of them, or `None` when the range
+    /// spans more partitions than can be attached at once.
+    async fn reader(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<Option<Db>> {
+        let from = *from - chrono::Duration::hours(LOOKBACK_HOURS);
+        let starts = self.starts_in(&from, to).await?;
+        if starts.len() > MAX_ATTACHED {
+            return Ok(None);
+        }
+
+        let mut statements = Vec::new();
+        for (i, start) in starts.iter().enumerate() {
+            let uri = read_only_uri(&self.read_path(*start).await?).await?;
+            statements.push(format!(
+                "ATTACH DATABASE '{}' AS p{i}",
+                uri.replace('\'', "''")
+            ));
+        }
+        if !starts.is_empty() {
+            for table in ["sa_connection", "sa_request", "sa_response"] {
+                let union = (0..starts.len())
+                    .map(|i| format!("SELECT * FROM p{i}.{table}"))
+                    .collect::<Vec<_>>()
+                    .join(" UNION ALL ");
+                statements.push(format!("CREATE TEMP VIEW {table} AS {union}"));
+            }
+        }
+        let statements = Arc::new(statements);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .after_connect(move |conn, _| {
+                let statements = statements.clone();
+                Box::pin(async move {
+                    for statement in statements.iter() {
+                        conn.execute(statement.as_str()).await?;
+                    }
+                    Ok(())
+                })
+            })
+            .connect_with(SqliteConnectOptions::new().in_memory(true))
+            .await?;
+
+        let reader = Db::from_pool(pool);
+        if starts.is_empty() {
+            // Nothing recorded in range, so query an empty schema.
+            reader.migrate().await?;
+        }
+        Ok(Some(reader))
+    }
+
+    /// Streams `table` across the partitions overlapping `[from, to)`, a page
+    /// at a time so no partition's connection is held between items.
+    fn paged<'a, S, T>(
+        &'a self,
+        table: &'static str,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+        from_stored: fn(S) -> T,
+        key: fn(&T) -> (DateTime<Utc>, ChronoId),
+    ) -> BoxStream<'a, anyhow::Result<T>>
+    where
+        S: for<'r> FromRow<'r, SqliteRow> + Send + Unpin + 'a,
+        T: Send + 'a,
+    {
+        struct Cursor {
+            partitions: VecDeque<Db>,
+            after: Option<(DateTime<Utc>, ChronoId)>,
+        }
+
+        let first_page = format!(
+            "SELECT * FROM {table} WHERE created_at >= ? AND created_at < ? ORDER BY created_at, id LIMIT ?"
+        );
+        let next_page = format!(
+            "
+            SELECT * FROM {table}
+            WHERE created_at < ? AND (created_at > ? OR (created_at = ? AND id > ?))
+            ORDER BY created_at, id
+            LIMIT ?
+        "
+        );
+
+        futures_util::stream::once(async move {
+            let mut partitions = VecDeque::new();
+            for start in self.starts_in(from, to).await? {
+                partitions.push_back(self.open_read(start).await?);
+            }
+            Ok::<_, anyhow::Error>(Cursor {
+                partitions,
+                after: None,
+            })
+        })
+        .map_ok(move |cursor| {
+            let first_page = first_page.clone();
+            let next_page = next_page.clone();
+            futures_util::stream::try_unfold(cursor, move |mut cursor| {
+                let first_page = first_page.clone();
+                let next_page = next_page.clone();
+                async move {
+                    while let Some(db) = cursor.partitions.front() {
+                        let rows: Vec<S> = match &cursor.after {
+                            None => {
+                                sqlx::query_as(&first_page)
+                                    .bind(from)
+                                    .bind(to)
+                                    .bind(PAGE_SIZE as i64)
+                                    .fetch_all(db.reader())
+                                    .await?
+                            }
+                            Some((created_at, id)) => {
+                                sqlx::query_as(&next_page)
+                                    .bind(to)
+                                    .bind(created_at)
+                                    .bind(created_at)
+                                    .bind(id)
+                                    .bind(PAGE_SIZE as i64)
+                                    .fetch_all(db.reader())
+                                    .await?
+                            }
+                        };
+                        let rows: Vec<T> = rows.into_iter().map(from_stored).collect();
+
+                        if rows.len() < PAGE_SIZE {
+                            cursor.partitions.pop_front();
+                            cursor.after = None;
+                        } else {
+                            cursor.after = rows.last().map(key);
+                        }
+                        if !rows.is_empty() {
+                            return Ok(Some((rows, cursor)));
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(None)
+                }
+            })
+            .map_ok(|rows| futures_util::stream::iter(rows.into_iter().map(Ok)))
+            .try_flatten()
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+#[async_trait]
+impl AnalyticsStore for PartitionedDb {
+    async fn insert_connection(&self, connection: &Connection) -> anyhow::Result<()> {
+        let db = self.writer(&connection.created_at).await?;
+        db.insert_connection(connection).await
+    }
+
+    async fn insert_request(&self, request: &Request) -> anyhow::Result<()> {
+        let db = self.writer(&request.created_at).await?;
+        db.insert_request(request).await
+    }
+
+    /// The response is stored on its request's row, which may be in the
+    /// partition before the one the response falls in.
+    async fn insert_response(&self, response: &Response) -> anyhow::Result<()> {
+        let db = self.writer(&response.created_at).await?;
+        match ResponseTable::insert_record(db.writer(), response).await {
+            Err(sqlx::Error::RowNotFound) => {
+                let start = self.start_of(&response.created_at);
+                let previous = self.period.start_of(start.pred_opt().unwrap());
+                anyhow::ensure!(
+                    tokio::fs::try_exists(self.path_of(previous)).await?,
+                    "no request {:?} for response {:?}",
+                    response.req_id,
+                    response.id
+                );
+                let db = self.open_existing(previous).await?;
+                Ok(ResponseTable::insert_record(db.writer(), response).await?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    async fn insert_exchange(
+        &self,
+        request: &Request,
+        response: Option<&Response>,
+    ) -> anyhow::Result<()> {
+        let db = self.writer(&request.created_at).await?;
+        db.insert_exchange(request, response).await
+    }
+
+    /// Looks in the partitions newest first, only decompressing archives
+    /// once none of the partitions on disk has the connection. Scans load
+    /// the connections in their range up front instead, see [`scan`].
+    async fn connection(&self, id: &ChronoId) -> anyhow::Result<Option<Connection>> {
+        for start in self.partition_starts().await?.into_iter().rev() {
+            let db = match self.open_existing(start).await {
+                Ok(db) => db,
+                // Archived or pruned since it was listed.
+                Err(_) => continue,
+            };
+            if let Some(connection) = db.connection_table().get(id).await? {
+                return Ok(Some(connection));
+            }
+        }
+        for start in self.archived_starts().await?.into_iter().rev() {
+            let db = self.open_read(start).await?;
+            if let Some(connection) = db.connection_table().get(id).await? {
+                return Ok(Some(connection));
+            }
+        }
+        Ok(None)
+    }
+
+    fn connections<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Connection>> {
+        self.paged("sa_connection", from, to, Connection::from_stored, |c| {
+            (c.created_at, c.id)
+        })
+    }
+
+    fn requests<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Request>> {
+        self.paged("sa_request", from, to, std::convert::identity, |r| {
+            (r.created_at, r.id)
+        })
+    }
+
+    fn responses<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Response>> {
+        self.paged("sa_response", from, to, Response::from_stored, |r| {
+            (r.created_at, r.id)
+        })
+    }
+
+    async fn recent_requests(&self, limit: u32) -> anyhow::Result<Vec<Request>> {
+        let mut requests = Vec::new();
+        for start in self.all_starts().await?.into_iter().rev() {
+            let remaining = limit - requests.len() as u32;
+            if remaining == 0 {
+                break;
+            }
+            let db = self.open_read(start).await?;
+            requests.extend(db.request_table().recent(remaining).await?);
+        }
+        Ok(requests)
+    }
+
+    async fn requests_since(
+        &self,
+        after: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<Request>> {
+        let first = self.start_of(after);
+        let mut requests = Vec::new();
+        for start in self.all_starts().await? {
+            let remaining = limit - requests.len() as u32;
+            if remaining == 0 {
+                break;
+            }
+            if start < first {
+                continue;
+            }
+            let db = self.open_read(start).await?;
+            requests.extend(db.request_table().since(after, remaining).await?);
+        }
+        Ok(requests)
+    }
+
+    async fn firing_alert(&self, rule: &str) -> anyhow::Result<Option<Alert>> {
+        self.alerts.firing_alert(rule).await
+    }
+
+    async fn alerts(&self, limit: u32) -> anyhow::Result<Vec<Alert>> {
+        AnalyticsStore::alerts(&self.alerts, limit).await
+    }
+
+    async fn fire_alert(&self, rule: &str, value: f64, threshold: f64) -> anyhow::Result<Alert> {
+        self.alerts.fire_alert(rule, value, threshold).await
+    }
+
+    async fn resolve_alert(&self, alert: Alert, value: Option<f64>) -> anyhow::Result<Alert> {
+        self.alerts.resolve_alert(alert, value).await
+    }
+
+    /// Deletes the files of partitions that ended by `before`, archived or
+    /// not. A partition that straddles `before` is kept whole until it has
+    /// ended. Archives' rows are counted from what was recorded when they
+    /// were archived, or not at all for archives that predate recording it.
+    async fn prune(&self, before: &DateTime<Utc>) -> anyhow::Result<PruneCounts> {
+        let mut counts = PruneCounts::default();
+        for start in self.partition_starts().await? {
+            if self.end_of(start) > *before {
+                break;
+            }
+
+            // Held until the files are gone, so nothing reopens the partition
+            // in between.
+            let mut partitions = self.partitions.lock().await;
+            let Some(db) = self.take(&mut partitions, start).await? else {
+                continue;
+            };
+            count_rows(&db, &mut counts).await?;
+            db.close().await;
+            remove_partition_files(&self.path_of(start)).await?;
+            drop(partitions);
+
+            tracing::info!(partition = %start, "dropped partition");
+        }
+
+        for start in self.archived_starts().await? {
+            if self.end_of(start) > *before {
+                break;
+            }
+
+            // Taken in the same order as when restoring.
+            let _partitions = self.partitions.lock().await;
+            let mut extracted = self.extracted.lock().await;
+            let archived = self.archive_path_of(start);
+            if !tokio::fs::try_exists(&archived).await? {
+                continue;
+            }
+            let counts_path = self.counts_path_of(start);
+            match tokio::fs::read(&counts_path).await {
+                Ok(json) => {
+                    let rows: PruneCounts = serde_json::from_slice(&json)?;
+                    counts.connections += rows.connections;
+                    counts.requests += rows.requests;
+                    counts.responses += rows.responses;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    tracing::warn!(partition = %start, "no row counts recorded for archive");
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(db) = extracted.remove(&start) {
+                db.close().await;
+                remove_partition_files(&self.extracted_path_of(start)).await?;
+            }
+            tokio::fs::remove_file(&archived).await?;
+            remove_file_if_exists(&counts_path).await?;
+
+            tracing::info!(partition = %start, "dropped archived partition");
+        }
+        Ok(counts)
+    }
+
+    async fn vacuum(&self) -> anyhow::Result<()> {
+        for start in self.partition_starts().await? {
+            self.open_existing(start).await?.vacuum().await?;
+        }
+        Ok(())
+    }
+
+    /// Open partitions' pools added up by role, alongside the alerts'.
+    async fn pool_stats(&self) -> Vec<PoolStats> {
+        let partitions = self.partitions.lock().await;
+        let extracted = self.extracted.lock().await;
+        let stats: Vec<PoolStats> = partitions
+            .values()
+            .chain(extracted.values())
+            .flat_map(Db::pool_stats)
+            .collect();
+
+        let mut totals: Vec<PoolStats> = ["writer", "reader"]
+            .into_iter()
+            .map(|role| PoolStats::sum(role, stats.iter().filter(|s| s.pool == role).copied()))
+            .collect();
+        totals.push(PoolStats::of("alerts", self.alerts.writer()));
+        totals
+    }
+
+    /// Backs up every partition, archive and the alerts into the directory
+    /// `path`, laid out like the partition directory so it can be opened
+    /// with [`PartitionedDb::new`]. Any directory already at `path` is
+    /// replaced once the backup is complete.
+    async fn backup_to(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        remove_dir_if_exists(&tmp).await?;
+        tokio::fs::create_dir_all(tmp.join(ARCHIVE_DIR)).await?;
+
+        for start in self.partition_starts().await? {
+            let db = self.open_existing(start).await?;
+            db.backup_to(tmp.join(format!("{start}.db"))).await?;
+        }
+        for start in self.archived_starts().await? {
+            let archived = self.archive_path_of(start);
+            tokio::fs::copy(
+                &archived,
+                tmp.join(ARCHIVE_DIR).join(archived.file_name().unwrap()),
+            )
+            .await?;
+            let counts_path = self.counts_path_of(start);
+            if tokio::fs::try_exists(&counts_path).await? {
+                tokio::fs::copy(
+                    &counts_path,
+                    tmp.join(ARCHIVE_DIR).join(counts_path.file_name().unwrap()),
+                )
+                .await?;
+            }
+        }
+        self.alerts.backup_to(tmp.join(ALERTS_FILE)).await?;
+
+        remove_dir_if_exists(path).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    async fn request_count(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<i64> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().request_count(from, to).await?),
+            None => scan::request_count(self, from, to).await,
+        }
+    }
+
+    async fn response_counts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<ResponseCounts> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().response_counts(from, to).await?),
+            None => scan::response_counts(self, from, to).await,
+        }
+    }
+
+    async fn latency_quantile(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quantile: f64,
+    ) -> anyhow::Result<Option<Duration>> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().latency_quantile(from, to, quantile).await?),
+            None => scan::latency_quantile(self, from, to, quantile).await,
+        }
+    }
+
+    async fn summary(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<Summary> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().summary(from, to).await?),
+            None => scan::summary(self, from, to).await,
+        }
+    }
+
+    async fn top(
+        &self,
+        field: TopField,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<TopEntry>> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().top(field, from, to, limit).await?),
+            None => scan::top(self, field, from, to, limit).await,
+        }
+    }
+
+    async fn cohorts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<CohortMatrix> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().cohorts(from, to).await?),
+            None => scan::cohorts(self, from, to).await,
+        }
+    }
+
+    async fn top_sources(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SourceReport>> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().top_sources(from, to, limit).await?),
+            None => scan::top_sources(self, from, to, limit).await,
+        }
+    }
+
+    async fn campaigns(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<CampaignReport>> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().campaigns(from, to, limit).await?),
+            None => scan::campaigns(self, from, to, limit).await,
+        }
+    }
+
+    async fn slo_report(&self, slo: &Slo, now: &DateTime<Utc>) -> anyhow::Result<SloReport> {
+        let from = SloTally::new(slo, now).from();
+        match self.reader(&from, now).await? {
+            Some(reader) => Ok(reader.stats().slo_report(slo, now).await?),
+            None => scan::slo_report(self, slo, now).await,
+        }
+    }
+
+    async fn apdex(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<ApdexScore> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader.stats().apdex(config, from, to).await?),
+            None => scan::apdex(self, config, from, to).await,
+        }
+    }
+
+    async fn apdex_timeseries(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        step: chrono::Duration,
+    ) -> anyhow::Result<Vec<ApdexPoint>> {
+        match self.reader(from, to).await? {
+            Some(reader) => Ok(reader
+                .stats()
+                .apdex_timeseries(config, from, to, step)
+                .await?),
+            None => scan::apdex_timeseries(self, config, from, to, step).await,
+        }
+    }
+
+    async fn export(
+        &self,
+        table: ExportTable,
+        format: ExportFormat,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> anyhow::Result<u64> {
+        match self.reader(from, to).await? {
+            Some(reader) => {
+                reader
+                    .exporter()
+                    .export(table, format, from, to, writer)
+                    .await
+            }
+            None => crate::export::export_records(self, table, format, from, to, writer).await,
+        }
+    }
+}
+
+/// Responses can land in a later partition than their request, so foreign
+/// keys are not enforced.
+async fn open_partition(path: PathBuf) -> sqlx::Result<Db> {
+    Db::with_options(Db::options(path).foreign_keys(false)).await
+}
+
+/// A `file:` URI opening `path` read-only, with the path percent-encoded so
+/// names containing `?`, `#` or `%` aren't mistaken for URI syntax.
+async fn read_only_uri(path: &Path) -> anyhow::Result<String> {
+    let path = tokio::fs::canonicalize(path).await?;
+    let mut uri = url::Url::from_file_path(&path)
+        .map_err(|()| anyhow::anyhow!("can't make a URI of {}", path.display()))?;
+    uri.set_query(Some("mode=ro"));
+    Ok(uri.into())
+}
+
+/// Start dates of the files in `dir` named `{start}{suffix}`, oldest first.
+async fn list_starts(dir: &Path, suffix: &str) -> std::io::Result<Vec<NaiveDate>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut starts = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(start) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(suffix))
+            .and_then(|stem| stem.parse::<NaiveDate>().ok())
+        {
+            starts.push(start);
+        }
+    }
+    starts.sort();
+    Ok(starts)
+}
+
+/// Adds the rows in a partition to `counts`.
+async fn count_rows(db: &Db, counts: &mut PruneCounts) -> sqlx::Result<()> {
+    let (connections, requests, responses): (i64, i64, i64) = sqlx::query_as(
+        "
+        SELECT
+            (SELECT COUNT(*) FROM sa_connection),
+            (SELECT COUNT(*) FROM sa_request),
+            (SELECT COUNT(*) FROM sa_response)
+    ",
+    )
+    .fetch_one(db.reader())
+    .await?;
+    counts.connections += connections as u64;
+    counts.requests += requests as u64;
+    counts.responses += responses as u64;
+    Ok(())
+}
+
+/// Deletes a database file along with its WAL and shared memory files.
+async fn remove_partition_files(path: &Path) -> std::io::Result<()> {
+    tokio::fs::remove_file(path).await?;
+    for suffix in ["-wal", "-shm"] {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        match tokio::fs::remove_file(sidecar).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+async fn remove_file_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+async fn remove_dir_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::attribution::Attribution;
+
+    /// A fresh directory whose name needs escaping in a URI.
+    async fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ssa-partitioned-{}-{name} ?#%", std::process::id()));
+        remove_dir_if_exists(&dir).await.unwrap();
+        dir
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn connection(created_at: DateTime<Utc>) -> Connection {
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut connection = Connection::new(
+            &addr,
+            &"192.0.2.1:50000".parse().unwrap(),
+            &http::uri::Scheme::HTTP,
+            &http::Version::HTTP_11,
+        );
+        connection.created_at = created_at;
+        connection
+    }
+
+    fn request(conn_id: Option<&ChronoId>, path: &str, created_at: DateTime<Utc>) -> Request {
+        let mut request = Request::new(
+            conn_id,
+            "GET",
+            path,
+            "example.com",
+            "",
+            &Attribution::default(),
+        );
+        request.created_at = created_at;
+        request
+    }
+
+    fn response(request: &Request, created_at: DateTime<Utc>) -> Response {
+        let mut response = Response::new(
+            request.conn_id.as_ref(),
+            &request.id,
+            &Duration::from_millis(5),
+            200,
+        );
+        response.created_at = created_at;
+        response
+    }
+
+    #[tokio::test]
+    async fn reads_across_a_period_boundary() {
+        let dir = temp_dir("boundary").await;
+        let store = PartitionedDb::new(&dir, PartitionPeriod::Week)
+            .await
+            .unwrap();
+
+        // Sunday night, answered after midnight in the next week's partition.
+        let conn = connection(at("2023-10-08T23:29:00Z"));
+        let late = request(Some(&conn.id), "/late", at("2023-10-08T23:30:00Z"));
+        let next = request(Some(&conn.id), "/next", at("2023-10-09T00:20:00Z"));
+        store.insert_connection(&conn).await.unwrap();
+        store.insert_request(&late).await.unwrap();
+        store.insert_request(&next).await.unwrap();
+        store
+            .insert_response(&response(&late, at("2023-10-09T00:10:00Z")))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.partition_starts().await.unwrap(),
+            [
+                at("2023-10-02T00:00:00Z").date_naive(),
+                at("2023-10-09T00:00:00Z").date_naive()
+            ]
+        );
+
+        let (from, to) = (at("2023-10-08T00:00:00Z"), at("2023-10-10T00:00:00Z"));
+        let paths: Vec<String> = store
+            .requests(&from, &to)
+            .map_ok(|r| r.path)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(paths, ["/late", "/next"]);
+        let responses: Vec<Response> = store.responses(&from, &to).try_collect().await.unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].req_id, late.id);
+
+        // The response is stored with its request in the first partition, but
+        // still counted by when it was sent.
+        let reader = store.reader(&from, &to).await.unwrap().unwrap();
+        let stats = reader.stats();
+        assert_eq!(stats.request_count(&from, &to).await.unwrap(), 2);
+        let monday = at("2023-10-09T00:00:00Z");
+        assert_eq!(stats.response_counts(&monday, &to).await.unwrap().total, 1);
+        assert_eq!(store.summary(&from, &to).await.unwrap().visitors, 1);
+        assert_eq!(
+            store
+                .connection(&conn.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .created_at,
+            conn.created_at
+        );
+
+        remove_dir_if_exists(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn prune_drops_whole_partitions() {
+        let dir = temp_dir("prune").await;
+        let store = PartitionedDb::new(&dir, PartitionPeriod::Month)
+            .await
+            .unwrap();
+        for created_at in [
+            "2023-01-20T12:00:00Z",
+            "2023-01-31T23:00:00Z",
+            "2023-02-15T12:00:00Z",
+        ] {
+            let request = request(None, "/", at(created_at));
+            store.insert_request(&request).await.unwrap();
+            store
+                .insert_response(&response(&request, at(created_at)))
+                .await
+                .unwrap();
+        }
+
+        // February straddles the cutoff, so it is kept whole.
+        let counts = store.prune(&at("2023-02-10T00:00:00Z")).await.unwrap();
+        assert_eq!((counts.requests, counts.responses), (2, 2));
+        assert_eq!(
+            store.partition_starts().await.unwrap(),
+            [at("2023-02-01T00:00:00Z").date_naive()]
+        );
+        assert!(!tokio::fs::try_exists(dir.join("2023-01-01.db"))
+            .await
+            .unwrap());
+        assert_eq!(
+            store
+                .request_count(&at("2023-01-01T00:00:00Z"), &at("2023-03-01T00:00:00Z"))
+                .await
+                .unwrap(),
+            1
+        );
+
+        let counts = store.prune(&at("2023-03-01T00:00:00Z")).await.unwrap();
+        assert_eq!(counts.requests, 1);
+        assert!(store.partition_starts().await.unwrap().is_empty());
+
+        remove_dir_if_exists(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn archives_restores_and_reads_archived_partitions() {
+        let dir = temp_dir("archive").await;
+        let store = PartitionedDb::new(&dir, PartitionPeriod::Month)
+            .await
+            .unwrap();
+        for created_at in ["2023-01-20T12:00:00Z", "2023-02-15T12:00:00Z"] {
+            let request = request(None, "/", at(created_at));
+            store.insert_request(&request).await.unwrap();
+            store
+                .insert_response(&response(&request, at(created_at)))
+                .await
+                .unwrap();
+        }
+        let january = at("2023-01-01T00:00:00Z").date_naive();
+        let (from, to) = (at("2023-01-01T00:00:00Z"), at("2023-03-01T00:00:00Z"));
+
+        let counts = store.archive(&at("2023-02-10T00:00:00Z")).await.unwrap();
+        assert_eq!(counts.partitions, 1);
+        assert_eq!(store.archived_starts().await.unwrap(), [january]);
+        assert!(!store.partition_starts().await.unwrap().contains(&january));
+
+        // Both through the attached reader and by paging partitions.
+        assert_eq!(store.request_count(&from, &to).await.unwrap(), 2);
+        let responses: Vec<Response> = store.responses(&from, &to).try_collect().await.unwrap();
+        assert_eq!(responses.len(), 2);
+
+        store.restore(january).await.unwrap();
+        assert!(store.archived_starts().await.unwrap().is_empty());
+        assert!(store.partition_starts().await.unwrap().contains(&january));
+        assert_eq!(store.request_count(&from, &to).await.unwrap(), 2);
+
+        // Pruning an archive reports the rows counted when it was archived.
+        store.archive(&at("2023-02-10T00:00:00Z")).await.unwrap();
+        let counts = store.prune(&at("2023-02-10T00:00:00Z")).await.unwrap();
+        assert_eq!((counts.requests, counts.responses), (1, 1));
+        assert!(store.archived_starts().await.unwrap().is_empty());
+        assert!(!tokio::fs::try_exists(store.counts_path_of(january))
+            .await
+            .unwrap());
+        assert_eq!(store.request_count(&from, &to).await.unwrap(), 1);
+
+        remove_dir_if_exists(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,433 @@
+//! Aggregates computed by scanning a store's records. These are the default
+//! implementations of the [`AnalyticsStore`] aggregates.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::IpAddr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use futures_util::TryStreamExt;
+use simple_id::chrono_id::Id as ChronoId;
+
+use super::AnalyticsStore;
+use crate::{
+    apdex::{ApdexConfig, ApdexPoint, ApdexScore, ApdexSeries},
+    attribution::{ReferrerCategory, SESSION_TIMEOUT},
+    slo::{Slo, SloReport, SloTally},
+    stats::{
+        quantile_of, session_lookback, CampaignReport, CohortMatrix, ResponseCounts, SourceReport,
+        Summary, TopEntry, TopField,
+    },
+    Request,
+};
+
+/// How far before a range requests are loaded from when joining them to the
+/// responses in that range.
+const REQUEST_LOOKBACK_HOURS: i64 = 1;
+
+/// How far before a range connections are loaded from when resolving the
+/// visitors of the requests in that range. Older ones are looked up singly.
+const CONNECTION_LOOKBACK_HOURS: i64 = 1;
+
+pub(crate) async fn request_count<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<i64> {
+    let mut count = 0;
+    let mut requests = store.requests(from, to);
+    while let Some(request) = requests.try_next().await? {
+        count += request.sample_weight;
+    }
+    Ok(count)
+}
+
+pub(crate) async fn response_counts<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<ResponseCounts> {
+    Ok(scan_responses(store, from, to).await?.0)
+}
+
+pub(crate) async fn latency_quantile<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    quantile: f64,
+) -> anyhow::Result<Option<Duration>> {
+    let (_, durations) = scan_responses(store, from, to).await?;
+    Ok(quantile_of(&durations, quantile))
+}
+
+pub(crate) async fn summary<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<Summary> {
+    let mut visitors = Visitors::load(store, from, to).await?;
+    let mut seen = BTreeSet::new();
+    let mut requests = 0;
+    let mut rows = store.requests(from, to);
+    while let Some(request) = rows.try_next().await? {
+        requests += request.sample_weight;
+        if let Some(visitor) = visitors.of(&request).await? {
+            seen.insert(visitor);
+        }
+    }
+
+    let (responses, durations) = scan_responses(store, from, to).await?;
+    let millis = |q| quantile_of(&durations, q).map(|d| d.as_secs_f64() * 1000.0);
+
+    Ok(Summary {
+        requests,
+        visitors: seen.len() as i64,
+        responses,
+        p50_ms: millis(0.5),
+        p90_ms: millis(0.9),
+        p99_ms: millis(0.99),
+    })
+}
+
+pub(crate) async fn top<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    field: TopField,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    limit: u32,
+) -> anyhow::Result<Vec<TopEntry>> {
+    let mut counts: HashMap<String, i64> = HashMap::new();
+    if field == TopField::Statuses {
+        let mut rows = store.responses(from, to);
+        while let Some(response) = rows.try_next().await? {
+            *counts.entry(response.status.to_string()).or_default() += response.sample_weight;
+        }
+    } else {
+        let mut rows = store.requests(from, to);
+        while let Some(r) = rows.try_next().await? {
+            let value = match field {
+                TopField::Paths => r.path,
+                TopField::Hosts => r.hostname,
+                TopField::Methods => r.method,
+                TopField::UserAgents => r.user_agent,
+                TopField::Referrers => r
+                    .attribution
+                    .referrer_domain
+                    .unwrap_or_else(|| "(direct)".to_owned()),
+                TopField::Statuses => unreachable!(),
+            };
+            *counts.entry(value).or_default() += r.sample_weight;
+        }
+    }
+
+    let mut entries: Vec<TopEntry> = counts
+        .into_iter()
+        .map(|(value, count)| TopEntry { value, count })
+        .collect();
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    entries.truncate(limit as usize);
+    Ok(entries)
+}
+
+pub(crate) async fn cohorts<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<CohortMatrix> {
+    let mut visitors = Visitors::load(store, from, to).await?;
+    let mut visits = BTreeSet::new();
+    let mut rows = store.requests(from, to);
+    while let Some(request) = rows.try_next().await? {
+        if let Some(visitor) = visitors.of(&request).await? {
+            visits.insert((visitor, week_of(&request.created_at)));
+        }
+    }
+
+    let mut first_visits: BTreeMap<&str, NaiveDate> = BTreeMap::new();
+    for (visitor, week) in &visits {
+        first_visits.entry(visitor.as_str()).or_insert(*week);
+    }
+
+    let mut counts: BTreeMap<(NaiveDate, i64), i64> = BTreeMap::new();
+    for (visitor, week) in &visits {
+        let cohort = first_visits[visitor.as_str()];
+        *counts
+            .entry((cohort, (*week - cohort).num_weeks()))
+            .or_default() += 1;
+    }
+
+    Ok(CohortMatrix::from_counts(
+        counts
+            .into_iter()
+            .map(|((cohort, offset), visitors)| (cohort, offset, visitors))
+            .collect(),
+    ))
+}
+
+/// Sessions grouped by where their landing request was referred from.
+pub(crate) async fn top_sources<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    limit: u32,
+) -> anyhow::Result<Vec<SourceReport>> {
+    let mut groups: HashMap<(ReferrerCategory, Option<String>), Group> = HashMap::new();
+    let lookback = session_lookback(from);
+    let mut visitors = Visitors::load(store, &lookback, to).await?;
+    let mut sessions = Sessions::default();
+    let mut rows = store.requests(&lookback, to);
+    while let Some(request) = rows.try_next().await? {
+        let visitor = visitors.of(&request).await?;
+        if !sessions.starts(visitor.as_deref(), &request.created_at)
+            || request.created_at < *from
+            || !request.attribution.is_landing()
+        {
+            continue;
+        }
+        let a = request.attribution;
+        groups
+            .entry((a.referrer_category, a.referrer_domain))
+            .or_default()
+            .add(request.sample_weight, visitor);
+    }
+
+    Ok(top_groups(groups, limit)
+        .into_iter()
+        .map(
+            |((referrer_category, referrer_domain), group)| SourceReport {
+                referrer_category,
+                referrer_domain,
+                visits: group.visits,
+                visitors: group.visitors.len() as i64,
+            },
+        )
+        .collect())
+}
+
+/// Sessions whose landing request carried `utm_*` parameters, grouped by
+/// campaign.
+pub(crate) async fn campaigns<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    limit: u32,
+) -> anyhow::Result<Vec<CampaignReport>> {
+    type Key = (Option<String>, Option<String>, Option<String>);
+    let mut groups: HashMap<Key, Group> = HashMap::new();
+    let lookback = session_lookback(from);
+    let mut visitors = Visitors::load(store, &lookback, to).await?;
+    let mut sessions = Sessions::default();
+    let mut rows = store.requests(&lookback, to);
+    while let Some(request) = rows.try_next().await? {
+        let visitor = visitors.of(&request).await?;
+        let a = &request.attribution;
+        if !sessions.starts(visitor.as_deref(), &request.created_at)
+            || request.created_at < *from
+            || (a.utm_source.is_none() && a.utm_campaign.is_none())
+        {
+            continue;
+        }
+        let a = request.attribution;
+        groups
+            .entry((a.utm_source, a.utm_medium, a.utm_campaign))
+            .or_default()
+            .add(request.sample_weight, visitor);
+    }
+
+    Ok(top_groups(groups, limit)
+        .into_iter()
+        .map(
+            |((utm_source, utm_medium, utm_campaign), group)| CampaignReport {
+                utm_source,
+                utm_medium,
+                utm_campaign,
+                visits: group.visits,
+                visitors: group.visitors.len() as i64,
+            },
+        )
+        .collect())
+}
+
+pub(crate) async fn slo_report<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    slo: &Slo,
+    now: &DateTime<Utc>,
+) -> anyhow::Result<SloReport> {
+    let mut tally = SloTally::new(slo, now);
+    let from = tally.from();
+    let paths = request_paths(store, &from, now).await?;
+
+    let mut rows = store.responses(&from, now);
+    while let Some(response) = rows.try_next().await? {
+        let in_slo = paths
+            .get(&response.req_id)
+            .map_or(false, |path| path.starts_with(&slo.path_prefix));
+        if in_slo {
+            tally.record(
+                &response.created_at,
+                response.status,
+                &response.duration,
+                response.sample_weight,
+            );
+        }
+    }
+
+    Ok(tally.report())
+}
+
+pub(crate) async fn apdex<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    config: &ApdexConfig,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<ApdexScore> {
+    let points = apdex_timeseries(store, config, from, to, *to - *from).await?;
+    Ok(points.first().map(|p| p.apdex).unwrap_or_default())
+}
+
+pub(crate) async fn apdex_timeseries<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    config: &ApdexConfig,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    step: chrono::Duration,
+) -> anyhow::Result<Vec<ApdexPoint>> {
+    let mut series = ApdexSeries::new(config, from, to, step);
+    let paths = request_paths(store, from, to).await?;
+
+    let mut rows = store.responses(from, to);
+    while let Some(response) = rows.try_next().await? {
+        if let Some(path) = paths.get(&response.req_id) {
+            series.record(
+                &response.created_at,
+                path,
+                response.status,
+                &response.duration,
+                response.sample_weight,
+            );
+        }
+    }
+
+    Ok(series.points)
+}
+
+/// Resolves which visitor made a request, the same way the SQLite queries do,
+/// looking up each connection only once.
+struct Visitors<'a, S: ?Sized> {
+    store: &'a S,
+    ips: BTreeMap<ChronoId, Option<IpAddr>>,
+}
+
+impl<'a, S: AnalyticsStore + ?Sized> Visitors<'a, S> {
+    /// Loads the connections that requests in `[from, to)` are likely to
+    /// have been made on in one scan.
+    async fn load(store: &'a S, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<Self> {
+        let from = *from - chrono::Duration::hours(CONNECTION_LOOKBACK_HOURS);
+        let ips = store
+            .connections(&from, to)
+            .map_ok(|c| (c.id, Some(c.remote_addr.ip())))
+            .try_collect()
+            .await?;
+        Ok(Self { store, ips })
+    }
+
+    async fn of(&mut self, request: &Request) -> anyhow::Result<Option<String>> {
+        let Some(conn_id) = request.conn_id else {
+            return Ok(None);
+        };
+        let ip = match self.ips.get(&conn_id) {
+            Some(ip) => *ip,
+            None => {
+                let connection = self.store.connection(&conn_id).await?;
+                let ip = connection.map(|c| c.remote_addr.ip());
+                self.ips.insert(conn_id, ip);
+                ip
+            }
+        };
+        Ok(ip.map(|ip| format!("{ip} {}", request.user_agent)))
+    }
+}
+
+/// When each visitor was last seen, to split their requests into sessions.
+#[derive(Default)]
+struct Sessions {
+    last_seen: HashMap<String, DateTime<Utc>>,
+}
+
+impl Sessions {
+    /// Whether a request at `at`, which must not be before any seen so far,
+    /// starts a session for `visitor`.
+    fn starts(&mut self, visitor: Option<&str>, at: &DateTime<Utc>) -> bool {
+        let Some(visitor) = visitor else {
+            return true;
+        };
+        let timeout = chrono::Duration::from_std(SESSION_TIMEOUT).unwrap();
+        match self.last_seen.insert(visitor.to_owned(), *at) {
+            Some(previous) => *at - previous > timeout,
+            None => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    visits: i64,
+    visitors: BTreeSet<String>,
+}
+
+impl Group {
+    fn add(&mut self, weight: i64, visitor: Option<String>) {
+        self.visits += weight;
+        self.visitors.extend(visitor);
+    }
+}
+
+fn top_groups<K>(groups: HashMap<K, Group>, limit: u32) -> Vec<(K, Group)> {
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by(|(_, a), (_, b)| b.visits.cmp(&a.visits));
+    groups.truncate(limit as usize);
+    groups
+}
+
+/// The Monday starting the week `at` falls in.
+fn week_of(at: &DateTime<Utc>) -> NaiveDate {
+    let date = at.date_naive();
+    date - chrono::Duration::days(date.weekday().num_days_from_monday().into())
+}
+
+/// Response counts along with every duration and its sample weight, sorted.
+async fn scan_responses<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<(ResponseCounts, Vec<(Duration, i64)>)> {
+    let mut counts = ResponseCounts::default();
+    let mut durations = Vec::new();
+    let mut rows = store.responses(from, to);
+    while let Some(response) = rows.try_next().await? {
+        let weight = response.sample_weight;
+        counts.total += weight;
+        counts.client_errors += (400..500).contains(&response.status) as i64 * weight;
+        counts.server_errors += (response.status >= 500) as i64 * weight;
+        durations.push((response.duration, weight));
+    }
+
+    durations.sort_unstable();
+    Ok((counts, durations))
+}
+
+/// Paths of the requests that responses in `[from, to)` may belong to.
+async fn request_paths<S: AnalyticsStore + ?Sized>(
+    store: &S,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> anyhow::Result<BTreeMap<ChronoId, String>> {
+    let from = *from - chrono::Duration::hours(REQUEST_LOOKBACK_HOURS);
+    store
+        .requests(&from, to)
+        .map_ok(|r| (r.id, r.path))
+        .try_collect()
+        .await
+}
@@ -0,0 +1,221 @@
+use std::{path::Path, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use simple_id::chrono_id::Id as ChronoId;
+use tokio::io::AsyncWrite;
+
+use super::AnalyticsStore;
+use crate::{
+    alerts::Alert,
+    apdex::{ApdexConfig, ApdexPoint, ApdexScore},
+    export::{ExportFormat, ExportTable},
+    slo::{Slo, SloReport},
+    stats::{
+        CampaignReport, CohortMatrix, ResponseCounts, SourceReport, Summary, TopEntry, TopField,
+    },
+    Connection, ConnectionTable, Db, ExchangeTable, PoolStats, PruneCounts, Request, RequestTable,
+    Response, ResponseTable,
+};
+
+#[async_trait]
+impl AnalyticsStore for Db {
+    async fn insert_connection(&self, connection: &Connection) -> anyhow::Result<()> {
+        Ok(ConnectionTable::insert_record(&self.writer, connection).await?)
+    }
+
+    async fn insert_request(&self, request: &Request) -> anyhow::Result<()> {
+        Ok(RequestTable::insert_record(&self.writer, request).await?)
+    }
+
+    async fn insert_response(&self, response: &Response) -> anyhow::Result<()> {
+        Ok(ResponseTable::insert_record(&self.writer, response).await?)
+    }
+
+    async fn insert_exchange(
+        &self,
+        request: &Request,
+        response: Option<&Response>,
+    ) -> anyhow::Result<()> {
+        Ok(ExchangeTable::insert_record(&self.writer, request, response).await?)
+    }
+
+    async fn connection(&self, id: &ChronoId) -> anyhow::Result<Option<Connection>> {
+        Ok(self.connection_table().get(id).await?)
+    }
+
+    fn connections<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Connection>> {
+        ConnectionTable::stream_records(&self.reader, from, to)
+            .err_into()
+            .boxed()
+    }
+
+    fn requests<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Request>> {
+        RequestTable::stream_records(&self.reader, from, to)
+            .err_into()
+            .boxed()
+    }
+
+    fn responses<'a>(
+        &'a self,
+        from: &'a DateTime<Utc>,
+        to: &'a DateTime<Utc>,
+    ) -> BoxStream<'a, anyhow::Result<Response>> {
+        ResponseTable::stream_records(&self.reader, from, to)
+            .err_into()
+            .boxed()
+    }
+
+    async fn recent_requests(&self, limit: u32) -> anyhow::Result<Vec<Request>> {
+        Ok(self.request_table().recent(limit).await?)
+    }
+
+    async fn requests_since(
+        &self,
+        after: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<Request>> {
+        Ok(self.request_table().since(after, limit).await?)
+    }
+
+    async fn firing_alert(&self, rule: &str) -> anyhow::Result<Option<Alert>> {
+        Ok(self.alert_table().firing(rule).await?)
+    }
+
+    async fn alerts(&self, limit: u32) -> anyhow::Result<Vec<Alert>> {
+        Ok(self.alert_table().list(limit).await?)
+    }
+
+    async fn fire_alert(&self, rule: &str, value: f64, threshold: f64) -> anyhow::Result<Alert> {
+        Ok(self.alert_table().fire(rule, value, threshold).await?)
+    }
+
+    async fn resolve_alert(&self, alert: Alert, value: Option<f64>) -> anyhow::Result<Alert> {
+        Ok(self.alert_table().resolve(alert, value).await?)
+    }
+
+    async fn prune(&self, before: &DateTime<Utc>) -> anyhow::Result<PruneCounts> {
+        Ok(Db::prune(self, before).await?)
+    }
+
+    async fn vacuum(&self) -> anyhow::Result<()> {
+        Ok(Db::vacuum(self).await?)
+    }
+
+    async fn pool_stats(&self) -> Vec<PoolStats> {
+        Db::pool_stats(self)
+    }
+
+    async fn backup_to(&self, path: &Path) -> anyhow::Result<()> {
+        Db::backup_to(self, path).await
+    }
+
+    async fn request_count(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<i64> {
+        Ok(self.stats().request_count(from, to).await?)
+    }
+
+    async fn response_counts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<ResponseCounts> {
+        Ok(self.stats().response_counts(from, to).await?)
+    }
+
+    async fn latency_quantile(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        quantile: f64,
+    ) -> anyhow::Result<Option<Duration>> {
+        Ok(self.stats().latency_quantile(from, to, quantile).await?)
+    }
+
+    async fn summary(&self, from: &DateTime<Utc>, to: &DateTime<Utc>) -> anyhow::Result<Summary> {
+        Ok(self.stats().summary(from, to).await?)
+    }
+
+    async fn top(
+        &self,
+        field: TopField,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<TopEntry>> {
+        Ok(self.stats().top(field, from, to, limit).await?)
+    }
+
+    async fn cohorts(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<CohortMatrix> {
+        Ok(self.stats().cohorts(from, to).await?)
+    }
+
+    async fn top_sources(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SourceReport>> {
+        Ok(self.stats().top_sources(from, to, limit).await?)
+    }
+
+    async fn campaigns(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<CampaignReport>> {
+        Ok(self.stats().campaigns(from, to, limit).await?)
+    }
+
+    async fn slo_report(&self, slo: &Slo, now: &DateTime<Utc>) -> anyhow::Result<SloReport> {
+        Ok(self.stats().slo_report(slo, now).await?)
+    }
+
+    async fn apdex(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<ApdexScore> {
+        Ok(self.stats().apdex(config, from, to).await?)
+    }
+
+    async fn apdex_timeseries(
+        &self,
+        config: &ApdexConfig,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        step: chrono::Duration,
+    ) -> anyhow::Result<Vec<ApdexPoint>> {
+        Ok(self
+            .stats()
+            .apdex_timeseries(config, from, to, step)
+            .await?)
+    }
+
+    async fn export(
+        &self,
+        table: ExportTable,
+        format: ExportFormat,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> anyhow::Result<u64> {
+        self.exporter()
+            .export(table, format, from, to, writer)
+            .await
+    }
+}
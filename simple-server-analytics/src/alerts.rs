@@ -0,0 +1,418 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use salvo::async_trait;
+use serde::{Deserialize, Serialize};
+use simple_server_analytics_db::{
+    alerts::{Alert, AlertState},
+    store::AnalyticsStore,
+};
+use tokio::{process::Command, task::JoinHandle};
+use tracing::*;
+
+use crate::SimpleAnalytics;
+
+/// How long a webhook may take to answer before the notification is given up
+/// on, as sinks are notified one after another.
+#[cfg(feature = "webhook")]
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    /// How far back each evaluation looks, in seconds.
+    pub window_secs: u64,
+    #[serde(flatten)]
+    pub condition: AlertCondition,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// Share of responses with a 5xx status, in percent.
+    ErrorRate { above_percent: f64 },
+    /// Response latency at the given quantile (e.g. `0.99`), in milliseconds.
+    Latency { quantile: f64, above_ms: f64 },
+    /// Drop in request count versus the same window one week earlier, in percent.
+    TrafficDrop { above_percent: f64 },
+}
+
+impl AlertCondition {
+    pub fn threshold(&self) -> f64 {
+        match *self {
+            Self::ErrorRate { above_percent } => above_percent,
+            Self::Latency { above_ms, .. } => above_ms,
+            Self::TrafficDrop { above_percent } => above_percent,
+        }
+    }
+
+    /// Measures the value compared against the threshold, or `None` if there
+    /// are no responses to measure errors or latency of.
+    pub async fn measure(
+        &self,
+        store: &dyn AnalyticsStore,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> anyhow::Result<Option<f64>> {
+        Ok(match *self {
+            Self::ErrorRate { .. } => {
+                let counts = store.response_counts(from, to).await?;
+                (counts.total > 0)
+                    .then(|| counts.server_errors as f64 * 100.0 / counts.total as f64)
+            }
+            Self::Latency { quantile, .. } => store
+                .latency_quantile(from, to, quantile)
+                .await?
+                .map(|d| d.as_secs_f64() * 1000.0),
+            Self::TrafficDrop { .. } => {
+                let week = chrono::Duration::weeks(1);
+                let current = store.request_count(from, to).await?;
+                let previous = store.request_count(&(*from - week), &(*to - week)).await?;
+                // No traffic a week earlier means there is nothing to drop.
+                Some(if previous > 0 {
+                    (previous - current) as f64 * 100.0 / previous as f64
+                } else {
+                    0.0
+                })
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertNotification {
+    #[serde(flatten)]
+    pub alert: Alert,
+    pub message: String,
+}
+
+impl AlertNotification {
+    fn new(alert: Alert, rule: &AlertRule) -> Self {
+        let message = match (alert.state, alert.value) {
+            (AlertState::Firing, Some(value)) => format!(
+                "[FIRING] {}: {value:.2} exceeds threshold {:.2}",
+                rule.name, alert.threshold
+            ),
+            (AlertState::Resolved, Some(value)) => format!(
+                "[RESOLVED] {}: {value:.2} is back under threshold {:.2}",
+                rule.name, alert.threshold
+            ),
+            (_, None) => format!(
+                "[RESOLVED] {}: nothing to measure in the last {}s",
+                rule.name, rule.window_secs
+            ),
+        };
+        Self { alert, message }
+    }
+}
+
+#[async_trait]
+pub trait AlertSink: Debug + Send + Sync {
+    async fn notify(&self, notification: &AlertNotification) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogSink;
+
+#[async_trait]
+impl AlertSink for LogSink {
+    async fn notify(&self, notification: &AlertNotification) -> anyhow::Result<()> {
+        match notification.alert.state {
+            AlertState::Firing => warn!("{}", notification.message),
+            AlertState::Resolved => info!("{}", notification.message),
+        }
+        Ok(())
+    }
+}
+
+/// POSTs the notification as JSON. Needs the `webhook` feature, which is on by
+/// default.
+#[cfg(feature = "webhook")]
+#[derive(Debug, Clone)]
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "webhook")]
+impl WebhookSink {
+    pub fn new(url: impl Into<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: url.into(),
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+        })
+    }
+}
+
+#[cfg(feature = "webhook")]
+#[async_trait]
+impl AlertSink for WebhookSink {
+    async fn notify(&self, notification: &AlertNotification) -> anyhow::Result<()> {
+        self.client
+            .post(&self.url)
+            .json(notification)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Runs a command with the notification passed through `SSA_ALERT_*`
+/// environment variables. `SSA_ALERT_VALUE` is empty if the alert resolved
+/// with nothing to measure.
+#[derive(Debug, Clone)]
+pub struct CommandSink {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandSink {
+    pub fn new(program: impl Into<String>, args: Vec<String>) -> Self {
+        Self {
+            program: program.into(),
+            args,
+        }
+    }
+}
+
+#[async_trait]
+impl AlertSink for CommandSink {
+    async fn notify(&self, notification: &AlertNotification) -> anyhow::Result<()> {
+        let alert = &notification.alert;
+        let status = Command::new(&self.program)
+            .args(&self.args)
+            .env("SSA_ALERT_RULE", &alert.rule)
+            .env(
+                "SSA_ALERT_STATE",
+                serde_json::to_string(&alert.state)?.trim_matches('"'),
+            )
+            .env(
+                "SSA_ALERT_VALUE",
+                alert.value.map(|v| v.to_string()).unwrap_or_default(),
+            )
+            .env("SSA_ALERT_THRESHOLD", alert.threshold.to_string())
+            .env("SSA_ALERT_MESSAGE", &notification.message)
+            .status()
+            .await?;
+
+        anyhow::ensure!(status.success(), "{} exited with {status}", self.program);
+        Ok(())
+    }
+}
+
+/// Periodically evaluates alert rules against the stored data, notifying every
+/// sink when a rule starts or stops firing.
+#[derive(Debug)]
+pub struct AlertEngine {
+    sa: SimpleAnalytics,
+    interval: Duration,
+    rules: Vec<AlertRule>,
+    sinks: Vec<Arc<dyn AlertSink>>,
+}
+
+impl AlertEngine {
+    pub fn new(sa: &SimpleAnalytics, interval: Duration) -> Self {
+        Self {
+            sa: sa.clone(),
+            interval,
+            rules: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn rule(mut self, rule: AlertRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn sink(mut self, sink: impl AlertSink + 'static) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                self.evaluate().await;
+            }
+        })
+    }
+
+    pub async fn evaluate(&self) {
+        for rule in &self.rules {
+            if let Err(e) = self.evaluate_rule(rule).await {
+                error!("Failed to evaluate alert rule {}: {e:?}", rule.name);
+            }
+        }
+    }
+
+    async fn evaluate_rule(&self, rule: &AlertRule) -> anyhow::Result<()> {
+        let to = Utc::now();
+        let from = to - chrono::Duration::seconds(rule.window_secs as i64);
+
+        let value = rule.condition.measure(self.sa.store(), &from, &to).await?;
+        let threshold = rule.condition.threshold();
+        let store = self.sa.store();
+
+        // With nothing to measure, a firing alert resolves rather than
+        // waiting for traffic that may never come back.
+        let alert = match (store.firing_alert(&rule.name).await?, value) {
+            (None, Some(value)) if value > threshold => {
+                store.fire_alert(&rule.name, value, threshold).await?
+            }
+            (Some(alert), None) => store.resolve_alert(alert, None).await?,
+            (Some(alert), Some(value)) if value <= threshold => {
+                store.resolve_alert(alert, Some(value)).await?
+            }
+            _ => return Ok(()),
+        };
+
+        let notification = AlertNotification::new(alert, rule);
+        for sink in &self.sinks {
+            if let Err(e) = sink.notify(&notification).await {
+                error!("Failed to send alert {} to {sink:?}: {e:?}", rule.name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use simple_server_analytics_db::{attribution::Attribution, Request, Response};
+
+    use super::*;
+
+    /// Keeps every notification it is sent.
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<AlertNotification>>>);
+
+    #[async_trait]
+    impl AlertSink for Recorder {
+        async fn notify(&self, notification: &AlertNotification) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<AlertNotification> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    async fn respond(sa: &SimpleAnalytics, status: u16, count: usize) {
+        for _ in 0..count {
+            let request =
+                Request::new(None, "GET", "/", "example.com", "", &Attribution::default());
+            let response = Response::new(None, &request.id, &Duration::from_millis(5), status);
+            sa.store()
+                .insert_exchange(&request, Some(&response))
+                .await
+                .unwrap();
+        }
+    }
+
+    fn error_rate_rule() -> AlertRule {
+        AlertRule {
+            name: "errors".to_owned(),
+            window_secs: 60,
+            condition: AlertCondition::ErrorRate {
+                above_percent: 10.0,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn fires_once_then_resolves() {
+        let sa = SimpleAnalytics::in_memory(1000);
+        let recorder = Recorder::default();
+        let engine = AlertEngine::new(&sa, Duration::from_secs(60))
+            .rule(error_rate_rule())
+            .sink(recorder.clone());
+
+        respond(&sa, 200, 1).await;
+        engine.evaluate().await;
+        assert!(recorder.take().is_empty());
+
+        respond(&sa, 500, 1).await;
+        engine.evaluate().await;
+        let fired = recorder.take();
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].alert.state, AlertState::Firing);
+        assert_eq!(fired[0].alert.value, Some(50.0));
+        assert_eq!(
+            fired[0].message,
+            "[FIRING] errors: 50.00 exceeds threshold 10.00"
+        );
+
+        // Still in breach, so no second alert.
+        engine.evaluate().await;
+        assert!(recorder.take().is_empty());
+
+        respond(&sa, 200, 18).await;
+        engine.evaluate().await;
+        let resolved = recorder.take();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].alert.state, AlertState::Resolved);
+        assert_eq!(resolved[0].alert.id, fired[0].alert.id);
+        assert_eq!(resolved[0].alert.value, Some(5.0));
+        assert!(sa.store().firing_alert("errors").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn resolves_without_a_value_when_nothing_is_measured() {
+        let sa = SimpleAnalytics::in_memory(1000);
+        let recorder = Recorder::default();
+        let engine = AlertEngine::new(&sa, Duration::from_secs(60))
+            .rule(error_rate_rule())
+            .sink(recorder.clone());
+        sa.store().fire_alert("errors", 50.0, 10.0).await.unwrap();
+
+        engine.evaluate().await;
+        let resolved = recorder.take();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].alert.state, AlertState::Resolved);
+        assert_eq!(resolved[0].alert.value, None);
+        assert_eq!(
+            resolved[0].message,
+            "[RESOLVED] errors: nothing to measure in the last 60s"
+        );
+    }
+
+    #[tokio::test]
+    async fn measures_traffic_drop_against_last_week() {
+        let sa = SimpleAnalytics::in_memory(1000);
+        respond(&sa, 200, 1).await;
+        let to = Utc::now();
+        let from = to - chrono::Duration::minutes(1);
+        let condition = AlertCondition::TrafficDrop {
+            above_percent: 50.0,
+        };
+
+        // No traffic a week ago means nothing has dropped.
+        assert_eq!(
+            condition.measure(sa.store(), &from, &to).await.unwrap(),
+            Some(0.0)
+        );
+
+        for _ in 0..4 {
+            let mut request =
+                Request::new(None, "GET", "/", "example.com", "", &Attribution::default());
+            request.created_at = from - chrono::Duration::weeks(1);
+            sa.store().insert_request(&request).await.unwrap();
+        }
+        assert_eq!(
+            condition.measure(sa.store(), &from, &to).await.unwrap(),
+            Some(75.0)
+        );
+    }
+}
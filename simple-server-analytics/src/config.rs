@@ -0,0 +1,711 @@
+//! Settings for [`SimpleAnalytics`], loadable from TOML and `SSA_*` environment
+//! variables.
+//!
+//! Environment variables override the file. Nested keys are separated by a
+//! double underscore, so `SSA_SAMPLING__RATE=0.1` sets `sampling.rate` and
+//! `SSA_STORAGE__PATH=/var/lib/ssa.db` sets `storage.path`. Settings that are
+//! strings take the value as is. Others read it as TOML where it parses as
+//! such, and as a string otherwise. Settings without a default, such as
+//! `storage.dir` for partitioned storage, fall back to the string when the
+//! TOML value has the wrong type.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use salvo::hyper::header::HeaderName;
+use serde::{Deserialize, Serialize};
+use simple_server_analytics_db::{
+    apdex::ApdexConfig,
+    slo::Slo,
+    store::{
+        memory::{MemoryStore, DEFAULT_CAPACITY},
+        partitioned::{PartitionPeriod, PartitionedDb},
+        AnalyticsStore,
+    },
+    Db,
+};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+
+use crate::{
+    retention::RetentionTask,
+    rules::{Rule, Rules},
+    sampling::{Sampler, SamplingConfig},
+    SimpleAnalytics,
+};
+
+const ENV_PREFIX: &str = "SSA_";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub storage: StorageConfig,
+    pub capture: CaptureConfig,
+    pub privacy: PrivacyMode,
+    pub sampling: SamplingConfig,
+    pub retention: RetentionConfig,
+    pub rules: Rules,
+    pub request_id: RequestIdConfig,
+    pub slos: Vec<Slo>,
+    pub apdex: ApdexConfig,
+    /// Push metrics to an OpenTelemetry collector.
+    #[cfg(feature = "otlp")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp: Option<crate::otlp::OtlpConfig>,
+}
+
+impl Config {
+    /// Reads `path`, if given, then applies any `SSA_*` environment variables.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        Self::load_with(path, std::env::vars())
+    }
+
+    fn load_with(
+        path: Option<&Path>,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> anyhow::Result<Self> {
+        let mut table = match path {
+            Some(path) => std::fs::read_to_string(path)?.parse::<toml::Table>()?,
+            None => toml::Table::new(),
+        };
+        let guesses = apply_env(&mut table, vars);
+        settle_guesses(&mut table, guesses);
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+/// Sets `table` keys from `SSA_*` variables. Whether a setting is a string
+/// is told from the defaults, so `SSA_REQUEST_ID__HEADER=123` isn't read as a
+/// number. Returns the settings absent from the defaults that were read as
+/// TOML, with their raw values, as those are only a guess.
+fn apply_env(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Vec<(Vec<String>, String)> {
+    let defaults = toml::Table::try_from(Config::default()).unwrap_or_default();
+    let mut guesses = Vec::new();
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+        let value = if is_string(&defaults, &path) {
+            toml::Value::String(raw)
+        } else {
+            match toml::from_str::<toml::Table>(&format!("v = {raw}"))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+            {
+                Some(value) => {
+                    if get_at(&defaults, &path).is_none() {
+                        guesses.push((path.clone(), raw));
+                    }
+                    value
+                }
+                None => toml::Value::String(raw),
+            }
+        };
+        insert_at(table, &path, value);
+    }
+    guesses
+}
+
+/// Reads each guessed setting as a string instead where the config rejects
+/// its TOML value but takes the string, so `SSA_OTLP__SERVICE_NAME=true` or
+/// `SSA_STORAGE__DIR=2024` aren't refused for not being strings. Each is
+/// tried alongside the rest of `table` but without the other guesses.
+fn settle_guesses(table: &mut toml::Table, guesses: Vec<(Vec<String>, String)>) {
+    let mut base = table.clone();
+    for (path, _) in &guesses {
+        remove_at(&mut base, path);
+    }
+    let accepts = |path: &[String], value: toml::Value| {
+        let mut table = base.clone();
+        insert_at(&mut table, path, value);
+        toml::Value::Table(table).try_into::<Config>().is_ok()
+    };
+
+    for (path, raw) in guesses {
+        let Some(value) = get_at(table, &path).cloned() else {
+            continue;
+        };
+        if !accepts(&path, value) && accepts(&path, toml::Value::String(raw.clone())) {
+            insert_at(table, &path, toml::Value::String(raw));
+        }
+    }
+}
+
+fn insert_at(table: &mut toml::Table, path: &[String], value: toml::Value) {
+    let (last, parents) = path.split_last().unwrap();
+    let mut target = table;
+    for parent in parents {
+        let entry = target
+            .entry(parent.clone())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+        if !entry.is_table() {
+            *entry = toml::Value::Table(Default::default());
+        }
+        target = entry.as_table_mut().unwrap();
+    }
+    target.insert(last.clone(), value);
+}
+
+fn remove_at(table: &mut toml::Table, path: &[String]) {
+    let (last, parents) = path.split_last().unwrap();
+    let mut target = table;
+    for parent in parents {
+        match target.get_mut(parent).and_then(toml::Value::as_table_mut) {
+            Some(t) => target = t,
+            None => return,
+        }
+    }
+    target.remove(last);
+}
+
+fn get_at<'a>(table: &'a toml::Table, path: &[String]) -> Option<&'a toml::Value> {
+    let (last, parents) = path.split_last().unwrap();
+    let mut table = table;
+    for parent in parents {
+        table = table.get(parent)?.as_table()?;
+    }
+    table.get(last)
+}
+
+/// Whether the key at `path` holds a string in `table`.
+fn is_string(table: &toml::Table, path: &[String]) -> bool {
+    get_at(table, path).is_some_and(toml::Value::is_str)
+}
+
+/// Where to record to, chosen by the `backend` key. SQLite when there is none,
+/// so a `[storage]` table with just a `path` works.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "Self", tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Sqlite(SqliteConfig),
+    /// One SQLite file per month or week in `dir`.
+    Partitioned {
+        dir: PathBuf,
+        #[serde(default)]
+        period: PartitionPeriod,
+    },
+    /// Nothing is written to disk.
+    Memory {
+        #[serde(default = "default_capacity")]
+        capacity: usize,
+    },
+    #[cfg(feature = "postgres")]
+    Postgres {
+        url: String,
+    },
+}
+
+impl Serialize for StorageConfig {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Self::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StorageConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = toml::Value::deserialize(deserializer)?;
+        if let Some(table) = value.as_table_mut() {
+            table.entry("backend").or_insert_with(|| "sqlite".into());
+        }
+        Self::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Sqlite(Default::default())
+    }
+}
+
+impl From<PathBuf> for StorageConfig {
+    /// A SQLite database at `path` with the default settings.
+    fn from(path: PathBuf) -> Self {
+        Self::Sqlite(SqliteConfig {
+            path,
+            ..Default::default()
+        })
+    }
+}
+
+impl From<&Path> for StorageConfig {
+    fn from(path: &Path) -> Self {
+        path.to_owned().into()
+    }
+}
+
+impl From<&PathBuf> for StorageConfig {
+    fn from(path: &PathBuf) -> Self {
+        path.clone().into()
+    }
+}
+
+impl From<&str> for StorageConfig {
+    fn from(path: &str) -> Self {
+        PathBuf::from(path).into()
+    }
+}
+
+impl From<String> for StorageConfig {
+    fn from(path: String) -> Self {
+        PathBuf::from(path).into()
+    }
+}
+
+impl From<&String> for StorageConfig {
+    fn from(path: &String) -> Self {
+        PathBuf::from(path).into()
+    }
+}
+
+fn default_capacity() -> usize {
+    DEFAULT_CAPACITY
+}
+
+impl StorageConfig {
+    pub async fn open(&self) -> anyhow::Result<Arc<dyn AnalyticsStore>> {
+        Ok(match self {
+            Self::Sqlite(sqlite) => Arc::new(Db::with_options(sqlite.connect_options()).await?),
+            Self::Partitioned { dir, period } => Arc::new(PartitionedDb::new(dir, *period).await?),
+            Self::Memory { capacity } => Arc::new(MemoryStore::new(*capacity)),
+            #[cfg(feature = "postgres")]
+            Self::Postgres { url } => {
+                Arc::new(simple_server_analytics_db::store::postgres::PostgresDb::new(url).await?)
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SqliteConfig {
+    pub path: PathBuf,
+    /// Write-ahead logging lets reports run while requests are recorded.
+    pub wal: bool,
+    pub synchronous: Synchronous,
+    /// Page cache per connection, in KiB.
+    pub cache_size_kib: u32,
+    pub busy_timeout_ms: u64,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            path: "analytics.db".into(),
+            wal: true,
+            synchronous: Synchronous::Normal,
+            cache_size_kib: 10_000,
+            busy_timeout_ms: simple_server_analytics_db::BUSY_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+impl SqliteConfig {
+    pub fn connect_options(&self) -> SqliteConnectOptions {
+        Db::options(&self.path)
+            .journal_mode(if self.wal {
+                SqliteJournalMode::Wal
+            } else {
+                SqliteJournalMode::Delete
+            })
+            .synchronous(match self.synchronous {
+                Synchronous::Off => SqliteSynchronous::Off,
+                Synchronous::Normal => SqliteSynchronous::Normal,
+                Synchronous::Full => SqliteSynchronous::Full,
+            })
+            .pragma("cache_size", format!("-{}", self.cache_size_kib))
+            .busy_timeout(Duration::from_millis(self.busy_timeout_ms))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+}
+
+/// Which request fields are recorded. Anything turned off is stored empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    pub hostname: bool,
+    pub user_agent: bool,
+    /// The referring domain and its category.
+    pub referrer: bool,
+    /// `utm_*` campaign parameters.
+    pub campaign: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            hostname: true,
+            user_agent: true,
+            referrer: true,
+            campaign: true,
+        }
+    }
+}
+
+/// How much about visitors is kept. Unique visitors are told apart by address
+/// and user agent, so stricter modes count them more coarsely.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyMode {
+    #[default]
+    Off,
+    /// Truncates remote addresses to their /24 (IPv4) or /48 (IPv6) network.
+    Anonymize,
+    /// Records neither remote addresses nor user agents.
+    Strict,
+}
+
+impl PrivacyMode {
+    pub fn remote_addr(&self, addr: &SocketAddr) -> SocketAddr {
+        let ip = match (self, addr.ip()) {
+            (Self::Off, ip) => ip,
+            (Self::Anonymize, IpAddr::V4(ip)) => {
+                let [a, b, c, _] = ip.octets();
+                IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+            }
+            (Self::Anonymize, IpAddr::V6(ip)) => {
+                let [a, b, c, ..] = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+            }
+            (Self::Strict, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (Self::Strict, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        match self {
+            Self::Off => *addr,
+            _ => SocketAddr::new(ip, 0),
+        }
+    }
+
+    pub fn records_user_agent(&self) -> bool {
+        *self != Self::Strict
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Data older than this many days is pruned. Kept forever when unset.
+    pub days: Option<u32>,
+    /// How often to prune, in seconds.
+    pub interval_secs: u64,
+    /// Reclaim the space freed by each prune.
+    pub vacuum: bool,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            days: None,
+            interval_secs: 60 * 60,
+            vacuum: false,
+        }
+    }
+}
+
+/// How requests are given the id that links them to their analytics row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestIdConfig {
+    pub header: String,
+    /// Use the id in an incoming request header instead of generating one.
+    /// It is echoed back and recorded as the request's `external_id`, while
+    /// the row keeps a generated id. Only enable this behind a proxy that
+    /// sets the header.
+    pub trust_incoming: bool,
+    /// Send the id back in a response header.
+    pub echo: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".to_owned(),
+            trust_incoming: false,
+            echo: true,
+        }
+    }
+}
+
+impl RequestIdConfig {
+    pub fn header_name(&self) -> anyhow::Result<HeaderName> {
+        Ok(HeaderName::from_bytes(self.header.as_bytes())?)
+    }
+}
+
+/// Builds a [`SimpleAnalytics`] from a [`Config`], with setters for anything
+/// that is easier to set in code.
+#[derive(Debug, Default)]
+pub struct SimpleAnalyticsBuilder {
+    config: Config,
+    store: Option<Arc<dyn AnalyticsStore>>,
+}
+
+impl SimpleAnalyticsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            store: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn storage(mut self, storage: StorageConfig) -> Self {
+        self.config.storage = storage;
+        self
+    }
+
+    /// Records to `store` instead of opening one from the storage config.
+    pub fn store(mut self, store: Arc<dyn AnalyticsStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn capture(mut self, capture: CaptureConfig) -> Self {
+        self.config.capture = capture;
+        self
+    }
+
+    pub fn privacy(mut self, privacy: PrivacyMode) -> Self {
+        self.config.privacy = privacy;
+        self
+    }
+
+    pub fn sampling(mut self, sampling: SamplingConfig) -> Self {
+        self.config.sampling = sampling;
+        self
+    }
+
+    pub fn sampling_rate(mut self, rate: f64) -> Self {
+        self.config.sampling.rate = rate;
+        self
+    }
+
+    pub fn retention_days(mut self, days: u32) -> Self {
+        self.config.retention.days = Some(days);
+        self
+    }
+
+    pub fn rules(mut self, rules: Rules) -> Self {
+        self.config.rules = rules;
+        self
+    }
+
+    pub fn include(mut self, rule: Rule) -> Self {
+        self.config.rules.include.push(rule);
+        self
+    }
+
+    pub fn exclude(mut self, rule: Rule) -> Self {
+        self.config.rules.exclude.push(rule);
+        self
+    }
+
+    pub fn request_id(mut self, request_id: RequestIdConfig) -> Self {
+        self.config.request_id = request_id;
+        self
+    }
+
+    pub fn slos(mut self, slos: Vec<Slo>) -> Self {
+        self.config.slos = slos;
+        self
+    }
+
+    pub fn apdex(mut self, apdex: ApdexConfig) -> Self {
+        self.config.apdex = apdex;
+        self
+    }
+
+    #[cfg(feature = "otlp")]
+    pub fn otlp(mut self, otlp: crate::otlp::OtlpConfig) -> Self {
+        self.config.otlp = Some(otlp);
+        self
+    }
+
+    /// Opens the store and, if retention or OTLP export is configured, starts
+    /// pruning it or exporting in the background.
+    pub async fn build(self) -> anyhow::Result<SimpleAnalytics> {
+        let Config {
+            storage,
+            capture,
+            privacy,
+            sampling,
+            retention,
+            rules,
+            request_id,
+            slos,
+            apdex,
+            #[cfg(feature = "otlp")]
+            otlp,
+        } = self.config;
+        let sampler = Sampler::new(sampling)?;
+        let request_id_header = request_id.header_name()?;
+
+        let store = match self.store {
+            Some(store) => store,
+            None => storage.open().await?,
+        };
+
+        let mut sa = SimpleAnalytics::with_store(store)
+            .with_slos(slos)
+            .with_apdex(apdex);
+        sa.capture = Arc::new(capture);
+        sa.privacy = privacy;
+        sa.sampler = Arc::new(sampler);
+        sa.request_id = Arc::new(request_id);
+        sa.request_id_header = request_id_header;
+        sa.set_rules(rules);
+
+        if retention.days.is_some() {
+            RetentionTask::new(&sa, retention).spawn();
+        }
+
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = otlp {
+            let exporter = Arc::new(crate::otlp::OtlpExporter::new(otlp)?);
+            exporter.clone().spawn();
+            sa = sa.with_otlp(exporter);
+        }
+
+        Ok(sa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn env_sets_documented_examples() {
+        let config = Config::load_with(
+            None,
+            vars(&[
+                ("SSA_SAMPLING__RATE", "0.1"),
+                ("SSA_STORAGE__PATH", "/var/lib/ssa.db"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.sampling.rate, 0.1);
+        let StorageConfig::Sqlite(sqlite) = config.storage else {
+            panic!("expected SQLite storage, got {:?}", config.storage);
+        };
+        assert_eq!(sqlite.path, Path::new("/var/lib/ssa.db"));
+        assert!(sqlite.wal);
+    }
+
+    #[test]
+    fn env_keeps_string_settings_as_is() {
+        let config = Config::load_with(
+            None,
+            vars(&[
+                ("SSA_REQUEST_ID__HEADER", "123"),
+                ("SSA_REQUEST_ID__ECHO", "false"),
+                ("SSA_RETENTION__DAYS", "30"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.request_id.header, "123");
+        assert!(!config.request_id.echo);
+        assert_eq!(config.retention.days, Some(30));
+    }
+
+    #[test]
+    fn env_reads_settings_without_defaults_as_strings_when_needed() {
+        let config = Config::load_with(
+            None,
+            vars(&[
+                ("SSA_STORAGE__BACKEND", "partitioned"),
+                ("SSA_STORAGE__DIR", "2024"),
+                ("SSA_STORAGE__PERIOD", "week"),
+            ]),
+        )
+        .unwrap();
+        let StorageConfig::Partitioned { dir, period } = config.storage else {
+            panic!("expected partitioned storage, got {:?}", config.storage);
+        };
+        assert_eq!(dir, Path::new("2024"));
+        assert_eq!(period, PartitionPeriod::Week);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn env_reads_otlp_settings() {
+        let config = Config::load_with(
+            None,
+            vars(&[
+                ("SSA_OTLP__SERVICE_NAME", "true"),
+                ("SSA_OTLP__LOGS", "true"),
+                ("SSA_OTLP__INTERVAL_SECS", "15"),
+            ]),
+        )
+        .unwrap();
+        let otlp = config.otlp.unwrap();
+        assert_eq!(otlp.service_name, "true");
+        assert!(otlp.logs);
+        assert_eq!(otlp.interval_secs, 15);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let path = std::env::temp_dir().join(format!("ssa-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[storage]\npath = \"file.db\"\nwal = false\n\n[sampling]\nrate = 0.5\n",
+        )
+        .unwrap();
+        let config = Config::load_with(Some(&path), vars(&[("SSA_SAMPLING__RATE", "0.25")]));
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.sampling.rate, 0.25);
+        let StorageConfig::Sqlite(sqlite) = config.storage else {
+            panic!("expected SQLite storage, got {:?}", config.storage);
+        };
+        assert_eq!(sqlite.path, Path::new("file.db"));
+        assert!(!sqlite.wal);
+    }
+
+    #[test]
+    fn storage_backend_is_chosen_by_tag() {
+        let config = Config::from_toml("[storage]\nbackend = \"memory\"\ncapacity = 10").unwrap();
+        assert!(matches!(
+            config.storage,
+            StorageConfig::Memory { capacity: 10 }
+        ));
+
+        assert!(Config::from_toml("[storage]\nbackend = \"nowhere\"").is_err());
+    }
+}
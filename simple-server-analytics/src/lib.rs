@@ -1,37 +1,223 @@
-use std::{net::SocketAddr, path::Path, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use salvo::{http::uri::Scheme, hyper::Version};
+use config::{
+    CaptureConfig, Config, PrivacyMode, RequestIdConfig, SimpleAnalyticsBuilder, StorageConfig,
+};
+use live::{LiveEvent, LiveFilter, LiveSubscription};
+use metrics::Metrics;
+use rules::{RequestInfo, Rules};
+use salvo::{
+    http::uri::Scheme,
+    hyper::{header::HeaderName, Version},
+};
+use salvo_ext::{request_id::RequestId, trace_context::TraceContext};
+use sampling::{Sample, Sampler};
 use simple_id::chrono_id::Id as ChronoId;
-use simple_server_analytics_db::Db;
+use simple_server_analytics_db::{
+    apdex::ApdexConfig,
+    attribution::Attribution,
+    slo::Slo,
+    store::{
+        memory::MemoryStore,
+        partitioned::{PartitionPeriod, PartitionedDb},
+        AnalyticsStore,
+    },
+    Connection, Request, Response,
+};
+use tokio::sync::broadcast;
 
+pub mod alerts;
+pub mod config;
+pub mod live;
+pub mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod retention;
+pub mod rules;
 pub mod salvo_ext;
+pub mod sampling;
+pub mod snapshots;
 
 #[derive(Debug, Clone)]
 pub struct SimpleAnalytics {
-    db: Db,
+    store: Arc<dyn AnalyticsStore>,
+    metrics: Arc<Metrics>,
+    live: broadcast::Sender<LiveEvent>,
+    slos: Arc<Vec<Slo>>,
+    apdex: Arc<ApdexConfig>,
+    capture: Arc<CaptureConfig>,
+    privacy: PrivacyMode,
+    sampler: Arc<Sampler>,
+    request_id: Arc<RequestIdConfig>,
+    /// `request_id.header`, parsed once.
+    request_id_header: HeaderName,
+    rules: Arc<RwLock<Arc<Rules>>>,
+    #[cfg(feature = "otlp")]
+    otlp: Option<Arc<otlp::OtlpExporter>>,
 }
 
 impl SimpleAnalytics {
-    pub async fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        Ok(Self {
-            db: Db::new(path).await?,
-        })
+    pub fn builder() -> SimpleAnalyticsBuilder {
+        SimpleAnalyticsBuilder::new()
     }
 
-    pub async fn report_new_connection(
+    /// Builds from `config`, as loaded by [`Config::load`].
+    pub async fn from_config(config: Config) -> anyhow::Result<Self> {
+        SimpleAnalyticsBuilder::from_config(config).build().await
+    }
+
+    /// Records to `storage`, e.g. `SimpleAnalytics::new("analytics.db")` for a
+    /// SQLite database at that path or `StorageConfig::Memory` for no file.
+    pub async fn new(storage: impl Into<StorageConfig>) -> anyhow::Result<Self> {
+        Ok(Self::with_store(storage.into().open().await?))
+    }
+
+    /// Keeps the last `capacity` records of each kind in memory instead of
+    /// writing them anywhere.
+    pub fn in_memory(capacity: usize) -> Self {
+        Self::with_store(Arc::new(MemoryStore::new(capacity)))
+    }
+
+    /// Records to one SQLite file per `period` in `dir`.
+    pub async fn partitioned<P: AsRef<Path>>(
+        dir: P,
+        period: PartitionPeriod,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::with_store(Arc::new(
+            PartitionedDb::new(dir, period).await?,
+        )))
+    }
+
+    pub fn with_store(store: Arc<dyn AnalyticsStore>) -> Self {
+        Self {
+            store,
+            metrics: Default::default(),
+            live: broadcast::channel(live::LIVE_CHANNEL_CAPACITY).0,
+            slos: Default::default(),
+            apdex: Default::default(),
+            capture: Default::default(),
+            privacy: Default::default(),
+            sampler: Default::default(),
+            request_id: Default::default(),
+            request_id_header: RequestIdConfig::default().header_name().unwrap(),
+            rules: Default::default(),
+            #[cfg(feature = "otlp")]
+            otlp: None,
+        }
+    }
+
+    /// Sets the SLOs reported on by the stats API.
+    pub fn with_slos(mut self, slos: Vec<Slo>) -> Self {
+        self.slos = Arc::new(slos);
+        self
+    }
+
+    /// Sets the Apdex thresholds, 500ms for every route by default.
+    pub fn with_apdex(mut self, apdex: ApdexConfig) -> Self {
+        self.apdex = Arc::new(apdex);
+        self
+    }
+
+    /// The include and exclude rules currently applied to requests.
+    pub fn rules(&self) -> Arc<Rules> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Replaces the rules for every clone of this handle, taking effect from
+    /// the next request.
+    pub fn set_rules(&self, rules: Rules) {
+        *self.rules.write().unwrap() = Arc::new(rules);
+    }
+
+    /// Reloads the rules from a TOML file, keeping the current ones if it
+    /// cannot be read.
+    pub async fn reload_rules(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.set_rules(Rules::from_toml_file(path).await?);
+        Ok(())
+    }
+
+    pub fn store(&self) -> &dyn AnalyticsStore {
+        self.store.as_ref()
+    }
+
+    pub fn request_id_config(&self) -> &RequestIdConfig {
+        &self.request_id
+    }
+
+    /// The header request ids are read from and echoed in.
+    pub fn request_id_header(&self) -> &HeaderName {
+        &self.request_id_header
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Sends requests to `exporter` as they complete. Its task still has to
+    /// be spawned to push them anywhere.
+    #[cfg(feature = "otlp")]
+    pub fn with_otlp(mut self, exporter: Arc<otlp::OtlpExporter>) -> Self {
+        self.otlp = Some(exporter);
+        self
+    }
+
+    #[cfg(feature = "otlp")]
+    pub fn otlp(&self) -> Option<&otlp::OtlpExporter> {
+        self.otlp.as_deref()
+    }
+
+    pub fn subscribe_live(&self, filter: LiveFilter) -> LiveSubscription {
+        LiveSubscription::new(self.live.subscribe(), filter)
+    }
+
+    fn publish_live(&self, event: impl FnOnce() -> LiveEvent) {
+        if self.live.receiver_count() > 0 {
+            let _ = self.live.send(event());
+        }
+    }
+
+    /// Starts tracking a connection. Nothing is written until
+    /// [`Self::record_connection`] is called for a request on it, so
+    /// connections only carrying excluded requests leave no row behind.
+    pub fn new_connection(
         &self,
         local_addr: &SocketAddr,
         remote_addr: &SocketAddr,
         http_scheme: &Scheme,
         http_version: &Version,
-    ) -> sqlx::Result<ChronoId> {
-        let conn_db = self
-            .db
-            .connection_table()
-            .insert(local_addr, remote_addr, http_scheme, http_version)
+    ) -> PendingConnection {
+        let remote_addr = self.privacy.remote_addr(remote_addr);
+        PendingConnection {
+            connection: Connection::new(local_addr, &remote_addr, http_scheme, http_version),
+            recorded: Default::default(),
+        }
+    }
+
+    /// Writes `conn` the first time it is called for it.
+    pub async fn record_connection(&self, conn: &PendingConnection) -> anyhow::Result<()> {
+        conn.recorded
+            .get_or_try_init(|| self.store.insert_connection(&conn.connection))
             .await?;
+        Ok(())
+    }
+
+    pub async fn report_new_connection(
+        &self,
+        local_addr: &SocketAddr,
+        remote_addr: &SocketAddr,
+        http_scheme: &Scheme,
+        http_version: &Version,
+    ) -> anyhow::Result<ChronoId> {
+        let remote_addr = self.privacy.remote_addr(remote_addr);
+        let conn = Connection::new(local_addr, &remote_addr, http_scheme, http_version);
+        self.store.insert_connection(&conn).await?;
 
-        Ok(conn_db.id)
+        Ok(conn.id)
     }
 
     pub async fn report_request(
@@ -41,14 +227,90 @@ impl SimpleAnalytics {
         path: &str,
         hostname: &str,
         user_agent: &str,
-    ) -> sqlx::Result<ChronoId> {
-        let req_db = self
-            .db
-            .request_table()
-            .insert(conn_id, method, path, hostname, user_agent)
-            .await?;
+        attribution: &Attribution,
+    ) -> anyhow::Result<ChronoId> {
+        let req = self.new_request(conn_id, method, path, hostname, user_agent, attribution);
+        self.store.insert_request(&req).await?;
+
+        let id = req.id;
+        self.publish_live(|| LiveEvent::Request(req));
 
-        Ok(req_db.id)
+        Ok(id)
+    }
+
+    /// Starts recording a request. Nothing is written or published to live
+    /// subscribers until the returned [`PendingRequest`] is completed with
+    /// its response, or dropped.
+    ///
+    /// Returns `None` if the request is excluded. Whether a request that was
+    /// not sampled is recorded anyway is decided once its response is known.
+    pub fn start_request(
+        &self,
+        request_id: &RequestId,
+        conn_id: Option<&ChronoId>,
+        method: &str,
+        path: &str,
+        hostname: &str,
+        user_agent: &str,
+        attribution: &Attribution,
+        trace: Option<&TraceContext>,
+    ) -> Option<PendingRequest> {
+        let info = RequestInfo {
+            method,
+            path,
+            hostname,
+            user_agent,
+        };
+        if !self.rules().allows(&info) {
+            return None;
+        }
+
+        let mut req = self.new_request(conn_id, method, path, hostname, user_agent, attribution);
+        req.id = *request_id.id();
+        req.external_id = request_id.external().map(str::to_owned);
+        if let Some(trace) = trace {
+            req.trace_id = Some(trace.trace_id.clone());
+            req.parent_span_id = Some(trace.parent_id.clone());
+        }
+
+        Some(PendingRequest {
+            sa: self.clone(),
+            request: Some(req),
+            sample: self.sampler.start(path),
+        })
+    }
+
+    /// Builds a request, leaving out whatever is not captured.
+    fn new_request(
+        &self,
+        conn_id: Option<&ChronoId>,
+        method: &str,
+        path: &str,
+        hostname: &str,
+        user_agent: &str,
+        attribution: &Attribution,
+    ) -> Request {
+        let capture = &self.capture;
+        let hostname = if capture.hostname { hostname } else { "" };
+        let user_agent = if capture.user_agent && self.privacy.records_user_agent() {
+            user_agent
+        } else {
+            ""
+        };
+        let mut attribution = attribution.clone();
+        if !capture.referrer {
+            attribution.referrer_domain = None;
+            attribution.referrer_category = Default::default();
+        }
+        if !capture.campaign {
+            attribution.utm_source = None;
+            attribution.utm_medium = None;
+            attribution.utm_campaign = None;
+            attribution.utm_term = None;
+            attribution.utm_content = None;
+        }
+
+        Request::new(conn_id, method, path, hostname, user_agent, &attribution)
     }
 
     pub async fn report_response(
@@ -57,13 +319,89 @@ impl SimpleAnalytics {
         req_id: &ChronoId,
         duration: &Duration,
         status: u16,
-    ) -> sqlx::Result<ChronoId> {
-        let res_db = self
-            .db
-            .response_table()
-            .insert(conn_id, req_id, duration, status)
-            .await?;
+    ) -> anyhow::Result<ChronoId> {
+        let res = Response::new(conn_id, req_id, duration, status);
+        self.store.insert_response(&res).await?;
+
+        let id = res.id;
+        self.publish_live(|| LiveEvent::Response(res));
+
+        Ok(id)
+    }
+}
+
+/// A connection that has been accepted but not necessarily recorded yet.
+#[derive(Debug)]
+pub struct PendingConnection {
+    connection: Connection,
+    recorded: tokio::sync::OnceCell<()>,
+}
+
+impl PendingConnection {
+    pub fn id(&self) -> ChronoId {
+        self.connection.id
+    }
+}
+
+/// A request that has started but not been recorded yet.
+///
+/// Completing it writes the request and response in one go, if sampling keeps
+/// it. If it is dropped instead, for example because the client went away and
+/// the handler was cancelled, the request is recorded on its own if it was
+/// sampled.
+#[derive(Debug)]
+pub struct PendingRequest {
+    sa: SimpleAnalytics,
+    request: Option<Request>,
+    sample: Sample,
+}
+
+impl PendingRequest {
+    pub fn id(&self) -> ChronoId {
+        self.request.as_ref().unwrap().id
+    }
+
+    /// Records the request and its response if sampling keeps them, and
+    /// only then publishes them to live subscribers.
+    pub async fn complete(mut self, duration: &Duration, status: u16) -> anyhow::Result<ChronoId> {
+        let mut req = self.request.take().unwrap();
+        let mut res = Response::new(req.conn_id.as_ref(), &req.id, duration, status);
+        let id = res.id;
+        if let Some(weight) = self.sa.sampler.finish(self.sample, status, duration) {
+            req.sample_weight = weight;
+            res.sample_weight = weight;
+            self.sa.store.insert_exchange(&req, Some(&res)).await?;
+
+            self.sa.publish_live(|| LiveEvent::Request(req));
+            self.sa.publish_live(|| LiveEvent::Response(res));
+        }
+
+        Ok(id)
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        let Some(mut req) = self.request.take() else {
+            return;
+        };
+        let Some(weight) = self.sample.weight() else {
+            return;
+        };
+        req.sample_weight = weight;
 
-        Ok(res_db.id)
+        // Dropped outside a runtime, e.g. while one is shutting down, there is
+        // nothing left to write with.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!("Dropped incomplete request {:?} outside a runtime", req.id);
+            return;
+        };
+        let sa = self.sa.clone();
+        runtime.spawn(async move {
+            match sa.store.insert_exchange(&req, None).await {
+                Ok(()) => sa.publish_live(|| LiveEvent::Request(req)),
+                Err(e) => tracing::error!("Failed to report incomplete request: {e:?}"),
+            }
+        });
     }
 }
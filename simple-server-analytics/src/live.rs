@@ -0,0 +1,139 @@
+use std::{collections::BTreeSet, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use simple_id::chrono_id::Id as ChronoId;
+use simple_server_analytics_db::{Request, Response};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::*;
+
+/// Number of events buffered per subscriber before it starts missing events.
+pub(crate) const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
+/// Most requests a subscriber remembers while waiting for their responses.
+const MAX_PENDING_REQUESTS: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LiveEvent {
+    Request(Request),
+    Response(Response),
+}
+
+impl LiveEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Request(_) => "request",
+            Self::Response(_) => "response",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LiveFilter {
+    /// Only requests whose path starts with this prefix.
+    pub path: Option<String>,
+    /// Only responses with this status, either exact (`404`) or a class (`5xx`).
+    pub status: Option<StatusFilter>,
+    /// Only requests for this host.
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatusFilter {
+    Exact(u16),
+    Class(u16),
+}
+
+impl StatusFilter {
+    pub fn matches(&self, status: u16) -> bool {
+        match *self {
+            Self::Exact(s) => status == s,
+            Self::Class(c) => status / 100 == c,
+        }
+    }
+}
+
+impl FromStr for StatusFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid status filter {s:?}, expected e.g. 404 or 5xx");
+        match s.to_ascii_lowercase().strip_suffix("xx") {
+            Some(class) => class.parse().map(Self::Class).map_err(|_| err()),
+            None => s.parse().map(Self::Exact).map_err(|_| err()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusFilter {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl LiveFilter {
+    fn matches_request(&self, req: &Request) -> bool {
+        self.path.as_ref().map_or(true, |p| req.path.starts_with(p))
+            && self.host.as_ref().map_or(true, |h| &req.hostname == h)
+    }
+}
+
+/// A filtered view of the live event stream. Responses only carry the id of
+/// their request, so requests that pass the path and host filters are tracked
+/// until their response arrives.
+pub struct LiveSubscription {
+    rx: broadcast::Receiver<LiveEvent>,
+    filter: LiveFilter,
+    pending: BTreeSet<ChronoId>,
+}
+
+impl LiveSubscription {
+    pub(crate) fn new(rx: broadcast::Receiver<LiveEvent>, filter: LiveFilter) -> Self {
+        Self {
+            rx,
+            filter,
+            pending: BTreeSet::new(),
+        }
+    }
+
+    /// Waits for the next event passing the filter, or `None` once the
+    /// analytics instance has been dropped.
+    pub async fn next(&mut self) -> Option<LiveEvent> {
+        loop {
+            let event = match self.rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Live subscriber lagged behind, skipped {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            match &event {
+                LiveEvent::Request(req) => {
+                    if !self.filter.matches_request(req) {
+                        continue;
+                    }
+                    self.pending.insert(req.id);
+                    if self.pending.len() > MAX_PENDING_REQUESTS {
+                        self.pending.pop_first();
+                    }
+                    // Status is unknown until the response, so only responses
+                    // are shown when filtering on it.
+                    if self.filter.status.is_none() {
+                        return Some(event);
+                    }
+                }
+                LiveEvent::Response(res) => {
+                    if !self.pending.remove(&res.req_id) {
+                        continue;
+                    }
+                    if self.filter.status.map_or(true, |s| s.matches(res.status)) {
+                        return Some(event);
+                    }
+                }
+            }
+        }
+    }
+}
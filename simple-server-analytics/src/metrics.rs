@@ -0,0 +1,318 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use simple_server_analytics_db::PoolStats;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Methods reported as-is. Any other is reported as `_OTHER`, so made-up
+/// methods can't add series.
+const KNOWN_METHODS: &[&str] = &[
+    "CONNECT", "DELETE", "GET", "HEAD", "OPTIONS", "PATCH", "POST", "PUT", "TRACE",
+];
+
+/// Most distinct routes given their own series. Requests to routes seen after
+/// that are counted under [`OTHER_ROUTE`], which keeps cardinality bounded even
+/// if routes end up taken from arbitrary paths.
+const MAX_ROUTES: usize = 1000;
+
+/// The route of requests that no router matched.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// The route of requests to any route past the first [`MAX_ROUTES`].
+pub const OTHER_ROUTE: &str = "other";
+
+/// In-memory counters kept alongside the database so they can be scraped
+/// without querying it.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<SeriesKey, Series>>,
+    routes: Mutex<HashSet<String>>,
+    open_connections: AtomicI64,
+    connections: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    method: &'static str,
+    route: String,
+    status_class: &'static str,
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// The label to report `route` under, being itself unless too many
+    /// distinct routes have been seen already.
+    pub fn route_label(&self, route: String) -> String {
+        let mut routes = self.routes.lock().unwrap();
+        if routes.contains(&route) {
+            return route;
+        }
+        if routes.len() >= MAX_ROUTES {
+            return OTHER_ROUTE.to_owned();
+        }
+        routes.insert(route.clone());
+        route
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, duration: &Duration) {
+        let key = SeriesKey {
+            method: known_method(method),
+            route: route.to_owned(),
+            status_class: status_class(status),
+        };
+        let secs = duration.as_secs_f64();
+
+        let mut requests = self.requests.lock().unwrap();
+        let series = requests.entry(key).or_default();
+        for (bucket, le) in series.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        series.count += 1;
+        series.sum += secs;
+    }
+
+    /// Counts the connection as open until the returned guard is dropped.
+    pub fn open_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Renders all metrics in the OpenMetrics text exposition format, along
+    /// with the store's connection pool usage.
+    pub fn render(&self, pools: &[PoolStats]) -> String {
+        let mut out = String::new();
+
+        let requests = self.requests.lock().unwrap();
+
+        out.push_str("# TYPE ssa_http_requests counter\n");
+        out.push_str("# HELP ssa_http_requests Completed HTTP requests.\n");
+        for (key, series) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "ssa_http_requests_total{{{}}} {}",
+                key.labels(),
+                series.count
+            );
+        }
+
+        out.push_str("# TYPE ssa_http_request_duration_seconds histogram\n");
+        out.push_str("# UNIT ssa_http_request_duration_seconds seconds\n");
+        out.push_str(
+            "# HELP ssa_http_request_duration_seconds Time taken to handle HTTP requests.\n",
+        );
+        for (key, series) in requests.iter() {
+            let labels = key.labels();
+            for (bucket, le) in series.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "ssa_http_request_duration_seconds_bucket{{{labels},le=\"{le:?}\"}} {bucket}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "ssa_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                series.count
+            );
+            let _ = writeln!(
+                out,
+                "ssa_http_request_duration_seconds_sum{{{labels}}} {}",
+                series.sum
+            );
+            let _ = writeln!(
+                out,
+                "ssa_http_request_duration_seconds_count{{{labels}}} {}",
+                series.count
+            );
+        }
+
+        drop(requests);
+
+        out.push_str("# TYPE ssa_open_connections gauge\n");
+        out.push_str("# HELP ssa_open_connections Currently open client connections.\n");
+        let _ = writeln!(
+            out,
+            "ssa_open_connections {}",
+            self.open_connections.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# TYPE ssa_connections counter\n");
+        out.push_str("# HELP ssa_connections Accepted client connections.\n");
+        let _ = writeln!(
+            out,
+            "ssa_connections_total {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# TYPE ssa_db_pool_connections gauge\n");
+        out.push_str("# HELP ssa_db_pool_connections Open database connections by pool.\n");
+        for p in pools {
+            let _ = writeln!(
+                out,
+                "ssa_db_pool_connections{{pool=\"{}\"}} {}",
+                p.pool, p.size
+            );
+        }
+
+        out.push_str("# TYPE ssa_db_pool_idle_connections gauge\n");
+        out.push_str("# HELP ssa_db_pool_idle_connections Idle database connections by pool.\n");
+        for p in pools {
+            let _ = writeln!(
+                out,
+                "ssa_db_pool_idle_connections{{pool=\"{}\"}} {}",
+                p.pool, p.idle
+            );
+        }
+
+        out.push_str("# TYPE ssa_db_pool_max_connections gauge\n");
+        out.push_str(
+            "# HELP ssa_db_pool_max_connections Most database connections each pool may open.\n",
+        );
+        for p in pools {
+            let _ = writeln!(
+                out,
+                "ssa_db_pool_max_connections{{pool=\"{}\"}} {}",
+                p.pool, p.max
+            );
+        }
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+impl SeriesKey {
+    fn labels(&self) -> String {
+        format!(
+            "method=\"{}\",route=\"{}\",status=\"{}\"",
+            self.method,
+            escape_label(&self.route),
+            self.status_class
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn known_method(method: &str) -> &'static str {
+    KNOWN_METHODS
+        .iter()
+        .find(|m| **m == method)
+        .copied()
+        .unwrap_or("_OTHER")
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "unknown",
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_routes() {
+        let metrics = Metrics::default();
+        for i in 0..MAX_ROUTES {
+            assert_eq!(metrics.route_label(format!("/{i}")), format!("/{i}"));
+        }
+        assert_eq!(metrics.route_label("/new".to_owned()), OTHER_ROUTE);
+        assert_eq!(metrics.route_label("/0".to_owned()), "/0");
+    }
+
+    #[test]
+    fn renders_openmetrics() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.observe_request("GET", "/items/{id}", 200, &Duration::from_millis(30));
+        metrics.observe_request("GET", "/items/{id}", 204, &Duration::from_secs(3));
+        metrics.observe_request("BREW", "unmatched", 418, &Duration::from_millis(1));
+        let _connection = metrics.open_connection();
+        drop(metrics.open_connection());
+
+        let out = metrics.render(&[PoolStats {
+            pool: "write",
+            size: 2,
+            idle: 1,
+            max: 4,
+        }]);
+        let lines: Vec<&str> = out.lines().collect();
+
+        let get = r#"method="GET",route="/items/{id}",status="2xx""#;
+        assert!(lines.contains(&format!("ssa_http_requests_total{{{get}}} 2").as_str()));
+        for (le, count) in [
+            ("0.025", 0),
+            ("0.05", 1),
+            ("1.0", 1),
+            ("5.0", 2),
+            ("10.0", 2),
+        ] {
+            let line =
+                format!("ssa_http_request_duration_seconds_bucket{{{get},le=\"{le}\"}} {count}");
+            assert!(lines.contains(&line.as_str()), "{line}");
+        }
+        assert!(lines.contains(
+            &format!("ssa_http_request_duration_seconds_bucket{{{get},le=\"+Inf\"}} 2").as_str()
+        ));
+        assert!(
+            lines.contains(&format!("ssa_http_request_duration_seconds_count{{{get}}} 2").as_str())
+        );
+
+        // Made-up methods share one series.
+        assert!(lines.contains(
+            &r#"ssa_http_requests_total{method="_OTHER",route="unmatched",status="4xx"} 1"#
+        ));
+        assert!(!out.contains("BREW"));
+
+        assert!(lines.contains(&"ssa_open_connections 1"));
+        assert!(lines.contains(&"ssa_connections_total 2"));
+        assert!(lines.contains(&r#"ssa_db_pool_connections{pool="write"} 2"#));
+        assert!(lines.contains(&r#"ssa_db_pool_idle_connections{pool="write"} 1"#));
+        assert!(lines.contains(&r#"ssa_db_pool_max_connections{pool="write"} 4"#));
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+}
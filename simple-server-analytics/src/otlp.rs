@@ -0,0 +1,625 @@
+//! Pushing HTTP server metrics, and optionally one log record per request, to
+//! an OpenTelemetry collector over OTLP/HTTP with JSON encoding.
+//!
+//! Metrics follow the HTTP semantic conventions: a cumulative
+//! `http.server.request.duration` histogram in seconds, with the
+//! `http.request.method`, `http.route`, `http.response.status_code`,
+//! `url.scheme`, `network.protocol.version` and `error.type` attributes.
+//! Requests excluded by the rules are left out, while sampling is not applied,
+//! so counts stay exact.
+//!
+//! ```toml
+//! [otlp]
+//! endpoint = "http://localhost:4318"
+//! interval_secs = 10
+//! logs = true
+//!
+//! [otlp.headers]
+//! authorization = "Bearer ..."
+//! ```
+//!
+//! To try it against a local collector that prints what it receives:
+//!
+//! ```sh
+//! cat > collector.yaml <<EOF
+//! receivers:
+//!   otlp:
+//!     protocols:
+//!       http:
+//!         endpoint: 0.0.0.0:4318
+//! exporters:
+//!   debug:
+//!     verbosity: detailed
+//! service:
+//!   pipelines:
+//!     metrics: { receivers: [otlp], exporters: [debug] }
+//!     logs: { receivers: [otlp], exporters: [debug] }
+//! EOF
+//! docker run --rm -p 4318:4318 -v $PWD/collector.yaml:/etc/otelcol/config.yaml \
+//!     otel/opentelemetry-collector
+//! ```
+//!
+//! Any server that answers POSTs to `/v1/metrics` and `/v1/logs` with a 2xx
+//! status stands in just as well, as the bodies are plain JSON.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use tracing::*;
+
+use crate::{
+    metrics::known_method,
+    salvo_ext::{request_id::RequestId, trace_context::TraceContext},
+};
+
+/// Upper bounds, in seconds, advised for `http.server.request.duration`.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+/// Most log records held between exports. Later ones are dropped.
+const MAX_PENDING_LOGS: usize = 10_000;
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`.
+const CUMULATIVE: u8 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    /// Base URL of the collector, to which `/v1/metrics` and `/v1/logs` are
+    /// appended.
+    pub endpoint: String,
+    /// Sent with every export, e.g. for authentication.
+    pub headers: BTreeMap<String, String>,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// The `service.name` resource attribute.
+    pub service_name: String,
+    /// Also send a log record for every request.
+    pub logs: bool,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:4318".to_owned(),
+            headers: BTreeMap::new(),
+            interval_secs: 60,
+            timeout_secs: 10,
+            service_name: env!("CARGO_PKG_NAME").to_owned(),
+            logs: false,
+        }
+    }
+}
+
+/// A handled request, as observed by
+/// [`SimpleAnalyticsHandler`](crate::salvo_ext::handler::SimpleAnalyticsHandler).
+#[derive(Debug, Clone, Copy)]
+pub struct ServerRequest<'a> {
+    pub method: &'a str,
+    /// The route template, or `None` if no route matched.
+    pub route: Option<&'a str>,
+    pub path: &'a str,
+    pub status: u16,
+    pub scheme: &'a str,
+    pub protocol_version: &'a str,
+    pub duration: Duration,
+    pub request_id: &'a RequestId,
+    pub trace: Option<&'a TraceContext>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    method: &'static str,
+    route: Option<String>,
+    status: u16,
+    scheme: String,
+    protocol_version: String,
+}
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+/// Aggregates requests in memory and periodically pushes them to a collector.
+#[derive(Debug)]
+pub struct OtlpExporter {
+    config: OtlpConfig,
+    client: reqwest::Client,
+    start_time: SystemTime,
+    series: Mutex<BTreeMap<SeriesKey, Histogram>>,
+    logs: Mutex<Vec<Value>>,
+    dropped_logs: AtomicU64,
+}
+
+impl OtlpExporter {
+    pub fn new(config: OtlpConfig) -> anyhow::Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(config.timeout_secs.max(1)))
+            .build()?;
+
+        Ok(Self {
+            config,
+            client,
+            start_time: SystemTime::now(),
+            series: Default::default(),
+            logs: Default::default(),
+            dropped_logs: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &OtlpConfig {
+        &self.config
+    }
+
+    pub fn observe(&self, req: &ServerRequest) {
+        let key = SeriesKey {
+            method: known_method(req.method),
+            route: req.route.map(str::to_owned),
+            status: req.status,
+            scheme: req.scheme.to_owned(),
+            protocol_version: req.protocol_version.to_owned(),
+        };
+        let secs = req.duration.as_secs_f64();
+
+        let mut series = self.series.lock().unwrap();
+        let histogram = series.entry(key).or_default();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(DURATION_BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.count += 1;
+        histogram.sum += secs;
+        histogram.min = histogram.min.min(secs);
+        histogram.max = histogram.max.max(secs);
+        drop(series);
+
+        if self.config.logs {
+            let mut logs = self.logs.lock().unwrap();
+            if logs.len() < MAX_PENDING_LOGS {
+                logs.push(log_record(req));
+            } else {
+                self.dropped_logs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn spawn(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.export().await {
+                    error!("Failed to export to {}: {e:?}", self.config.endpoint);
+                }
+            }
+        })
+    }
+
+    /// Pushes the metrics, and any log records held since the last export.
+    /// Log records that fail to send are not retried.
+    pub async fn export(&self) -> anyhow::Result<()> {
+        let logs = std::mem::take(&mut *self.logs.lock().unwrap());
+        let dropped = self.dropped_logs.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Dropped {dropped} log records waiting to be exported");
+        }
+
+        if let Some(metrics) = self.metrics_payload() {
+            self.post("v1/metrics", &metrics).await?;
+        }
+        if !logs.is_empty() {
+            self.post("v1/logs", &self.logs_payload(logs)).await?;
+        }
+        Ok(())
+    }
+
+    /// The `ExportMetricsServiceRequest` for everything observed so far, or
+    /// `None` before the first request.
+    pub fn metrics_payload(&self) -> Option<Value> {
+        let start = unix_nanos(self.start_time);
+        let now = unix_nanos(SystemTime::now());
+
+        let series = self.series.lock().unwrap();
+        if series.is_empty() {
+            return None;
+        }
+        let data_points: Vec<Value> = series
+            .iter()
+            .map(|(key, histogram)| {
+                let bucket_counts: Vec<String> =
+                    histogram.buckets.iter().map(u64::to_string).collect();
+                json!({
+                    "attributes": key.attributes(),
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "count": histogram.count.to_string(),
+                    "sum": histogram.sum,
+                    "min": histogram.min,
+                    "max": histogram.max,
+                    "bucketCounts": bucket_counts,
+                    "explicitBounds": DURATION_BUCKETS,
+                })
+            })
+            .collect();
+        drop(series);
+
+        Some(json!({
+            "resourceMetrics": [{
+                "resource": self.resource(),
+                "scopeMetrics": [{
+                    "scope": scope(),
+                    "metrics": [{
+                        "name": "http.server.request.duration",
+                        "description": "Duration of HTTP server requests.",
+                        "unit": "s",
+                        "histogram": {
+                            "aggregationTemporality": CUMULATIVE,
+                            "dataPoints": data_points,
+                        },
+                    }],
+                }],
+            }],
+        }))
+    }
+
+    fn logs_payload(&self, records: Vec<Value>) -> Value {
+        json!({
+            "resourceLogs": [{
+                "resource": self.resource(),
+                "scopeLogs": [{
+                    "scope": scope(),
+                    "logRecords": records,
+                }],
+            }],
+        })
+    }
+
+    fn resource(&self) -> Value {
+        json!({ "attributes": [string_attribute("service.name", &self.config.service_name)] })
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> anyhow::Result<()> {
+        let url = format!("{}/{path}", self.config.endpoint.trim_end_matches('/'));
+        self.client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl SeriesKey {
+    fn attributes(&self) -> Vec<Value> {
+        let mut attributes = vec![
+            string_attribute("http.request.method", self.method),
+            int_attribute("http.response.status_code", self.status.into()),
+            string_attribute("url.scheme", &self.scheme),
+            string_attribute("network.protocol.version", &self.protocol_version),
+        ];
+        if let Some(route) = &self.route {
+            attributes.push(string_attribute("http.route", route));
+        }
+        if self.status >= 500 {
+            attributes.push(string_attribute("error.type", &self.status.to_string()));
+        }
+        attributes
+    }
+}
+
+/// A log record for `req`, timed at when it started and linked to the trace
+/// it arrived with. Its span is the caller's, as no span is started here.
+fn log_record(req: &ServerRequest) -> Value {
+    let now = SystemTime::now();
+    let started = now.checked_sub(req.duration).unwrap_or(now);
+    let (severity_number, severity_text) = match req.status {
+        500.. => (17, "ERROR"),
+        400..=499 => (13, "WARN"),
+        _ => (9, "INFO"),
+    };
+
+    let mut attributes = vec![
+        string_attribute("http.request.method", known_method(req.method)),
+        string_attribute("url.path", req.path),
+        int_attribute("http.response.status_code", req.status.into()),
+        double_attribute("http.server.request.duration", req.duration.as_secs_f64()),
+        string_attribute("url.scheme", req.scheme),
+        string_attribute("network.protocol.version", req.protocol_version),
+        string_attribute("http.request.id", req.request_id.as_str()),
+    ];
+    if let Some(route) = req.route {
+        attributes.push(string_attribute("http.route", route));
+    }
+
+    let mut record = json!({
+        "timeUnixNano": unix_nanos(started),
+        "observedTimeUnixNano": unix_nanos(now),
+        "severityNumber": severity_number,
+        "severityText": severity_text,
+        "body": { "stringValue": format!("{} {} {}", req.method, req.path, req.status) },
+        "attributes": attributes,
+    });
+    if let Some(trace) = req.trace {
+        record["traceId"] = trace.trace_id.clone().into();
+        record["spanId"] = trace.parent_id.clone().into();
+        record["flags"] = u32::from(trace.flags).into();
+    }
+    record
+}
+
+fn scope() -> Value {
+    json!({ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") })
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn double_attribute(key: &str, value: f64) -> Value {
+    json!({ "key": key, "value": { "doubleValue": value } })
+}
+
+/// 64-bit integers are strings in OTLP/JSON.
+fn int_attribute(key: &str, value: i64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// A POST received by the collector stand-in.
+    struct Received {
+        path: String,
+        head: String,
+        body: Value,
+    }
+
+    /// Reads one request off `stream` and answers it with 200 OK.
+    async fn receive(mut stream: TcpStream) -> Received {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        let head_len = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid-request");
+            data.extend_from_slice(&buf[..n]);
+            if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let head = String::from_utf8(data[..head_len].to_vec())
+            .unwrap()
+            .to_lowercase();
+        let body_len: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        while data.len() < head_len + body_len {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid-body");
+            data.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        Received {
+            path: head.split_whitespace().nth(1).unwrap().to_owned(),
+            body: serde_json::from_slice(&data[head_len..head_len + body_len]).unwrap(),
+            head,
+        }
+    }
+
+    /// Starts a collector stand-in, returning its endpoint and what it
+    /// receives.
+    async fn collector() -> (String, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if tx.send(receive(stream).await).is_err() {
+                    break;
+                }
+            }
+        });
+        (endpoint, rx)
+    }
+
+    fn keys(attributes: &Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = attributes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| a["key"].as_str().unwrap())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn exports_metrics_and_logs_as_json() {
+        let (endpoint, mut received) = collector().await;
+        let exporter = OtlpExporter::new(OtlpConfig {
+            endpoint,
+            headers: BTreeMap::from([("authorization".to_owned(), "Bearer test".to_owned())]),
+            service_name: "shop".to_owned(),
+            logs: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let request_id = RequestId::generate();
+        let trace = TraceContext::parse_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        let ok = ServerRequest {
+            method: "GET",
+            route: Some("/items/{id}"),
+            path: "/items/1",
+            status: 200,
+            scheme: "https",
+            protocol_version: "1.1",
+            duration: Duration::from_millis(30),
+            request_id: &request_id,
+            trace: Some(&trace),
+        };
+        exporter.observe(&ok);
+        exporter.observe(&ServerRequest {
+            method: "BREW",
+            route: None,
+            path: "/pot",
+            status: 503,
+            duration: Duration::from_secs(2),
+            trace: None,
+            ..ok
+        });
+        exporter.export().await.unwrap();
+
+        let metrics = received.recv().await.unwrap();
+        assert_eq!(metrics.path, "/v1/metrics");
+        assert!(metrics.head.contains("authorization: bearer test"));
+        assert!(metrics.head.contains("content-type: application/json"));
+        let resource = &metrics.body["resourceMetrics"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0],
+            string_attribute("service.name", "shop")
+        );
+        let metric = &resource["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(metric["name"], "http.server.request.duration");
+        assert_eq!(metric["unit"], "s");
+        assert_eq!(metric["histogram"]["aggregationTemporality"], CUMULATIVE);
+
+        let points = metric["histogram"]["dataPoints"].as_array().unwrap();
+        assert_eq!(points.len(), 2);
+        for point in points {
+            let bounds = point["explicitBounds"].as_array().unwrap();
+            let counts = point["bucketCounts"].as_array().unwrap();
+            assert_eq!(counts.len(), bounds.len() + 1);
+            assert_eq!(point["count"], "1");
+        }
+        // Series are ordered by method, and unknown methods become `_OTHER`.
+        assert_eq!(
+            keys(&points[0]["attributes"]),
+            [
+                "http.request.method",
+                "http.response.status_code",
+                "http.route",
+                "network.protocol.version",
+                "url.scheme",
+            ]
+        );
+        assert_eq!(points[0]["bucketCounts"][3], "1");
+        assert_eq!(
+            keys(&points[1]["attributes"]),
+            [
+                "error.type",
+                "http.request.method",
+                "http.response.status_code",
+                "network.protocol.version",
+                "url.scheme",
+            ]
+        );
+        assert_eq!(
+            points[1]["attributes"][0],
+            string_attribute("http.request.method", "_OTHER")
+        );
+        assert_eq!(points[1]["bucketCounts"][10], "1");
+
+        let logs = received.recv().await.unwrap();
+        assert_eq!(logs.path, "/v1/logs");
+        let records = logs.body["resourceLogs"][0]["scopeLogs"][0]["logRecords"]
+            .as_array()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            keys(&records[0]["attributes"]),
+            [
+                "http.request.id",
+                "http.request.method",
+                "http.response.status_code",
+                "http.route",
+                "http.server.request.duration",
+                "network.protocol.version",
+                "url.path",
+                "url.scheme",
+            ]
+        );
+        assert_eq!(records[0]["severityText"], "INFO");
+        assert_eq!(records[0]["traceId"], trace.trace_id);
+        assert_eq!(records[0]["spanId"], trace.parent_id);
+        assert_eq!(records[1]["severityText"], "ERROR");
+        assert!(records[1].get("traceId").is_none());
+    }
+
+    #[tokio::test]
+    async fn exports_nothing_before_the_first_request() {
+        let (endpoint, mut received) = collector().await;
+        let exporter = OtlpExporter::new(OtlpConfig {
+            endpoint,
+            ..Default::default()
+        })
+        .unwrap();
+
+        exporter.export().await.unwrap();
+        assert!(received.try_recv().is_err());
+    }
+}
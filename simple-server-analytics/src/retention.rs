@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use simple_server_analytics_db::PruneCounts;
+use tokio::task::JoinHandle;
+use tracing::*;
+
+use crate::{config::RetentionConfig, SimpleAnalytics};
+
+/// Periodically prunes data older than the configured number of days.
+#[derive(Debug)]
+pub struct RetentionTask {
+    sa: SimpleAnalytics,
+    config: RetentionConfig,
+}
+
+impl RetentionTask {
+    pub fn new(sa: &SimpleAnalytics, config: RetentionConfig) -> Self {
+        Self {
+            sa: sa.clone(),
+            config,
+        }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.prune().await {
+                    error!("Failed to prune old data: {e:?}");
+                }
+            }
+        })
+    }
+
+    /// Prunes now, doing nothing if no retention period is set.
+    pub async fn prune(&self) -> anyhow::Result<PruneCounts> {
+        let Some(days) = self.config.days else {
+            return Ok(Default::default());
+        };
+        let before = Utc::now() - chrono::Duration::days(days.into());
+        let counts = self.sa.store().prune(&before).await?;
+        info!(
+            "Pruned {} connections, {} requests and {} responses from before {before}",
+            counts.connections, counts.requests, counts.responses
+        );
+
+        if self.config.vacuum {
+            self.sa.store().vacuum().await?;
+        }
+        Ok(counts)
+    }
+}
@@ -0,0 +1,274 @@
+//! Include and exclude rules deciding which requests are recorded.
+//!
+//! A request is recorded if it matches any include rule, or there are none,
+//! and matches no exclude rule. Every field set on a rule must match for the
+//! rule to match:
+//!
+//! ```toml
+//! [[rules.exclude]]
+//! path = "/static/**"
+//!
+//! [[rules.exclude]]
+//! path_regex = "^/(health|ready)z?$"
+//!
+//! [[rules.exclude]]
+//! host = "admin.*"
+//! methods = ["GET", "POST"]
+//!
+//! [[rules.exclude]]
+//! user_agent = "(?i)bot|crawler|spider"
+//! ```
+
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// The parts of a request rules are matched against.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestInfo<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub hostname: &'a str,
+    pub user_agent: &'a str,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+    pub include: Vec<Rule>,
+    pub exclude: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub async fn from_toml_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_toml(&tokio::fs::read_to_string(path).await?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, req: &RequestInfo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(req)))
+            && !self.exclude.iter().any(|rule| rule.matches(req))
+    }
+
+    pub fn include(mut self, rule: Rule) -> Self {
+        self.include.push(rule);
+        self
+    }
+
+    pub fn exclude(mut self, rule: Rule) -> Self {
+        self.exclude.push(rule);
+        self
+    }
+}
+
+/// What a [`Rule`] matches, as written in configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleSpec {
+    /// Glob over the path, where `*` stays within a segment and `**` does not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_regex: Option<String>,
+    /// Case-insensitive glob over the `Host` header, without its port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// Regex searched for in the `User-Agent` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+/// A compiled [`RuleSpec`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RuleSpec", into = "RuleSpec")]
+pub struct Rule {
+    spec: RuleSpec,
+    path: Option<GlobMatcher>,
+    path_regex: Option<Regex>,
+    host: Option<GlobMatcher>,
+    user_agent: Option<Regex>,
+}
+
+impl Rule {
+    pub fn new(spec: RuleSpec) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: spec
+                .path
+                .as_deref()
+                .map(|p| GlobBuilder::new(p).literal_separator(true).build())
+                .transpose()?
+                .map(|g| g.compile_matcher()),
+            path_regex: spec.path_regex.as_deref().map(Regex::new).transpose()?,
+            host: spec
+                .host
+                .as_deref()
+                .map(|h| GlobBuilder::new(h).case_insensitive(true).build())
+                .transpose()?
+                .map(|g| g.compile_matcher()),
+            user_agent: spec.user_agent.as_deref().map(Regex::new).transpose()?,
+            spec,
+        })
+    }
+
+    /// Matches paths against a glob.
+    pub fn path(glob: &str) -> anyhow::Result<Self> {
+        Self::new(RuleSpec {
+            path: Some(glob.to_owned()),
+            ..Default::default()
+        })
+    }
+
+    /// Matches hosts against a glob.
+    pub fn host(glob: &str) -> anyhow::Result<Self> {
+        Self::new(RuleSpec {
+            host: Some(glob.to_owned()),
+            ..Default::default()
+        })
+    }
+
+    /// Matches user agents containing `regex`.
+    pub fn user_agent(regex: &str) -> anyhow::Result<Self> {
+        Self::new(RuleSpec {
+            user_agent: Some(regex.to_owned()),
+            ..Default::default()
+        })
+    }
+
+    pub fn spec(&self) -> &RuleSpec {
+        &self.spec
+    }
+
+    pub fn matches(&self, req: &RequestInfo) -> bool {
+        let host = req.hostname.split(':').next().unwrap_or_default();
+
+        self.path.as_ref().map_or(true, |g| g.is_match(req.path))
+            && self
+                .path_regex
+                .as_ref()
+                .map_or(true, |r| r.is_match(req.path))
+            && self.host.as_ref().map_or(true, |g| g.is_match(host))
+            && (self.spec.methods.is_empty()
+                || self
+                    .spec
+                    .methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(req.method)))
+            && self
+                .user_agent
+                .as_ref()
+                .map_or(true, |r| r.is_match(req.user_agent))
+    }
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: RuleSpec) -> anyhow::Result<Self> {
+        Self::new(spec)
+    }
+}
+
+impl From<Rule> for RuleSpec {
+    fn from(rule: Rule) -> Self {
+        rule.spec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str) -> RequestInfo<'_> {
+        RequestInfo {
+            method: "GET",
+            path,
+            hostname: "example.com",
+            user_agent: "Mozilla/5.0",
+        }
+    }
+
+    #[test]
+    fn path_globs_keep_single_stars_within_a_segment() {
+        let rule = Rule::path("/api/*").unwrap();
+        assert!(rule.matches(&get("/api/items")));
+        assert!(!rule.matches(&get("/api/items/1")));
+
+        let rule = Rule::path("/static/**").unwrap();
+        assert!(rule.matches(&get("/static/css/site.css")));
+        assert!(!rule.matches(&get("/statics")));
+    }
+
+    #[test]
+    fn regexes_match_paths_and_user_agents() {
+        let rule = Rule::new(RuleSpec {
+            path_regex: Some("^/(health|ready)z?$".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(rule.matches(&get("/healthz")));
+        assert!(rule.matches(&get("/ready")));
+        assert!(!rule.matches(&get("/healthcheck")));
+
+        let rule = Rule::user_agent("(?i)bot|crawler").unwrap();
+        let bot = RequestInfo {
+            user_agent: "Mozilla/5.0 (compatible; Googlebot/2.1)",
+            ..get("/")
+        };
+        assert!(rule.matches(&bot));
+        assert!(!rule.matches(&get("/")));
+    }
+
+    #[test]
+    fn hosts_match_without_port_and_case() {
+        let rule = Rule::host("admin.*").unwrap();
+        let admin = RequestInfo {
+            hostname: "ADMIN.example.com:8443",
+            ..get("/")
+        };
+        assert!(rule.matches(&admin));
+        assert!(!rule.matches(&get("/")));
+    }
+
+    #[test]
+    fn every_field_must_match() {
+        let rule = Rule::new(RuleSpec {
+            path: Some("/api/**".to_owned()),
+            methods: vec!["post".to_owned()],
+            ..Default::default()
+        })
+        .unwrap();
+        let post = RequestInfo {
+            method: "POST",
+            ..get("/api/items")
+        };
+        assert!(rule.matches(&post));
+        assert!(!rule.matches(&get("/api/items")));
+    }
+
+    #[test]
+    fn excludes_take_precedence_over_includes() {
+        assert!(Rules::default().allows(&get("/")));
+
+        let rules = Rules::default()
+            .include(Rule::path("/api/**").unwrap())
+            .exclude(Rule::path("/api/internal/**").unwrap());
+        assert!(rules.allows(&get("/api/items")));
+        assert!(!rules.allows(&get("/api/internal/jobs")));
+        assert!(!rules.allows(&get("/")));
+
+        let rules = Rules::default().exclude(Rule::path("/static/**").unwrap());
+        assert!(rules.allows(&get("/")));
+        assert!(!rules.allows(&get("/static/app.js")));
+    }
+}
@@ -1,6 +1,12 @@
+pub mod export;
 pub mod handler;
 pub mod listener;
+pub mod live;
+pub mod metrics;
+pub mod request_id;
 pub mod service;
+pub mod stats;
+pub mod trace_context;
 
 use std::sync::Arc;
 
@@ -10,7 +16,13 @@ use super::SimpleAnalytics;
 
 impl SimpleAnalytics {
     pub fn append_routes(&self, router: &mut salvo::Router) {
-        router.routers_mut().push(Router::with_path("/analytics"))
+        router.routers_mut().push(
+            Router::with_path("/analytics")
+                .push(stats::router(self))
+                .push(Router::with_path("live").get(live::LiveHandler::new(self)))
+                .push(Router::with_path("export").get(export::ExportHandler::new(self)))
+                .push(Router::with_path("metrics").get(metrics::MetricsHandler::new(self))),
+        )
     }
 
     pub fn prepend_handler(&self, router: &mut salvo::Router) {
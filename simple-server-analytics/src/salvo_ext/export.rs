@@ -0,0 +1,108 @@
+use std::io;
+
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use salvo::{
+    async_trait,
+    http::{
+        header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusError,
+    },
+    Depot, FlowCtrl, Handler, Request, Response,
+};
+use serde::Deserialize;
+use simple_server_analytics_db::export::{ExportFormat, ExportTable};
+use tokio_util::io::ReaderStream;
+use tracing::*;
+
+use crate::SimpleAnalytics;
+
+/// Bytes buffered between the exporter and the response body.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub table: ExportTable,
+    pub format: ExportFormat,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+pub struct ExportHandler {
+    sa: SimpleAnalytics,
+}
+
+impl ExportHandler {
+    pub fn new(sa: &SimpleAnalytics) -> Self {
+        Self { sa: sa.clone() }
+    }
+}
+
+#[async_trait]
+impl Handler for ExportHandler {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        let query = match req.parse_queries::<ExportQuery>() {
+            Ok(query) => query,
+            Err(e) => {
+                res.render(StatusError::bad_request().brief(e.to_string()));
+                return;
+            }
+        };
+        let to = query.to.unwrap_or_else(Utc::now);
+        let from = query.from.unwrap_or(to - Duration::days(7));
+
+        let filename = format!(
+            "{}-{}-{}.{}",
+            query.table.name(),
+            from.format("%Y%m%d"),
+            to.format("%Y%m%d"),
+            query.format.extension()
+        );
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(query.format.content_type()),
+        );
+        if let Ok(disposition) = format!("attachment; filename=\"{filename}\"").parse() {
+            res.headers_mut().insert(CONTENT_DISPOSITION, disposition);
+        }
+
+        let (mut writer, reader) = tokio::io::duplex(PIPE_CAPACITY);
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let sa = self.sa.clone();
+        tokio::spawn(async move {
+            let result = sa
+                .store()
+                .export(query.table, query.format, &from, &to, &mut writer)
+                .await;
+            if let Err(e) = &result {
+                error!("Failed to export {filename}: {e:?}");
+            }
+            let _ = done_tx.send(result.map(|_| ()));
+        });
+
+        // The status has gone out by the time the export can fail, so fail
+        // the body instead, which resets the connection rather than ending a
+        // truncated file cleanly.
+        let failure = futures_util::stream::once(async move {
+            match done_rx.await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(Err(io::Error::new(io::ErrorKind::Other, e.to_string()))),
+                Err(_) => Some(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "export stopped unexpectedly",
+                ))),
+            }
+        })
+        .filter_map(|result| async move { result });
+
+        if let Err(e) = res.stream(ReaderStream::new(reader).chain(failure)) {
+            error!("Failed to stream export: {e:?}");
+        }
+    }
+}
@@ -1,13 +1,16 @@
+use std::sync::Arc;
+
 use salvo::{
     async_trait,
-    hyper::header::{HOST, USER_AGENT},
+    hyper::header::{HeaderValue, HOST, REFERER, USER_AGENT},
     Depot, FlowCtrl, Handler, Request, Response,
 };
+use simple_server_analytics_db::attribution::Attribution;
 use tracing::*;
 
-use crate::SimpleAnalytics;
+use crate::{metrics::UNMATCHED_ROUTE, PendingConnection, SimpleAnalytics};
 
-use super::service::ConnId;
+use super::{request_id::RequestId, service::ConnId, trace_context::TraceContext};
 
 pub struct SimpleAnalyticsHandler {
     sa: SimpleAnalytics,
@@ -29,44 +32,217 @@ impl Handler for SimpleAnalyticsHandler {
         ctrl: &mut FlowCtrl,
     ) {
         let conn_id = req.extensions().get::<ConnId>().cloned();
+        let connection = req.extensions().get::<Arc<PendingConnection>>().cloned();
         let started = std::time::Instant::now();
 
-        let req_id = self
-            .sa
-            .report_request(
-                conn_id.map(|ci| ci.0).as_ref(),
-                req.method().as_str(),
-                req.uri().path(),
-                req.headers()
-                    .get(HOST)
-                    .map(|v| v.to_str().ok())
-                    .flatten()
-                    .unwrap_or_default(),
-                req.headers()
-                    .get(USER_AGENT)
-                    .map(|v| v.to_str().ok())
-                    .flatten()
-                    .unwrap_or_default(),
-            )
-            .await;
-        if let Err(ref e) = req_id {
-            error!("Failed to report request: {e:?}");
+        let config = self.sa.request_id_config();
+        let header = self.sa.request_id_header();
+        let request_id = Some(header)
+            .filter(|_| config.trust_incoming)
+            .and_then(|h| req.headers().get(h))
+            .and_then(|v| v.to_str().ok())
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        depot.inject(request_id.clone());
+        let trace = TraceContext::from_headers(req.headers());
+
+        let hostname = req
+            .headers()
+            .get(HOST)
+            .map(|v| v.to_str().ok())
+            .flatten()
+            .unwrap_or_default();
+        let attribution = Attribution::parse(
+            req.headers()
+                .get(REFERER)
+                .map(|v| v.to_str().ok())
+                .flatten(),
+            hostname,
+            req.uri().query(),
+        );
+
+        let pending = self.sa.start_request(
+            &request_id,
+            conn_id.map(|ci| ci.0).as_ref(),
+            req.method().as_str(),
+            req.uri().path(),
+            hostname,
+            req.headers()
+                .get(USER_AGENT)
+                .map(|v| v.to_str().ok())
+                .flatten()
+                .unwrap_or_default(),
+            &attribution,
+            trace.as_ref(),
+        );
+        if let (Some(_), Some(connection)) = (&pending, &connection) {
+            if let Err(e) = self.sa.record_connection(connection).await {
+                error!("Failed to report connection: {e:?}");
+            }
         }
 
-        ctrl.call_next(req, depot, res).await;
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = req.uri().path(),
+            route = field::Empty,
+            status = field::Empty,
+            trace_id = trace.as_ref().map(|t| t.trace_id.as_str()),
+            parent_span_id = trace.as_ref().map(|t| t.parent_id.as_str()),
+        );
+        if let Some(trace) = trace.clone() {
+            depot.inject(trace);
+        }
+
+        ctrl.call_next(req, depot, res)
+            .instrument(span.clone())
+            .await;
 
         let duration = started.elapsed();
         let status = res.status_code.unwrap_or_default().as_u16();
 
-        if let Ok(req_id) = req_id {
-            let res_id = self
-                .sa
-                .report_response(conn_id.map(|ci| ci.0).as_ref(), &req_id, &duration, status)
-                .await;
+        if config.echo {
+            if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+                res.headers_mut().entry(header.clone()).or_insert(value);
+            }
+        }
+
+        let route = match route_template(req, status) {
+            Some(template) => self.sa.metrics.route_label(template),
+            None => UNMATCHED_ROUTE.to_owned(),
+        };
+        span.record("route", route.as_str());
+        span.record("status", status);
+
+        self.sa
+            .metrics
+            .observe_request(req.method().as_str(), &route, status, &duration);
+
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = self.sa.otlp().filter(|_| pending.is_some()) {
+            otlp.observe(&crate::otlp::ServerRequest {
+                method: req.method().as_str(),
+                route: Some(route.as_str())
+                    .filter(|r| ![UNMATCHED_ROUTE, crate::metrics::OTHER_ROUTE].contains(r)),
+                path: req.uri().path(),
+                status,
+                scheme: req.scheme().as_str(),
+                protocol_version: protocol_version(req.version()),
+                duration,
+                request_id: &request_id,
+                trace: trace.as_ref(),
+            });
+        }
 
-            if let Err(ref e) = res_id {
-                error!("Failed to report response: {e:?}");
+        if let Some(pending) = pending {
+            if let Err(e) = pending.complete(&duration, status).await {
+                error!("Failed to report request: {e:?}");
             }
         }
     }
 }
+
+/// Rebuilds the matched route from the request path by substituting path
+/// parameters back in, keeping metric label cardinality bounded. Returns `None`
+/// if no route matched, meaning there are no path parameters and the response
+/// is 404 Not Found or 405 Method Not Allowed.
+///
+/// Parameters are substituted in the order they appear in the path, so a
+/// segment is only taken for a parameter if it holds the next one's value.
+fn route_template(req: &Request, status: u16) -> Option<String> {
+    let params = req.params();
+    if params.is_empty() && matches!(status, 404 | 405) {
+        return None;
+    }
+
+    let params: Vec<(&str, &str)> = params
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    Some(substitute_params(req.uri().path(), &params))
+}
+
+fn substitute_params(path: &str, params: &[(&str, &str)]) -> String {
+    let mut segment_params = params
+        .iter()
+        .filter(|(_, value)| !value.is_empty() && !value.contains('/'))
+        .peekable();
+    let mut segments: Vec<String> = path
+        .split('/')
+        .map(|segment| match segment_params.peek() {
+            Some((name, value)) if *value == segment => {
+                segment_params.next();
+                format!("{{{name}}}")
+            }
+            _ => segment.to_owned(),
+        })
+        .collect();
+
+    // A wildcard such as `<**rest>` spans the segments at the end of the path.
+    for (name, value) in params.iter().filter(|(_, value)| value.contains('/')) {
+        let at_segment = path
+            .strip_suffix(value)
+            .is_some_and(|prefix| prefix.ends_with('/'));
+        if at_segment {
+            let len = segments.len().saturating_sub(value.split('/').count());
+            segments.truncate(len);
+            segments.push(format!("{{{name}}}"));
+        }
+    }
+
+    segments.join("/")
+}
+
+#[cfg(feature = "otlp")]
+fn protocol_version(version: salvo::hyper::Version) -> &'static str {
+    use salvo::hyper::Version;
+
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_11 => "1.1",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_params_in_order() {
+        assert_eq!(
+            substitute_params("/users/42/posts/7", &[("id", "42"), ("post", "7")]),
+            "/users/{id}/posts/{post}"
+        );
+        assert_eq!(substitute_params("/about", &[]), "/about");
+        // A segment holding a later parameter's value is left as it is.
+        assert_eq!(
+            substitute_params("/7/users/42/7", &[("id", "42"), ("n", "7")]),
+            "/7/users/{id}/{n}"
+        );
+    }
+
+    #[test]
+    fn substitutes_wildcards_after_other_params() {
+        assert_eq!(
+            substitute_params(
+                "/users/42/files/a/b.txt",
+                &[("id", "42"), ("rest", "a/b.txt")]
+            ),
+            "/users/{id}/files/{rest}"
+        );
+        assert_eq!(
+            substitute_params("/static/css/site.css", &[("path", "css/site.css")]),
+            "/static/{path}"
+        );
+        // A single segment caught by a wildcard is substituted like any other.
+        assert_eq!(
+            substitute_params("/static/site.css", &[("path", "site.css")]),
+            "/static/{path}"
+        );
+    }
+}
@@ -14,7 +14,7 @@ use simple_id::chrono_id::Id as ChronoId;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_util::sync::CancellationToken;
 
-use crate::SimpleAnalytics;
+use crate::{metrics::ConnectionGuard, PendingConnection, SimpleAnalytics};
 
 use super::service::SimpleAnalyticsService;
 
@@ -75,18 +75,15 @@ where
     #[inline]
     async fn accept(&mut self) -> IoResult<Accepted<Self::Conn>> {
         let accepted = self.inner.accept().await?;
-        let reported_conn = self
-            .sa
-            .report_new_connection(
-                &accepted.local_addr.clone().into_std().unwrap(),
-                &accepted.remote_addr.clone().into_std().unwrap(),
-                &accepted.http_scheme,
-                &accepted.http_version,
-            )
-            .await;
-
-        Ok(accepted
-            .map_conn(|conn| SimpleAnalyticsStream::new(conn, reported_conn.ok().map(|id| id))))
+        let guard = self.sa.metrics.open_connection();
+        let connection = self.sa.new_connection(
+            &accepted.local_addr.clone().into_std().unwrap(),
+            &accepted.remote_addr.clone().into_std().unwrap(),
+            &accepted.http_scheme,
+            &accepted.http_version,
+        );
+
+        Ok(accepted.map_conn(|conn| SimpleAnalyticsStream::new(conn, Arc::new(connection), guard)))
     }
 }
 
@@ -94,12 +91,17 @@ where
 pub struct SimpleAnalyticsStream<T> {
     #[pin]
     inner: T,
-    conn_id: Option<ChronoId>,
+    connection: Arc<PendingConnection>,
+    _guard: ConnectionGuard,
 }
 
 impl<T> SimpleAnalyticsStream<T> {
-    pub fn new(inner: T, conn_id: Option<ChronoId>) -> Self {
-        Self { inner, conn_id }
+    pub fn new(inner: T, connection: Arc<PendingConnection>, guard: ConnectionGuard) -> Self {
+        Self {
+            inner,
+            connection,
+            _guard: guard,
+        }
     }
 }
 
@@ -149,7 +151,7 @@ where
         server_shutdown_token: CancellationToken,
         idle_connection_timeout: Option<Duration>,
     ) -> IoResult<()> {
-        let service = SimpleAnalyticsService::new(handler, self.conn_id);
+        let service = SimpleAnalyticsService::new(handler, self.connection.clone());
 
         builder
             .serve_connection(
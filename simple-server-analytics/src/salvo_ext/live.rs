@@ -0,0 +1,56 @@
+use std::convert::Infallible;
+
+use futures_util::stream;
+use salvo::{
+    async_trait,
+    http::StatusError,
+    sse::{SseEvent, SseKeepAlive},
+    Depot, FlowCtrl, Handler, Request, Response,
+};
+use tracing::*;
+
+use crate::{live::LiveFilter, SimpleAnalytics};
+
+pub struct LiveHandler {
+    sa: SimpleAnalytics,
+}
+
+impl LiveHandler {
+    pub fn new(sa: &SimpleAnalytics) -> Self {
+        Self { sa: sa.clone() }
+    }
+}
+
+#[async_trait]
+impl Handler for LiveHandler {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        let filter = match req.parse_queries::<LiveFilter>() {
+            Ok(filter) => filter,
+            Err(e) => {
+                res.render(StatusError::bad_request().brief(e.to_string()));
+                return;
+            }
+        };
+
+        let subscription = self.sa.subscribe_live(filter);
+        let events = stream::unfold(subscription, |mut subscription| async move {
+            loop {
+                let event = subscription.next().await?;
+                match SseEvent::default().name(event.name()).json(&event) {
+                    Ok(sse) => return Some((Ok::<_, Infallible>(sse), subscription)),
+                    Err(e) => error!("Failed to serialize live event: {e:?}"),
+                }
+            }
+        });
+
+        if let Err(e) = SseKeepAlive::new(events).streaming(res) {
+            error!("Failed to start live stream: {e:?}");
+        }
+    }
+}
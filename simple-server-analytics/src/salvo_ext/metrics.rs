@@ -0,0 +1,40 @@
+use salvo::{
+    async_trait,
+    http::header::{HeaderValue, CONTENT_TYPE},
+    Depot, FlowCtrl, Handler, Request, Response,
+};
+use tracing::*;
+
+use crate::SimpleAnalytics;
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+pub struct MetricsHandler {
+    sa: SimpleAnalytics,
+}
+
+impl MetricsHandler {
+    pub fn new(sa: &SimpleAnalytics) -> Self {
+        Self { sa: sa.clone() }
+    }
+}
+
+#[async_trait]
+impl Handler for MetricsHandler {
+    async fn handle(
+        &self,
+        _req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(OPENMETRICS_CONTENT_TYPE),
+        );
+        let pools = self.sa.store().pool_stats().await;
+        if let Err(e) = res.write_body(self.sa.metrics.render(&pools)) {
+            error!("Failed to write metrics: {e:?}");
+        }
+    }
+}
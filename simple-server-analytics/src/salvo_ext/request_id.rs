@@ -0,0 +1,105 @@
+use std::fmt;
+
+use simple_id::chrono_id::Id as ChronoId;
+
+/// Longest incoming request id taken from a header.
+const MAX_EXTERNAL_LEN: usize = 200;
+
+/// The id of the request being handled.
+/// [`SimpleAnalyticsHandler`](super::handler::SimpleAnalyticsHandler) injects
+/// it into the `Depot`:
+///
+/// ```ignore
+/// let request_id = depot.obtain::<RequestId>().unwrap();
+/// tracing::info!(%request_id, "handling request");
+/// ```
+///
+/// Its text form is what the `X-Request-Id` response header carries: the
+/// incoming header's id when it is trusted, which is recorded as the request's
+/// `external_id`, and the generated id of its analytics row otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId {
+    id: ChronoId,
+    external: Option<String>,
+    text: String,
+}
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self::from_id(ChronoId::new())
+    }
+
+    pub fn from_id(id: ChronoId) -> Self {
+        Self {
+            id,
+            external: None,
+            text: id.to_string(),
+        }
+    }
+
+    /// Takes the id a proxy set in the request id header, such as a UUID,
+    /// alongside a generated row id. `None` if it is empty or too long.
+    pub fn from_header(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.is_empty() || text.len() > MAX_EXTERNAL_LEN {
+            return None;
+        }
+        Some(Self {
+            id: ChronoId::new(),
+            external: Some(text.to_owned()),
+            text: text.to_owned(),
+        })
+    }
+
+    /// The id of the request's analytics row.
+    pub fn id(&self) -> &ChronoId {
+        &self.id
+    }
+
+    /// The id from the request header, if it was taken from there.
+    pub fn external(&self) -> Option<&str> {
+        self.external.as_deref()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_any_incoming_id_alongside_a_generated_one() {
+        for text in [
+            "3f1c1f5e-8d6a-4c8e-9b1a-2f4e6d8c0a1b",
+            "8e3a5f0c2b7d4e19a6c3",
+        ] {
+            let a = RequestId::from_header(text).unwrap();
+            let b = RequestId::from_header(text).unwrap();
+            assert_eq!(a.as_str(), text);
+            assert_eq!(a.external(), Some(text));
+            assert_ne!(a.id(), b.id());
+        }
+
+        assert_eq!(RequestId::from_header("  "), None);
+        assert_eq!(
+            RequestId::from_header(&"a".repeat(MAX_EXTERNAL_LEN + 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn generated_ids_display_as_the_row_id() {
+        let request_id = RequestId::generate();
+        assert_eq!(request_id.as_str(), request_id.id().to_string());
+        assert_eq!(request_id.external(), None);
+    }
+}
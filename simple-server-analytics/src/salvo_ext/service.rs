@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use salvo::hyper::{
     service::Service as HyperService, Request as HyperRequest, Response as HyperResponse,
 };
@@ -5,20 +7,22 @@ use simple_id::chrono_id::Id as ChronoId;
 
 use salvo::http::body::HyperBody;
 
+use crate::PendingConnection;
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, derive_more::Deref)]
 pub struct ConnId(pub ChronoId);
 
 pub struct SimpleAnalyticsService<T> {
     inner: T,
-    conn_id: Option<ChronoId>,
+    connection: Arc<PendingConnection>,
 }
 
 impl<T> SimpleAnalyticsService<T> {
-    pub fn new(service: T, conn_id: Option<ChronoId>) -> Self {
+    pub fn new(service: T, connection: Arc<PendingConnection>) -> Self {
         Self {
             inner: service,
-            conn_id,
+            connection,
         }
     }
 }
@@ -37,9 +41,8 @@ where
     type Future = T::Future;
 
     fn call(&self, mut req: HyperRequest<HyperBody>) -> Self::Future {
-        if let Some(conn_id) = self.conn_id {
-            req.extensions_mut().insert(ConnId(conn_id));
-        }
+        req.extensions_mut().insert(ConnId(self.connection.id()));
+        req.extensions_mut().insert(self.connection.clone());
         self.inner.call(req)
     }
 }
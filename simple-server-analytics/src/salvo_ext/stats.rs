@@ -0,0 +1,185 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+use salvo::{
+    async_trait, http::StatusError, writing::Json, Depot, FlowCtrl, Handler, Request, Response,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use simple_server_analytics_db::apdex::MAX_POINTS;
+use tracing::*;
+
+use crate::SimpleAnalytics;
+
+pub fn router(sa: &SimpleAnalytics) -> Router {
+    Router::with_path("stats")
+        .push(Router::with_path("cohorts").get(StatsHandler::new(sa, StatsReport::Cohorts)))
+        .push(Router::with_path("sources").get(StatsHandler::new(sa, StatsReport::Sources)))
+        .push(Router::with_path("campaigns").get(StatsHandler::new(sa, StatsReport::Campaigns)))
+        .push(Router::with_path("slos").get(StatsHandler::new(sa, StatsReport::Slos)))
+        .push(
+            Router::with_path("apdex")
+                .get(StatsHandler::new(sa, StatsReport::Apdex))
+                .push(
+                    Router::with_path("timeseries")
+                        .get(StatsHandler::new(sa, StatsReport::ApdexTimeseries)),
+                ),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<u32>,
+    pub step_secs: Option<i64>,
+}
+
+impl StatsQuery {
+    pub fn range(
+        &self,
+        default_span: Duration,
+    ) -> Result<(DateTime<Utc>, DateTime<Utc>), BadQuery> {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = self.from.unwrap_or(to - default_span);
+        if from > to {
+            return Err(BadQuery(format!("from {from} is after to {to}")));
+        }
+        Ok((from, to))
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(10)
+    }
+
+    /// The step between points over `[from, to)`, which must be positive and
+    /// split the range into at most [`MAX_POINTS`].
+    pub fn step(
+        &self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        default_step: Duration,
+    ) -> Result<Duration, BadQuery> {
+        let step_secs = self.step_secs.unwrap_or(default_step.num_seconds());
+        if step_secs <= 0 {
+            return Err(BadQuery(format!("step_secs {step_secs} is not positive")));
+        }
+        let points = (*to - *from).num_seconds() / step_secs;
+        if points > MAX_POINTS {
+            return Err(BadQuery(format!(
+                "{points} points of {step_secs}s is more than the {MAX_POINTS} allowed"
+            )));
+        }
+        Ok(Duration::seconds(step_secs))
+    }
+}
+
+/// A query that can't be answered as asked, rendered as 400 Bad Request.
+#[derive(Debug)]
+pub struct BadQuery(pub String);
+
+impl fmt::Display for BadQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadQuery {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatsReport {
+    Cohorts,
+    Sources,
+    Campaigns,
+    Slos,
+    Apdex,
+    ApdexTimeseries,
+}
+
+pub struct StatsHandler {
+    sa: SimpleAnalytics,
+    report: StatsReport,
+}
+
+impl StatsHandler {
+    pub fn new(sa: &SimpleAnalytics, report: StatsReport) -> Self {
+        Self {
+            sa: sa.clone(),
+            report,
+        }
+    }
+
+    async fn query(&self, query: &StatsQuery) -> anyhow::Result<serde_json::Value> {
+        let store = self.sa.store();
+
+        Ok(match self.report {
+            StatsReport::Cohorts => {
+                let (from, to) = query.range(Duration::weeks(12))?;
+                to_json(store.cohorts(&from, &to).await?)?
+            }
+            StatsReport::Sources => {
+                let (from, to) = query.range(Duration::days(30))?;
+                to_json(store.top_sources(&from, &to, query.limit()).await?)?
+            }
+            StatsReport::Campaigns => {
+                let (from, to) = query.range(Duration::days(30))?;
+                to_json(store.campaigns(&from, &to, query.limit()).await?)?
+            }
+            StatsReport::Slos => {
+                let now = query.to.unwrap_or_else(Utc::now);
+                let mut reports = Vec::with_capacity(self.sa.slos.len());
+                for slo in self.sa.slos.iter() {
+                    reports.push(store.slo_report(slo, &now).await?);
+                }
+                to_json(reports)?
+            }
+            StatsReport::Apdex => {
+                let (from, to) = query.range(Duration::days(1))?;
+                to_json(store.apdex(&self.sa.apdex, &from, &to).await?)?
+            }
+            StatsReport::ApdexTimeseries => {
+                let (from, to) = query.range(Duration::days(1))?;
+                let step = query.step(&from, &to, Duration::hours(1))?;
+                to_json(
+                    store
+                        .apdex_timeseries(&self.sa.apdex, &from, &to, step)
+                        .await?,
+                )?
+            }
+        })
+    }
+}
+
+fn to_json<T: Serialize>(value: T) -> serde_json::Result<serde_json::Value> {
+    serde_json::to_value(value)
+}
+
+#[async_trait]
+impl Handler for StatsHandler {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        let query = match req.parse_queries::<StatsQuery>() {
+            Ok(query) => query,
+            Err(e) => {
+                res.render(StatusError::bad_request().brief(e.to_string()));
+                return;
+            }
+        };
+
+        match self.query(&query).await {
+            Ok(report) => res.render(Json(report)),
+            Err(e) => match e.downcast_ref::<BadQuery>() {
+                Some(bad) => res.render(StatusError::bad_request().brief(bad.to_string())),
+                None => {
+                    error!("Failed to query {:?} stats: {e:?}", self.report);
+                    res.render(StatusError::internal_server_error());
+                }
+            },
+        }
+    }
+}
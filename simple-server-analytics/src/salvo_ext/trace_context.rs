@@ -0,0 +1,207 @@
+//! W3C Trace Context, see <https://www.w3.org/TR/trace-context/>.
+
+use salvo::hyper::header::HeaderMap;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// More list members than this make `tracestate` invalid.
+const MAX_TRACESTATE_MEMBERS: usize = 32;
+
+/// The trace a request is part of, from its `traceparent` and `tracestate`
+/// headers. [`SimpleAnalyticsHandler`](super::handler::SimpleAnalyticsHandler)
+/// injects it into the `Depot` when the request carries one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// The span that made the request, as 16 lowercase hex digits.
+    pub parent_id: String,
+    pub flags: u8,
+    /// Vendor-specific entries, passed along untouched. Empty if the header
+    /// was missing or invalid.
+    pub trace_state: Vec<(String, String)>,
+}
+
+impl TraceContext {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let mut context = Self::parse_traceparent(traceparent)?;
+
+        let tracestate: Vec<&str> = headers
+            .get_all(TRACESTATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        context.trace_state = parse_tracestate(&tracestate.join(",")).unwrap_or_default();
+
+        Some(context)
+    }
+
+    /// Parses `{version}-{trace-id}-{parent-id}-{flags}`. Versions after `00`
+    /// may append fields, which are ignored.
+    pub fn parse_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next().filter(|v| is_hex(v, 2) && *v != "ff")?;
+        let trace_id = parts.next().filter(|v| is_hex(v, 32) && !is_zero(v))?;
+        let parent_id = parts.next().filter(|v| is_hex(v, 16) && !is_zero(v))?;
+        let flags = parts.next().filter(|v| is_hex(v, 2))?;
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_owned(),
+            parent_id: parent_id.to_owned(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+            trace_state: Vec::new(),
+        })
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    pub fn tracestate(&self) -> String {
+        self.trace_state
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Parses comma-separated `key=value` members, or `None` if any is malformed
+/// or a key repeats.
+fn parse_tracestate(value: &str) -> Option<Vec<(String, String)>> {
+    let mut members: Vec<(String, String)> = Vec::new();
+    for member in value.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+        let (key, value) = member.split_once('=')?;
+        let valid_key = !key.is_empty()
+            && key.len() <= 256
+            && key
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"_-*/@".contains(&b));
+        let valid_value = !value.is_empty()
+            && value.len() <= 256
+            && value
+                .bytes()
+                .all(|b| (0x20..=0x7e).contains(&b) && b != b',' && b != b'=')
+            && !value.ends_with(' ');
+        if !valid_key || !valid_value || members.iter().any(|(k, _)| k == key) {
+            return None;
+        }
+        members.push((key.to_owned(), value.to_owned()));
+    }
+    (members.len() <= MAX_TRACESTATE_MEMBERS).then_some(members)
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn is_zero(s: &str) -> bool {
+    s.bytes().all(|b| b == b'0')
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::hyper::header::HeaderValue;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    fn traceparent(version: &str, trace_id: &str, parent_id: &str) -> String {
+        format!("{version}-{trace_id}-{parent_id}-01")
+    }
+
+    #[test]
+    fn parses_valid_traceparent() {
+        let context =
+            TraceContext::parse_traceparent(&traceparent("00", TRACE_ID, PARENT_ID)).unwrap();
+        assert_eq!(context.trace_id, TRACE_ID);
+        assert_eq!(context.parent_id, PARENT_ID);
+        assert!(context.sampled());
+        assert!(context.trace_state.is_empty());
+    }
+
+    #[test]
+    fn rejects_all_zero_ids() {
+        let zero_trace = "0".repeat(32);
+        let zero_parent = "0".repeat(16);
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("00", &zero_trace, PARENT_ID)),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("00", TRACE_ID, &zero_parent)),
+            None
+        );
+    }
+
+    #[test]
+    fn accepts_unknown_versions_with_extra_fields() {
+        let value = format!("{}-extra", traceparent("cc", TRACE_ID, PARENT_ID));
+        let context = TraceContext::parse_traceparent(&value).unwrap();
+        assert_eq!(context.trace_id, TRACE_ID);
+
+        let value = format!("{}-extra", traceparent("00", TRACE_ID, PARENT_ID));
+        assert_eq!(TraceContext::parse_traceparent(&value), None);
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("ff", TRACE_ID, PARENT_ID)),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_uppercase_hex() {
+        let upper = TRACE_ID.to_uppercase();
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("00", &upper, PARENT_ID)),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("00", &TRACE_ID[1..], PARENT_ID)),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("00", TRACE_ID, &format!("{PARENT_ID}0"))),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse_traceparent(&traceparent("0", TRACE_ID, PARENT_ID)),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_tracestate_and_drops_it_when_invalid() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT,
+            HeaderValue::from_str(&traceparent("00", TRACE_ID, PARENT_ID)).unwrap(),
+        );
+        headers.append(TRACESTATE, HeaderValue::from_static("congo=t61rcWkgMzE"));
+        headers.append(
+            TRACESTATE,
+            HeaderValue::from_static("rojo=00f067aa0ba902b7"),
+        );
+        let context = TraceContext::from_headers(&headers).unwrap();
+        assert_eq!(
+            context.tracestate(),
+            "congo=t61rcWkgMzE,rojo=00f067aa0ba902b7"
+        );
+
+        headers.append(TRACESTATE, HeaderValue::from_static("congo=again"));
+        let context = TraceContext::from_headers(&headers).unwrap();
+        assert!(context.trace_state.is_empty());
+    }
+}
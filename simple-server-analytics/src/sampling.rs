@@ -0,0 +1,293 @@
+//! Recording only a share of requests, for deployments where writing every
+//! one is too costly.
+//!
+//! Rates are rounded to one in `n` requests, and each recorded request stores
+//! `n` as its sample weight so that reports scale counts back up. Requests
+//! kept because they failed or were slow stand only for themselves.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use globset::{GlobBuilder, GlobMatcher};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How often the adaptive rate is recalculated.
+const ADAPTIVE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    /// Share of requests recorded, from 0 to 1.
+    pub rate: f64,
+    /// Rates for paths matching a glob, used instead of `rate`. The first
+    /// match wins.
+    pub routes: Vec<RouteRate>,
+    /// Record every response with a 5xx status.
+    pub keep_errors: bool,
+    /// Record every request slower than this.
+    pub keep_slower_than_ms: Option<u64>,
+    /// Lower the rate as traffic grows to record about this many requests per
+    /// second, not counting those kept as errors or slow.
+    pub target_per_sec: Option<f64>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            routes: Vec::new(),
+            keep_errors: true,
+            keep_slower_than_ms: None,
+            target_per_sec: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRate {
+    /// Glob over the path, where `*` stays within a segment and `**` does not.
+    pub path: String,
+    pub rate: f64,
+}
+
+/// Whether a request was picked when it started, before its response was
+/// known.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    weight: Option<i64>,
+}
+
+impl Sample {
+    /// The weight to record an incomplete request with, or `None` if it was
+    /// not picked.
+    pub fn weight(&self) -> Option<i64> {
+        self.weight
+    }
+}
+
+#[derive(Debug)]
+pub struct Sampler {
+    config: SamplingConfig,
+    routes: Vec<(GlobMatcher, f64)>,
+    adaptive: Option<Mutex<Adaptive>>,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self::new(SamplingConfig::default()).unwrap()
+    }
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig) -> anyhow::Result<Self> {
+        let rates = std::iter::once(config.rate).chain(config.routes.iter().map(|r| r.rate));
+        for rate in rates {
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&rate),
+                "sampling rate {rate} is not between 0 and 1"
+            );
+        }
+        if let Some(target) = config.target_per_sec {
+            anyhow::ensure!(
+                target > 0.0,
+                "target of {target} requests/s is not positive"
+            );
+        }
+
+        let routes = config
+            .routes
+            .iter()
+            .map(|r| {
+                let glob = GlobBuilder::new(&r.path).literal_separator(true).build()?;
+                Ok((glob.compile_matcher(), r.rate))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let adaptive = config
+            .target_per_sec
+            .map(|target| Mutex::new(Adaptive::new(target)));
+
+        Ok(Self {
+            config,
+            routes,
+            adaptive,
+        })
+    }
+
+    pub fn config(&self) -> &SamplingConfig {
+        &self.config
+    }
+
+    /// Picks requests to `path` at its configured rate, lowered further if
+    /// needed to meet the target rows per second.
+    pub fn start(&self, path: &str) -> Sample {
+        self.start_with(path, &mut rand::thread_rng())
+    }
+
+    /// [`Self::start`], drawing from `rng`.
+    pub fn start_with(&self, path: &str, rng: &mut impl Rng) -> Sample {
+        let mut rate = self
+            .routes
+            .iter()
+            .find(|(glob, _)| glob.is_match(path))
+            .map_or(self.config.rate, |(_, rate)| *rate);
+        if let Some(adaptive) = &self.adaptive {
+            rate = rate.min(adaptive.lock().unwrap().observe());
+        }
+
+        let weight = one_in(rate).filter(|n| *n == 1 || rng.gen_range(0..*n) == 0);
+        Sample { weight }
+    }
+
+    /// The weight to record a completed request with, or `None` to drop it.
+    pub fn finish(&self, sample: Sample, status: u16, duration: &Duration) -> Option<i64> {
+        let error = self.config.keep_errors && status >= 500;
+        let slow = self
+            .config
+            .keep_slower_than_ms
+            .map_or(false, |ms| duration.as_millis() > u128::from(ms));
+        if error || slow {
+            return Some(1);
+        }
+        sample.weight
+    }
+}
+
+/// `rate` as one in how many requests, or `None` for none at all.
+fn one_in(rate: f64) -> Option<i64> {
+    (rate > 0.0).then(|| (1.0 / rate).round().max(1.0) as i64)
+}
+
+/// Tracks incoming requests per second to derive the rate that meets the
+/// target.
+#[derive(Debug)]
+struct Adaptive {
+    target_per_sec: f64,
+    window_start: Instant,
+    seen: u64,
+    rate: f64,
+}
+
+impl Adaptive {
+    fn new(target_per_sec: f64) -> Self {
+        Self {
+            target_per_sec,
+            window_start: Instant::now(),
+            seen: 0,
+            rate: 1.0,
+        }
+    }
+
+    /// Counts a request and returns the current rate.
+    fn observe(&mut self) -> f64 {
+        self.seen += 1;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= ADAPTIVE_WINDOW {
+            let per_sec = self.seen as f64 / elapsed.as_secs_f64();
+            let rate = (self.target_per_sec / per_sec).min(1.0);
+            // Average with the previous rate so one burst doesn't swing it.
+            self.rate = (self.rate + rate) / 2.0;
+            self.window_start = Instant::now();
+            self.seen = 0;
+        }
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn route(path: &str, rate: f64) -> RouteRate {
+        RouteRate {
+            path: path.to_owned(),
+            rate,
+        }
+    }
+
+    #[test]
+    fn rates_round_to_one_in_n() {
+        assert_eq!(one_in(1.0), Some(1));
+        assert_eq!(one_in(0.9), Some(1));
+        assert_eq!(one_in(0.5), Some(2));
+        assert_eq!(one_in(0.4), Some(3));
+        assert_eq!(one_in(0.3), Some(3));
+        assert_eq!(one_in(0.001), Some(1000));
+        assert_eq!(one_in(0.0), None);
+    }
+
+    #[test]
+    fn picks_about_one_in_n() {
+        let sampler = Sampler::new(SamplingConfig {
+            rate: 0.25,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let weights: Vec<_> = (0..10_000)
+            .filter_map(|_| sampler.start_with("/", &mut rng).weight())
+            .collect();
+        assert!((2_250..2_750).contains(&weights.len()), "{}", weights.len());
+        assert!(weights.iter().all(|w| *w == 4));
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let sampler = Sampler::new(SamplingConfig {
+            rate: 1.0,
+            routes: vec![route("/api/admin/**", 1.0), route("/api/**", 0.0)],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        assert_eq!(
+            sampler.start_with("/api/admin/users", &mut rng).weight(),
+            Some(1)
+        );
+        assert_eq!(sampler.start_with("/api/items", &mut rng).weight(), None);
+        assert_eq!(sampler.start_with("/pricing", &mut rng).weight(), Some(1));
+    }
+
+    #[test]
+    fn errors_and_slow_requests_are_kept_alone() {
+        let sampler = Sampler::new(SamplingConfig {
+            rate: 0.0,
+            keep_errors: true,
+            keep_slower_than_ms: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+        let dropped = Sample { weight: None };
+        let fast = Duration::from_millis(100);
+
+        assert_eq!(sampler.finish(dropped, 200, &fast), None);
+        assert_eq!(sampler.finish(dropped, 503, &fast), Some(1));
+        assert_eq!(
+            sampler.finish(dropped, 200, &Duration::from_millis(101)),
+            Some(1)
+        );
+
+        let picked = Sample { weight: Some(10) };
+        assert_eq!(sampler.finish(picked, 200, &fast), Some(10));
+        assert_eq!(sampler.finish(picked, 500, &fast), Some(1));
+    }
+
+    #[test]
+    fn errors_follow_the_rate_unless_kept() {
+        let sampler = Sampler::new(SamplingConfig {
+            rate: 0.0,
+            keep_errors: false,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let dropped = Sample { weight: None };
+        assert_eq!(sampler.finish(dropped, 500, &Duration::ZERO), None);
+    }
+}
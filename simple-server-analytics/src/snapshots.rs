@@ -0,0 +1,115 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Utc};
+use tokio::task::JoinHandle;
+use tracing::*;
+
+use crate::SimpleAnalytics;
+
+const PREFIX: &str = "snapshot-";
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Periodically backs up the store into `dir`, keeping the most recent
+/// snapshots and deleting older ones.
+///
+/// Snapshots are named `snapshot-<time>`, with a `.db` extension for stores
+/// that back up to a single file.
+#[derive(Debug)]
+pub struct SnapshotTask {
+    sa: SimpleAnalytics,
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+    extension: Option<String>,
+}
+
+impl SnapshotTask {
+    pub fn new(sa: &SimpleAnalytics, dir: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            sa: sa.clone(),
+            dir: dir.into(),
+            interval,
+            keep: 7,
+            extension: Some("db".to_owned()),
+        }
+    }
+
+    /// How many snapshots to keep, 7 by default.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep.max(1);
+        self
+    }
+
+    /// Extension of the snapshot names, or `None` for stores that back up to
+    /// a directory.
+    pub fn extension(mut self, extension: Option<&str>) -> Self {
+        self.extension = extension.map(str::to_owned);
+        self
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.snapshot().await {
+                    error!("Failed to take snapshot in {}: {e:?}", self.dir.display());
+                }
+            }
+        })
+    }
+
+    /// Takes a snapshot now and deletes the ones no longer kept, returning
+    /// the path of the new snapshot.
+    pub async fn snapshot(&self) -> anyhow::Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let mut name = format!("{PREFIX}{}", Utc::now().format(TIME_FORMAT));
+        if let Some(extension) = &self.extension {
+            name = format!("{name}.{extension}");
+        }
+        let path = self.dir.join(name);
+        self.sa.store().backup_to(&path).await?;
+        info!("Took snapshot {}", path.display());
+
+        self.rotate().await?;
+        Ok(path)
+    }
+
+    async fn rotate(&self) -> anyhow::Result<()> {
+        let mut snapshots = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(taken_at) = self.taken_at(&entry.path()) {
+                snapshots.push((taken_at, entry.path()));
+            }
+        }
+        snapshots.sort();
+
+        let excess = snapshots.len().saturating_sub(self.keep);
+        for (_, path) in snapshots.drain(..excess) {
+            if path.is_dir() {
+                tokio::fs::remove_dir_all(&path).await?;
+            } else {
+                tokio::fs::remove_file(&path).await?;
+            }
+            info!("Deleted snapshot {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    /// When the snapshot at `path` was taken, or `None` if it is not one of
+    /// ours.
+    fn taken_at(&self, path: &Path) -> Option<NaiveDateTime> {
+        let name = path.file_name()?.to_str()?.strip_prefix(PREFIX)?;
+        let time = match &self.extension {
+            Some(extension) => name.strip_suffix(extension.as_str())?.strip_suffix('.')?,
+            None => name,
+        };
+        NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()
+    }
+}